@@ -1,42 +1,384 @@
-const PARAM_PREFIX: &str = "--";
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+
+const PARAM_PREFIX: &str = "-";
 const PARAM_RESOLVER: &str = "--resolver";
+const PARAM_LISTEN: &str = "--listen";
+const PARAM_CONFIG: &str = "--config";
+const PARAM_CONTROL: &str = "--control";
+const PARAM_SERVER: &str = "--server";
+const PARAM_TYPE: &str = "--type";
+const PARAM_CLASS: &str = "--class";
+const PARAM_TCP: &str = "--tcp";
+const PARAM_NO_RECURSE: &str = "--no-recurse";
+const PARAM_CD: &str = "--cd";
+const PARAM_EDNS: &str = "--edns";
+const PARAM_BUFSIZE: &str = "--bufsize";
+const PARAM_DNSSEC: &str = "--dnssec";
+const PARAM_JSON: &str = "--json";
+const PARAM_MESSAGE: &str = "--message";
+const PARAMS_HELP: [&str; 2] = ["--help", "-h"];
+const PARAMS_VERSION: [&str; 2] = ["--version", "-V"];
+
+const DEFAULT_QUERY_SERVER: &str = "127.0.0.1:2053";
+const DEFAULT_QUERY_TYPE: &str = "A";
+const DEFAULT_QUERY_CLASS: &str = "IN";
+// The EDNS buffer size recommended by the DNS flag day 2020
+const DEFAULT_EDNS_BUFSIZE: u16 = 1232;
+const DEFAULT_CONTROL_ADDRESS: &str = "127.0.0.1:2054";
+
+pub const USAGE: &str = "\
+Usage: dns-starter-rust [serve] [OPTIONS]
+       dns-starter-rust query <NAME> [OPTIONS]
+       dns-starter-rust check-zone <ORIGIN> <FILE>
+       dns-starter-rust reload [OPTIONS]
+
+Commands:
+  serve       Run the DNS server (default)
+  query       Send a query and print the response
+  check-zone  Validate a zone file
+  reload      Reload the configuration and zones of a running server
+
+Options:
+  -h, --help     Print help
+  -V, --version  Print version";
+
+pub const SERVE_USAGE: &str = "\
+Usage: dns-starter-rust [serve] [OPTIONS]
+
+Options:
+      --config <PATH>      Configuration file
+      --listen <ADDRESS>   Address to listen on, repeatable (default 127.0.0.1:2053)
+      --resolver <ADDRESS> Upstream resolver, repeatable
+      --control <ADDRESS>  Address of the control socket, disabled by default
+  -h, --help               Print help
+
+Sending SIGHUP reloads the configuration and zones.";
 
-#[derive(Debug)]
+pub const QUERY_USAGE: &str = "\
+Usage: dns-starter-rust query <NAME> [OPTIONS]
+       dns-starter-rust query --message <FILE> [OPTIONS]
+
+Options:
+      --server <ADDRESS>   Server to query (default 127.0.0.1:2053)
+      --type <TYPE>        Record type (default A)
+      --class <CLASS>      Record class (default IN)
+      --tcp                Query over TCP instead of UDP
+      --no-recurse         Don't ask the server to recurse
+      --cd                 Set the checking disabled flag
+      --edns               Add an EDNS OPT record
+      --bufsize <SIZE>     EDNS UDP buffer size, implies --edns (default 1232)
+      --dnssec             Set the DNSSEC OK flag, implies --edns
+      --message <FILE>     Send the message of a JSON file (RFC 8427) instead
+      --json               Print the response as JSON (RFC 8427)
+  -h, --help               Print help";
+
+pub const CHECK_ZONE_USAGE: &str = "\
+Usage: dns-starter-rust check-zone <ORIGIN> <FILE>
+
+Options:
+  -h, --help  Print help";
+
+pub const RELOAD_USAGE: &str = "\
+Usage: dns-starter-rust reload [OPTIONS]
+
+Options:
+      --control <ADDRESS>  Control socket of the server (default 127.0.0.1:2054)
+  -h, --help               Print help";
+
+#[derive(Debug, PartialEq)]
 pub enum CliParam {
-    Resolver(String),
+    Resolver(SocketAddr),
+    Listen(SocketAddr),
+    Config(String),
+    Control(SocketAddr),
 }
 
-impl CliParam {
-    fn get_param_value(string: &str) -> Option<String> {
-        let string = string.trim();
-        if string.is_empty() || string.starts_with(PARAM_PREFIX) {
-            return None;
+#[derive(Debug, PartialEq)]
+pub struct QueryParams {
+    pub name: String,
+    pub record_type: String,
+    pub class: String,
+    pub server: SocketAddr,
+    pub tcp: bool,
+    pub recursion_desired: bool,
+    pub checking_disabled: bool,
+    // The UDP buffer size to advertise, without EDNS when None
+    pub edns_bufsize: Option<u16>,
+    pub dnssec_ok: bool,
+    // A JSON file with the message to send, which then needs no name
+    pub message: Option<String>,
+    pub json: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve(Vec<CliParam>),
+    Query(QueryParams),
+    CheckZone { origin: String, file: String },
+    Reload { control: SocketAddr },
+    Help(&'static str),
+    Version,
+}
+
+impl Command {
+    pub fn from(strings: &[String]) -> Result<Self> {
+        let (command, strings) = match strings.first().map(String::as_str) {
+            Some("serve") => ("serve", &strings[1..]),
+            Some("query") => ("query", &strings[1..]),
+            Some("check-zone") => ("check-zone", &strings[1..]),
+            Some("reload") => ("reload", &strings[1..]),
+            Some(string) if !string.starts_with(PARAM_PREFIX) => {
+                bail!("unknown command '{string}'")
+            }
+            // Without a command the server is started, as it always was
+            _ => ("", strings),
+        };
+
+        let usage = match command {
+            "serve" => SERVE_USAGE,
+            "query" => QUERY_USAGE,
+            "check-zone" => CHECK_ZONE_USAGE,
+            "reload" => RELOAD_USAGE,
+            _ => USAGE,
+        };
+        if strings.iter().any(|s| PARAMS_HELP.contains(&s.as_str())) {
+            return Ok(Command::Help(usage));
+        }
+        if command.is_empty() && strings.iter().any(|s| PARAMS_VERSION.contains(&s.as_str())) {
+            return Ok(Command::Version);
+        }
+
+        match command {
+            "query" => Self::query_from(strings),
+            "check-zone" => Self::check_zone_from(strings),
+            "reload" => Self::reload_from(strings),
+            _ => Ok(Command::Serve(CliParam::from(strings)?)),
         }
-        Some(string.to_string())
     }
 
-    pub fn from(strings: &[String]) -> Vec<Self> {
-        let mut params: Vec<Self> = Vec::new();
-        if strings.is_empty() {
-            return params;
-        }
-
-        let mut strings_next_index = 1;
-        match strings[0].to_lowercase().as_str() {
-            PARAM_RESOLVER => {
-                if strings.len() >= 2 {
-                    if let Some(value) = Self::get_param_value(&strings[1]) {
-                        params.push(CliParam::Resolver(value));
-                        strings_next_index += 1;
-                    }
+    fn query_from(strings: &[String]) -> Result<Self> {
+        let mut name = None;
+        let mut params = QueryParams {
+            name: String::new(),
+            record_type: DEFAULT_QUERY_TYPE.to_string(),
+            class: DEFAULT_QUERY_CLASS.to_string(),
+            server: DEFAULT_QUERY_SERVER.parse()?,
+            tcp: false,
+            recursion_desired: true,
+            checking_disabled: false,
+            edns_bufsize: None,
+            dnssec_ok: false,
+            message: None,
+            json: false,
+        };
+
+        let mut strings = strings.iter();
+        while let Some(string) = strings.next() {
+            match string.as_str() {
+                PARAM_SERVER => params.server = parse_socket_address(string, strings.next())?,
+                PARAM_TYPE => params.record_type = param_value(string, strings.next())?,
+                PARAM_CLASS => params.class = param_value(string, strings.next())?,
+                PARAM_TCP => params.tcp = true,
+                PARAM_NO_RECURSE => params.recursion_desired = false,
+                PARAM_CD => params.checking_disabled = true,
+                PARAM_EDNS => {
+                    params.edns_bufsize.get_or_insert(DEFAULT_EDNS_BUFSIZE);
+                }
+                PARAM_BUFSIZE => {
+                    let value = param_value(string, strings.next())?;
+                    let bufsize = value
+                        .parse()
+                        .with_context(|| format!("{string}: invalid buffer size '{value}'"))?;
+                    params.edns_bufsize = Some(bufsize);
+                }
+                PARAM_DNSSEC => {
+                    params.dnssec_ok = true;
+                    params.edns_bufsize.get_or_insert(DEFAULT_EDNS_BUFSIZE);
                 }
+                PARAM_MESSAGE => params.message = Some(param_value(string, strings.next())?),
+                PARAM_JSON => params.json = true,
+                string if string.starts_with(PARAM_PREFIX) => bail!("unknown option '{string}'"),
+                string if name.is_none() => name = Some(string.to_string()),
+                string => bail!("unexpected argument '{string}'"),
             }
-            _ => {}
         }
 
-        // Tries to get other params from the remaining strings
-        params.extend(Self::from(&strings[strings_next_index..]));
+        params.name = match (name, &params.message) {
+            (Some(name), None) => name,
+            (None, Some(_)) => String::new(),
+            (Some(name), Some(_)) => bail!("unexpected argument '{name}' with --message"),
+            (None, None) => bail!("missing the name to query"),
+        };
+        Ok(Command::Query(params))
+    }
 
-        params
+    fn check_zone_from(strings: &[String]) -> Result<Self> {
+        if let Some(string) = strings.iter().find(|s| s.starts_with(PARAM_PREFIX)) {
+            bail!("unknown option '{string}'");
+        }
+        match strings {
+            [origin, file] => Ok(Command::CheckZone {
+                origin: origin.clone(),
+                file: file.clone(),
+            }),
+            [_, _, string, ..] => bail!("unexpected argument '{string}'"),
+            _ => bail!("check-zone needs a zone origin and a zone file"),
+        }
+    }
+
+    fn reload_from(strings: &[String]) -> Result<Self> {
+        let mut control: SocketAddr = DEFAULT_CONTROL_ADDRESS.parse()?;
+
+        let mut strings = strings.iter();
+        while let Some(string) = strings.next() {
+            match string.as_str() {
+                PARAM_CONTROL => control = parse_socket_address(string, strings.next())?,
+                string if string.starts_with(PARAM_PREFIX) => bail!("unknown option '{string}'"),
+                string => bail!("unexpected argument '{string}'"),
+            }
+        }
+
+        Ok(Command::Reload { control })
+    }
+}
+
+impl CliParam {
+    pub fn from(strings: &[String]) -> Result<Vec<Self>> {
+        let mut params: Vec<Self> = Vec::new();
+
+        let mut strings = strings.iter();
+        while let Some(string) = strings.next() {
+            let param = match string.to_lowercase().as_str() {
+                PARAM_RESOLVER => CliParam::Resolver(parse_socket_address(string, strings.next())?),
+                PARAM_LISTEN => CliParam::Listen(parse_socket_address(string, strings.next())?),
+                PARAM_CONFIG => CliParam::Config(param_value(string, strings.next())?),
+                PARAM_CONTROL => CliParam::Control(parse_socket_address(string, strings.next())?),
+                _ if string.starts_with(PARAM_PREFIX) => bail!("unknown option '{string}'"),
+                _ => bail!("unexpected argument '{string}'"),
+            };
+            params.push(param);
+        }
+
+        Ok(params)
+    }
+}
+
+fn param_value(param: &str, value: Option<&String>) -> Result<String> {
+    match value.map(|value| value.trim()) {
+        Some(value) if !value.is_empty() && !value.starts_with(PARAM_PREFIX) => {
+            Ok(value.to_string())
+        }
+        _ => bail!("{param} needs a value"),
+    }
+}
+
+fn parse_socket_address(param: &str, value: Option<&String>) -> Result<SocketAddr> {
+    let value = param_value(param, value)?;
+    value
+        .parse()
+        .with_context(|| format!("{param}: invalid socket address '{value}'"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_when_no_command_then_server_params_are_parsed() {
+        // Given
+        let args = strings("--resolver [::1]:53 --listen 0.0.0.0:53 --config dns.toml");
+        // When
+        let command = Command::from(&args).unwrap();
+        // Then
+        assert_eq!(
+            command,
+            Command::Serve(vec![
+                CliParam::Resolver("[::1]:53".parse().unwrap()),
+                CliParam::Listen("0.0.0.0:53".parse().unwrap()),
+                CliParam::Config("dns.toml".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_when_commands_have_arguments_then_they_are_parsed() {
+        // Given
+        // When
+        let query = Command::from(&strings("query example.com --type AAAA")).unwrap();
+        let edns_query =
+            Command::from(&strings("query example.com --tcp --no-recurse --dnssec")).unwrap();
+        let check_zone = Command::from(&strings("check-zone example.com a.zone")).unwrap();
+        let help = Command::from(&strings("query --help")).unwrap();
+        // Then
+        assert_eq!(
+            query,
+            Command::Query(QueryParams {
+                name: "example.com".to_string(),
+                record_type: "AAAA".to_string(),
+                class: "IN".to_string(),
+                server: DEFAULT_QUERY_SERVER.parse().unwrap(),
+                tcp: false,
+                recursion_desired: true,
+                checking_disabled: false,
+                edns_bufsize: None,
+                dnssec_ok: false,
+                message: None,
+                json: false,
+            })
+        );
+        assert!(matches!(
+            edns_query,
+            Command::Query(QueryParams {
+                tcp: true,
+                recursion_desired: false,
+                edns_bufsize: Some(1232),
+                dnssec_ok: true,
+                ..
+            })
+        ));
+        assert_eq!(
+            check_zone,
+            Command::CheckZone {
+                origin: "example.com".to_string(),
+                file: "a.zone".to_string(),
+            }
+        );
+        assert_eq!(help, Command::Help(QUERY_USAGE));
+    }
+
+    #[test]
+    fn test_when_arguments_are_malformed_then_parsing_fails() {
+        // Given
+        let inputs = [
+            ("--resolvr 8.8.8.8:53", "unknown option '--resolvr'"),
+            ("--resolver", "--resolver needs a value"),
+            ("--resolver --listen 0.0.0.0:53", "--resolver needs a value"),
+            (
+                "--resolver 8:8:8:8:53",
+                "--resolver: invalid socket address '8:8:8:8:53'",
+            ),
+            ("serve extra", "unexpected argument 'extra'"),
+            ("resolve", "unknown command 'resolve'"),
+            ("query", "missing the name to query"),
+            (
+                "query example.com --bufsize 70000",
+                "--bufsize: invalid buffer size '70000'",
+            ),
+            (
+                "check-zone example.com",
+                "check-zone needs a zone origin and a zone file",
+            ),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = Command::from(&strings(input)).unwrap_err();
+            // Then
+            assert_eq!(error.to_string(), expected_error);
+        }
     }
 }
@@ -0,0 +1,209 @@
+use crate::cli_params::{CliParam, QueryParams};
+use crate::json;
+use crate::server::control::{send_command, COMMAND_RELOAD};
+use crate::server::dispatcher::Dispatcher;
+use crate::server::message::answer::Answer;
+use crate::server::message::header::{Header, Z_CD_FLAG};
+use crate::server::message::name::labels_bytes;
+use crate::server::message::question::Question;
+use crate::server::message::rdata::{absolute_name, parse_name, RData};
+use crate::server::message::record_type::{parse_class, RecordType};
+use crate::server::message::{Message, EDNS_DO_FLAG};
+use crate::server::resolver::{exchange_tcp, exchange_udp};
+use crate::server::zone::Zone;
+use crate::server::{start_server, Context};
+use anyhow::{bail, Context as _, Result};
+use rand::Rng;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub fn serve(params: Vec<CliParam>) -> Result<()> {
+    let context = Context::new(params, Dispatcher::default())?;
+
+    start_server(Arc::new(context))
+}
+
+pub fn query(params: &QueryParams) -> Result<()> {
+    let request_message = match &params.message {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("failed to read the message file {path}"))?;
+            Message::from_json(&json::parse(&text)?)
+                .with_context(|| format!("invalid message file {path}"))?
+        }
+        None => query_message(params)?,
+    };
+    let request_bytes = Vec::<u8>::try_from(request_message)?;
+
+    let started_at = Instant::now();
+    let mut transport = if params.tcp { "tcp" } else { "udp" };
+    let mut response_bytes = if params.tcp {
+        exchange_tcp(&request_bytes, &params.server)?
+    } else {
+        exchange_udp(&request_bytes, &params.server)?
+    };
+    // Like dig, a truncated response is retried over TCP
+    let header = Header::try_from(response_bytes.as_slice())
+        .with_context(|| format!("malformed response from {}", params.server))?;
+    if transport == "udp" && header.tc == 1 {
+        if !params.json {
+            println!(";; Truncated, retrying over TCP");
+        }
+        transport = "tcp";
+        response_bytes = exchange_tcp(&request_bytes, &params.server)?;
+    }
+    let query_time = started_at.elapsed();
+
+    let response_message = Message::try_from(response_bytes.as_slice())
+        .with_context(|| format!("malformed response from {}", params.server))?;
+    if params.json {
+        println!("{}", response_message.to_json());
+        return Ok(());
+    }
+    println!(
+        "{response_message}\n\n;; Query time: {} msec\n;; SERVER: {}({transport})\n;; MSG SIZE  rcvd: {}",
+        query_time.as_millis(),
+        params.server,
+        response_bytes.len()
+    );
+    Ok(())
+}
+
+fn query_message(params: &QueryParams) -> Result<Message> {
+    let record_type: RecordType = params.record_type.parse()?;
+    let class = parse_class(&params.class)?;
+    let label = parse_name(&params.name, "")?;
+    // The EDNS flags are in the TTL of the OPT record, and its class is the buffer size
+    let additionals = match params.edns_bufsize {
+        Some(bufsize) => {
+            let flags = if params.dnssec_ok { EDNS_DO_FLAG } else { 0 };
+            let rdata = RData::Unknown(vec![]);
+            vec![Answer::new(".", RecordType::Opt, bufsize, flags, &rdata)]
+        }
+        None => vec![],
+    };
+    Ok(Message {
+        header: Header {
+            id: rand::thread_rng().gen(),
+            rd: params.recursion_desired.into(),
+            z: if params.checking_disabled {
+                Z_CD_FLAG
+            } else {
+                0
+            },
+            ..Header::default()
+        },
+        questions: vec![Question {
+            qname: labels_bytes(&label),
+            qtype: record_type.into(),
+            qclass: class,
+            label,
+        }],
+        answers: vec![],
+        authorities: vec![],
+        additionals,
+    })
+}
+
+pub fn check_zone(origin: &str, file: &str) -> Result<()> {
+    let zone = Zone::load(&absolute_name(origin, ""), Path::new(file))?;
+    println!(
+        "zone {}: loaded {} records from {file}",
+        zone.origin,
+        zone.records_count()
+    );
+    Ok(())
+}
+
+pub fn reload(control: &SocketAddr) -> Result<()> {
+    let reply = send_command(control, COMMAND_RELOAD)
+        .with_context(|| format!("failed to reach the control socket on {control}"))?;
+    if reply != "ok" {
+        bail!("{}", reply.trim_start_matches("error: "));
+    }
+    println!("reloaded");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::message::record_type::CLASS_CH;
+
+    fn query_params(name: &str, record_type: &str, class: &str) -> QueryParams {
+        QueryParams {
+            name: name.to_string(),
+            record_type: record_type.to_string(),
+            class: class.to_string(),
+            server: "127.0.0.1:2053".parse().unwrap(),
+            tcp: false,
+            recursion_desired: true,
+            checking_disabled: false,
+            edns_bufsize: None,
+            dnssec_ok: false,
+            message: None,
+            json: false,
+        }
+    }
+
+    #[test]
+    fn test_when_query_has_class_flags_and_edns_then_message_carries_them() {
+        // Given
+        let params = QueryParams {
+            recursion_desired: false,
+            checking_disabled: true,
+            edns_bufsize: Some(1232),
+            dnssec_ok: true,
+            ..query_params("version.bind", "txt", "CH")
+        };
+        // When
+        let message = query_message(&params).unwrap();
+        // Then
+        assert_eq!(message.header.rd, 0);
+        assert_eq!(message.header.z, Z_CD_FLAG);
+        assert_eq!(message.questions[0].label, "version.bind.");
+        assert_eq!(message.questions[0].qname, labels_bytes("version.bind."));
+        assert_eq!(message.questions[0].qtype, u16::from(RecordType::Txt));
+        assert_eq!(message.questions[0].qclass, CLASS_CH);
+        let opt = message.edns().unwrap();
+        assert_eq!(opt.class, 1232);
+        assert_eq!(opt.ttl, EDNS_DO_FLAG);
+    }
+
+    #[test]
+    fn test_when_query_has_no_edns_then_message_has_no_additionals() {
+        // Given
+        let params = query_params("example.com.", "AAAA", "IN");
+        // When
+        let message = query_message(&params).unwrap();
+        // Then
+        assert_eq!(message.header.rd, 1);
+        assert_eq!(message.header.z, 0);
+        assert_eq!(message.questions[0].label, "example.com.");
+        assert!(message.additionals.is_empty());
+    }
+
+    #[test]
+    fn test_when_query_type_or_class_is_unknown_then_message_is_not_built() {
+        // Given
+        let inputs = [
+            (
+                query_params("example.com", "NOPE", "IN"),
+                "unsupported record type 'NOPE'",
+            ),
+            (
+                query_params("example.com", "A", "NOPE"),
+                "unsupported class 'NOPE'",
+            ),
+        ];
+        for (params, expected_error) in inputs {
+            // When
+            let error = query_message(&params).unwrap_err();
+            // Then
+            assert_eq!(error.to_string(), expected_error);
+        }
+    }
+}
@@ -0,0 +1,1237 @@
+use self::toml::{Entry, Table, Value};
+use crate::cli_params::CliParam;
+use crate::log::Level;
+use crate::server::acl::{Acl, Cidr};
+use crate::server::blocklist::BlockAction;
+use crate::server::hosts::parse_static_record;
+use crate::server::message::answer::Answer;
+use crate::server::message::rdata::parse_name;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+mod toml;
+mod yaml;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:2053";
+const DEFAULT_CACHE_SIZE: usize = 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DOH_PATH: &str = "/dns-query";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub upstreams: Vec<Upstream>,
+    pub forwards: Vec<ForwardConfig>,
+    pub cache: CacheConfig,
+    pub static_records: StaticConfig,
+    pub zones: Vec<ZoneConfig>,
+    // Response policy zones, applied to resolved answers in order
+    pub rpz: Vec<ZoneConfig>,
+    pub blocklist: BlocklistConfig,
+    pub policies: Vec<PolicyConfig>,
+    pub acl: Acl,
+    pub rrl: RrlConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    // Queries are only logged when set
+    pub query_log: Option<QueryLogConfig>,
+    pub control: Option<SocketAddr>,
+    // Where the Prometheus metrics are served over HTTP
+    pub metrics: Option<SocketAddr>,
+    pub dnstap: Option<DnstapConfig>,
+    pub doh: Option<DohConfig>,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    // A plain DNS server, queried over UDP
+    Dns(SocketAddr),
+    // An RFC 8484 endpoint over plain HTTP, without TLS, queried with POST on reused connections
+    Http { address: SocketAddr, path: String },
+}
+
+impl Upstream {
+    pub fn address(&self) -> SocketAddr {
+        match self {
+            Upstream::Dns(address) | Upstream::Http { address, .. } => *address,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Upstream::Dns(address) => write!(f, "{address}"),
+            Upstream::Http { address, path } => write!(f, "http://{address}{path}"),
+        }
+    }
+}
+
+// Names under the suffix are resolved by its own upstreams
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardConfig {
+    pub suffix: String,
+    pub upstreams: Vec<Upstream>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub size: usize,
+    // Where the cache is saved on shutdown and restored on start
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StaticConfig {
+    pub hosts: Vec<PathBuf>,
+    pub records: Vec<Answer>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlocklistConfig {
+    pub files: Vec<PathBuf>,
+    // Names never blocked, even when a blocklist has them
+    pub allowlist: Vec<PathBuf>,
+    pub action: BlockAction,
+}
+
+// A blocklist replacing the default one for the clients of some subnets
+#[derive(Debug, Clone)]
+pub struct PolicyConfig {
+    pub name: String,
+    pub clients: Vec<Cidr>,
+    pub blocklist: BlocklistConfig,
+}
+
+// Response rate limiting of UDP responses, disabled when the rate is 0
+#[derive(Debug, Clone)]
+pub struct RrlConfig {
+    // Identical responses per second to a client prefix
+    pub responses_per_second: u32,
+    // NXDOMAIN and error responses per second to a client prefix, the same as identical
+    // responses when not set
+    pub nxdomains_per_second: Option<u32>,
+    pub errors_per_second: Option<u32>,
+    // Every slip-th limited response is sent truncated instead of dropped, 0 drops them all
+    pub slip: u32,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        RrlConfig {
+            responses_per_second: 0,
+            nxdomains_per_second: None,
+            errors_per_second: None,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+// Per client limits, disabled when 0
+#[derive(Debug, Clone, Default)]
+pub struct LimitsConfig {
+    pub queries_per_second: u32,
+    // Upstream resolutions in flight for one client
+    pub concurrent_resolutions: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub level: Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryLogFormat {
+    Json,
+    Logfmt,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryLogConfig {
+    pub format: QueryLogFormat,
+    // Successful queries are info, failed ones warn
+    pub level: Level,
+    // One successful query in sample is logged
+    pub sample: u32,
+    // Standard error when not set
+    pub file: Option<PathBuf>,
+    // The file is rotated above this size in bytes, unless 0
+    pub max_size: u64,
+    pub max_files: u32,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        QueryLogConfig {
+            format: QueryLogFormat::Logfmt,
+            level: Level::Info,
+            sample: 1,
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnstapOutput {
+    // A Frame Streams reader listening on a Unix socket
+    Socket(PathBuf),
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct DnstapConfig {
+    pub output: DnstapOutput,
+    pub identity: String,
+}
+
+// RFC 8484 messages served over plain HTTP, HTTPS needs a proxy terminating TLS
+#[derive(Debug, Clone)]
+pub struct DohConfig {
+    pub listen: SocketAddr,
+    pub path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    // How long in flight requests are waited for
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![DEFAULT_LISTEN_ADDRESS.parse().unwrap()],
+            upstreams: vec![],
+            forwards: vec![],
+            cache: CacheConfig {
+                size: DEFAULT_CACHE_SIZE,
+                file: None,
+            },
+            static_records: StaticConfig::default(),
+            zones: vec![],
+            rpz: vec![],
+            blocklist: BlocklistConfig {
+                files: vec![],
+                allowlist: vec![],
+                action: BlockAction::NxDomain,
+            },
+            policies: vec![],
+            acl: Acl::default(),
+            rrl: RrlConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig { level: Level::Info },
+            query_log: None,
+            control: None,
+            metrics: None,
+            dnstap: None,
+            doh: None,
+            shutdown: ShutdownConfig {
+                timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            },
+        }
+    }
+}
+
+impl Config {
+    // Loads the configuration file if one is given, then applies the CLI flags over it
+    pub fn from_params(params: &[CliParam]) -> Result<Self> {
+        let config_path = params.iter().find_map(|param| match param {
+            CliParam::Config(path) => Some(path),
+            _ => None,
+        });
+        let mut config = match config_path {
+            Some(path) => Config::load(Path::new(path))?,
+            None => Config::default(),
+        };
+
+        let listen: Vec<SocketAddr> = params
+            .iter()
+            .filter_map(|param| match param {
+                CliParam::Listen(address) => Some(*address),
+                _ => None,
+            })
+            .collect();
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+
+        let upstreams: Vec<Upstream> = params
+            .iter()
+            .filter_map(|param| match param {
+                CliParam::Resolver(address) => Some(Upstream::Dns(*address)),
+                _ => None,
+            })
+            .collect();
+        if !upstreams.is_empty() {
+            config.upstreams = upstreams;
+        }
+
+        let control = params.iter().find_map(|param| match param {
+            CliParam::Control(address) => Some(*address),
+            _ => None,
+        });
+        if let Some(address) = control {
+            config.control = Some(check_control_address("--control", address)?);
+        }
+
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let base_directory = path.parent().unwrap_or(Path::new(""));
+        let is_yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml" | "yml")
+        );
+        let config = if is_yaml {
+            yaml::parse(&text).and_then(|root| Config::from_table(root, base_directory))
+        } else {
+            Config::parse(&text, base_directory)
+        };
+        config.with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str, base_directory: &Path) -> Result<Self> {
+        Config::from_table(toml::parse(text)?, base_directory)
+    }
+
+    // Relative hosts, zone, RPZ, blocklist and cache files are resolved from base_directory
+    fn from_table(root: Table, base_directory: &Path) -> Result<Self> {
+        check_keys(
+            &root,
+            "",
+            &[
+                "listen",
+                "resolver",
+                "forward",
+                "cache",
+                "static",
+                "zones",
+                "rpz",
+                "blocklist",
+                "policies",
+                "acl",
+                "rrl",
+                "limits",
+                "log",
+                "query_log",
+                "control",
+                "metrics",
+                "dnstap",
+                "doh",
+                "shutdown",
+            ],
+        )?;
+        let mut config = Config::default();
+
+        if let Some(entry) = root.entries.get("listen") {
+            config.listen = strings(entry, "listen")?
+                .iter()
+                .map(|value| parse_socket_address("listen", value))
+                .collect::<Result<_>>()
+                .with_context(|| format!("line {}", entry.line))?;
+            if config.listen.is_empty() {
+                bail!("line {}: listen must have at least one address", entry.line);
+            }
+        }
+
+        if let Some(resolver) = table(&root, "resolver")? {
+            check_keys(resolver, "resolver", &["upstreams"])?;
+            if let Some(entry) = resolver.entries.get("upstreams") {
+                config.upstreams = strings(entry, "resolver.upstreams")?
+                    .iter()
+                    .map(|value| parse_upstream("resolver.upstreams", value))
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("line {}", entry.line))?;
+            }
+        }
+
+        for forward in array_of_tables(&root, "forward")? {
+            check_keys(forward, "forward", &["suffix", "upstreams"])?;
+            let suffix = required_string(forward, "forward", "suffix")?;
+            let suffix = parse_name(suffix, "")
+                .with_context(|| {
+                    format!("line {}: forward.suffix", forward.entries["suffix"].line)
+                })?
+                .to_lowercase();
+            if config.forwards.iter().any(|f| f.suffix == suffix) {
+                bail!(
+                    "line {}: suffix '{suffix}' is forwarded twice",
+                    forward.line
+                );
+            }
+            let Some(entry) = forward.entries.get("upstreams") else {
+                bail!(
+                    "line {}: missing key 'upstreams' in [forward]",
+                    forward.line
+                );
+            };
+            let upstreams: Vec<Upstream> = strings(entry, "forward.upstreams")?
+                .iter()
+                .map(|value| parse_upstream("forward.upstreams", value))
+                .collect::<Result<_>>()
+                .with_context(|| format!("line {}", entry.line))?;
+            if upstreams.is_empty() {
+                bail!(
+                    "line {}: forward.upstreams must have at least one address",
+                    entry.line
+                );
+            }
+            config.forwards.push(ForwardConfig { suffix, upstreams });
+        }
+
+        if let Some(cache) = table(&root, "cache")? {
+            check_keys(cache, "cache", &["size", "file"])?;
+            if let Some(entry) = cache.entries.get("size") {
+                config.cache.size = match entry.value {
+                    Value::Integer(size) if size >= 0 => size as usize,
+                    _ => bail!(
+                        "line {}: cache.size must be a non negative integer",
+                        entry.line
+                    ),
+                };
+            }
+            if let Some(entry) = cache.entries.get("file") {
+                config.cache.file = Some(base_directory.join(string(entry, "cache.file")?));
+            }
+        }
+
+        if let Some(static_records) = table(&root, "static")? {
+            check_keys(static_records, "static", &["hosts", "records"])?;
+            if let Some(entry) = static_records.entries.get("hosts") {
+                config.static_records.hosts = strings(entry, "static.hosts")?
+                    .iter()
+                    .map(|file| base_directory.join(file))
+                    .collect();
+            }
+            if let Some(entry) = static_records.entries.get("records") {
+                config.static_records.records = strings(entry, "static.records")?
+                    .iter()
+                    .map(|record| parse_static_record(record))
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("line {}: static.records", entry.line))?;
+            }
+        }
+
+        config.zones = zone_configs(&root, "zones", base_directory)?;
+        config.rpz = zone_configs(&root, "rpz", base_directory)?;
+
+        if let Some(blocklist) = table(&root, "blocklist")? {
+            check_keys(
+                blocklist,
+                "blocklist",
+                &["files", "allowlist", "action", "sinkhole"],
+            )?;
+            config.blocklist = blocklist_config(blocklist, "blocklist", base_directory)?;
+        }
+
+        for policy in array_of_tables(&root, "policies")? {
+            check_keys(
+                policy,
+                "policies",
+                &[
+                    "name",
+                    "clients",
+                    "files",
+                    "allowlist",
+                    "action",
+                    "sinkhole",
+                ],
+            )?;
+            let name = required_string(policy, "policies", "name")?.to_string();
+            if config.policies.iter().any(|p| p.name == name) {
+                bail!("line {}: policy '{name}' is defined twice", policy.line);
+            }
+            let Some(clients) = policy.entries.get("clients") else {
+                bail!("line {}: missing key 'clients' in [policies]", policy.line);
+            };
+            let clients = strings(clients, "policies.clients")?
+                .iter()
+                .map(|value| value.parse::<Cidr>())
+                .collect::<Result<_>>()
+                .with_context(|| format!("line {}: policies.clients", clients.line))?;
+            config.policies.push(PolicyConfig {
+                name,
+                clients,
+                blocklist: blocklist_config(policy, "policies", base_directory)?,
+            });
+        }
+
+        if let Some(acl) = table(&root, "acl")? {
+            check_keys(acl, "acl", &["query", "recursion", "transfer", "update"])?;
+            for (key, cidrs) in [
+                ("query", &mut config.acl.query),
+                ("recursion", &mut config.acl.recursion),
+                ("transfer", &mut config.acl.transfer),
+                ("update", &mut config.acl.update),
+            ] {
+                if let Some(entry) = acl.entries.get(key) {
+                    *cidrs = strings(entry, &format!("acl.{key}"))?
+                        .iter()
+                        .map(|value| value.parse::<Cidr>())
+                        .collect::<Result<_>>()
+                        .with_context(|| format!("line {}: acl.{key}", entry.line))?;
+                }
+            }
+        }
+
+        if let Some(rrl) = table(&root, "rrl")? {
+            check_keys(
+                rrl,
+                "rrl",
+                &[
+                    "responses_per_second",
+                    "nxdomains_per_second",
+                    "errors_per_second",
+                    "slip",
+                    "ipv4_prefix",
+                    "ipv6_prefix",
+                ],
+            )?;
+            if let Some(entry) = rrl.entries.get("responses_per_second") {
+                config.rrl.responses_per_second =
+                    integer(entry, "rrl.responses_per_second", u32::MAX as i64)? as u32;
+            }
+            if let Some(entry) = rrl.entries.get("nxdomains_per_second") {
+                config.rrl.nxdomains_per_second =
+                    Some(integer(entry, "rrl.nxdomains_per_second", u32::MAX as i64)? as u32);
+            }
+            if let Some(entry) = rrl.entries.get("errors_per_second") {
+                config.rrl.errors_per_second =
+                    Some(integer(entry, "rrl.errors_per_second", u32::MAX as i64)? as u32);
+            }
+            if let Some(entry) = rrl.entries.get("slip") {
+                config.rrl.slip = integer(entry, "rrl.slip", u32::MAX as i64)? as u32;
+            }
+            if let Some(entry) = rrl.entries.get("ipv4_prefix") {
+                config.rrl.ipv4_prefix = integer(entry, "rrl.ipv4_prefix", 32)? as u8;
+            }
+            if let Some(entry) = rrl.entries.get("ipv6_prefix") {
+                config.rrl.ipv6_prefix = integer(entry, "rrl.ipv6_prefix", 128)? as u8;
+            }
+        }
+
+        if let Some(limits) = table(&root, "limits")? {
+            check_keys(
+                limits,
+                "limits",
+                &["queries_per_second", "concurrent_resolutions"],
+            )?;
+            if let Some(entry) = limits.entries.get("queries_per_second") {
+                config.limits.queries_per_second =
+                    integer(entry, "limits.queries_per_second", u32::MAX as i64)? as u32;
+            }
+            if let Some(entry) = limits.entries.get("concurrent_resolutions") {
+                config.limits.concurrent_resolutions =
+                    integer(entry, "limits.concurrent_resolutions", u32::MAX as i64)? as usize;
+            }
+        }
+
+        if let Some(log) = table(&root, "log")? {
+            check_keys(log, "log", &["level"])?;
+            if let Some(entry) = log.entries.get("level") {
+                config.log.level = string(entry, "log.level")?
+                    .parse()
+                    .with_context(|| format!("line {}: log.level", entry.line))?;
+            }
+        }
+
+        if let Some(query_log) = table(&root, "query_log")? {
+            check_keys(
+                query_log,
+                "query_log",
+                &["format", "level", "sample", "file", "max_size", "max_files"],
+            )?;
+            let mut query_log_config = QueryLogConfig::default();
+            if let Some(entry) = query_log.entries.get("format") {
+                query_log_config.format = match string(entry, "query_log.format")? {
+                    "json" => QueryLogFormat::Json,
+                    "logfmt" => QueryLogFormat::Logfmt,
+                    format => bail!(
+                        "line {}: unknown query_log.format '{format}', expected json or logfmt",
+                        entry.line
+                    ),
+                };
+            }
+            if let Some(entry) = query_log.entries.get("level") {
+                query_log_config.level = string(entry, "query_log.level")?
+                    .parse()
+                    .with_context(|| format!("line {}: query_log.level", entry.line))?;
+            }
+            if let Some(entry) = query_log.entries.get("sample") {
+                let sample = integer(entry, "query_log.sample", u32::MAX as i64)?;
+                query_log_config.sample = sample.max(1) as u32;
+            }
+            if let Some(entry) = query_log.entries.get("file") {
+                query_log_config.file = Some(base_directory.join(string(entry, "query_log.file")?));
+            }
+            if let Some(entry) = query_log.entries.get("max_size") {
+                query_log_config.max_size = integer(entry, "query_log.max_size", i64::MAX)? as u64;
+            }
+            if let Some(entry) = query_log.entries.get("max_files") {
+                query_log_config.max_files =
+                    integer(entry, "query_log.max_files", u32::MAX as i64)? as u32;
+            }
+            config.query_log = Some(query_log_config);
+        }
+
+        if let Some(control) = table(&root, "control")? {
+            check_keys(control, "control", &["listen"])?;
+            if let Some(entry) = control.entries.get("listen") {
+                let address =
+                    parse_socket_address("control.listen", string(entry, "control.listen")?)
+                        .and_then(|address| check_control_address("control.listen", address))
+                        .with_context(|| format!("line {}", entry.line))?;
+                config.control = Some(address);
+            }
+        }
+
+        if let Some(metrics) = table(&root, "metrics")? {
+            check_keys(metrics, "metrics", &["listen"])?;
+            if let Some(entry) = metrics.entries.get("listen") {
+                let address =
+                    parse_socket_address("metrics.listen", string(entry, "metrics.listen")?)
+                        .with_context(|| format!("line {}", entry.line))?;
+                config.metrics = Some(address);
+            }
+        }
+
+        if let Some(dnstap) = table(&root, "dnstap")? {
+            check_keys(dnstap, "dnstap", &["socket", "file", "identity"])?;
+            let output = match (dnstap.entries.get("socket"), dnstap.entries.get("file")) {
+                (Some(entry), None) => {
+                    DnstapOutput::Socket(base_directory.join(string(entry, "dnstap.socket")?))
+                }
+                (None, Some(entry)) => {
+                    DnstapOutput::File(base_directory.join(string(entry, "dnstap.file")?))
+                }
+                _ => bail!(
+                    "line {}: [dnstap] needs either a socket or a file",
+                    dnstap.line
+                ),
+            };
+            let identity = match dnstap.entries.get("identity") {
+                Some(entry) => string(entry, "dnstap.identity")?.to_string(),
+                None => env!("CARGO_PKG_NAME").to_string(),
+            };
+            config.dnstap = Some(DnstapConfig { output, identity });
+        }
+
+        if let Some(doh) = table(&root, "doh")? {
+            check_keys(doh, "doh", &["listen", "path"])?;
+            let listen = required_string(doh, "doh", "listen")?;
+            let listen = parse_socket_address("doh.listen", listen)
+                .with_context(|| format!("line {}", doh.entries["listen"].line))?;
+            let path = match doh.entries.get("path") {
+                Some(entry) => {
+                    let path = string(entry, "doh.path")?;
+                    if !path.starts_with('/') {
+                        bail!("line {}: doh.path must start with '/'", entry.line);
+                    }
+                    path.to_string()
+                }
+                None => DEFAULT_DOH_PATH.to_string(),
+            };
+            config.doh = Some(DohConfig { listen, path });
+        }
+
+        if let Some(shutdown) = table(&root, "shutdown")? {
+            check_keys(shutdown, "shutdown", &["timeout"])?;
+            if let Some(entry) = shutdown.entries.get("timeout") {
+                config.shutdown.timeout = match entry.value {
+                    Value::Integer(seconds) if seconds >= 0 => Duration::from_secs(seconds as u64),
+                    _ => bail!(
+                        "line {}: shutdown.timeout must be a non negative number of seconds",
+                        entry.line
+                    ),
+                };
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Reads [[zones]] and [[rpz]], where names are unique and files relative to the config
+fn zone_configs(root: &Table, key: &str, base_directory: &Path) -> Result<Vec<ZoneConfig>> {
+    let mut zones: Vec<ZoneConfig> = vec![];
+    for zone in array_of_tables(root, key)? {
+        check_keys(zone, key, &["name", "file"])?;
+        let name = required_string(zone, key, "name")?;
+        let file = required_string(zone, key, "file")?;
+        let name = parse_name(name, "")
+            .with_context(|| format!("line {}: {key}.name", zone.entries["name"].line))?
+            .to_lowercase();
+        if zones.iter().any(|z| z.name == name) {
+            bail!("line {}: zone '{name}' is defined twice", zone.line);
+        }
+        zones.push(ZoneConfig {
+            name,
+            file: base_directory.join(file),
+        });
+    }
+    Ok(zones)
+}
+
+// Reads the files, allowlist, action and sinkhole keys shared by [blocklist] and [[policies]]
+fn blocklist_config(
+    table: &Table,
+    table_name: &str,
+    base_directory: &Path,
+) -> Result<BlocklistConfig> {
+    let files = |key: &str| -> Result<Vec<PathBuf>> {
+        match table.entries.get(key) {
+            Some(entry) => Ok(strings(entry, &format!("{table_name}.{key}"))?
+                .iter()
+                .map(|file| base_directory.join(file))
+                .collect()),
+            None => Ok(vec![]),
+        }
+    };
+    let mut config = BlocklistConfig {
+        files: files("files")?,
+        allowlist: files("allowlist")?,
+        action: BlockAction::NxDomain,
+    };
+
+    let sinkhole = table.entries.get("sinkhole");
+    if let Some(entry) = table.entries.get("action") {
+        config.action = match string(entry, &format!("{table_name}.action"))? {
+            "nxdomain" => BlockAction::NxDomain,
+            "null" => BlockAction::Null,
+            "sinkhole" => {
+                let Some(sinkhole) = sinkhole else {
+                    bail!(
+                        "line {}: {table_name}.action sinkhole needs {table_name}.sinkhole addresses",
+                        entry.line
+                    );
+                };
+                let addresses = strings(sinkhole, &format!("{table_name}.sinkhole"))?
+                    .iter()
+                    .map(|value| {
+                        value.parse::<IpAddr>().with_context(|| {
+                            format!("{table_name}.sinkhole: invalid address '{value}'")
+                        })
+                    })
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("line {}", sinkhole.line))?;
+                BlockAction::Sinkhole(addresses)
+            }
+            action => bail!(
+                "line {}: {table_name}.action must be nxdomain, null or sinkhole, found '{action}'",
+                entry.line
+            ),
+        };
+    }
+    Ok(config)
+}
+
+fn parse_socket_address(name: &str, value: &str) -> Result<SocketAddr> {
+    value
+        .parse()
+        .with_context(|| format!("{name}: invalid socket address '{value}'"))
+}
+
+// Upstreams are DNS servers or RFC 8484 endpoints over plain HTTP, encrypted transports need a
+// local proxy in front of them
+fn parse_upstream(name: &str, value: &str) -> Result<Upstream> {
+    if value.starts_with("tls://") {
+        bail!(
+            "{name}: DNS over TLS upstream '{value}' is not supported, \
+            forward to a local TLS proxy such as stunnel instead"
+        );
+    }
+    if value.starts_with("quic://") {
+        bail!(
+            "{name}: DNS over QUIC upstream '{value}' is not supported, \
+            forward to a local forwarder speaking DoQ instead"
+        );
+    }
+    if value.starts_with("https://") {
+        bail!(
+            "{name}: DNS over HTTPS upstream '{value}' is not supported, \
+            use an http:// endpoint or a local TLS proxy instead"
+        );
+    }
+    let Some(url) = value.strip_prefix("http://") else {
+        return Ok(Upstream::Dns(parse_socket_address(name, value)?));
+    };
+
+    // http://address[:port][/path], the port is 80 and the path /dns-query by default
+    let (authority, path) = match url.find('/') {
+        Some(index) => url.split_at(index),
+        None => (url, DEFAULT_DOH_PATH),
+    };
+    let address = authority
+        .parse()
+        .or_else(|_| {
+            let ip = authority.trim_start_matches('[').trim_end_matches(']');
+            ip.parse().map(|ip| SocketAddr::new(ip, 80))
+        })
+        .with_context(|| format!("{name}: invalid DNS over HTTP upstream '{value}'"))?;
+    Ok(Upstream::Http {
+        address,
+        path: path.to_string(),
+    })
+}
+
+// Commands on the control socket aren't authenticated, so only local clients may reach it
+fn check_control_address(name: &str, address: SocketAddr) -> Result<SocketAddr> {
+    if !address.ip().is_loopback() {
+        bail!("{name}: '{address}' is not a loopback address, the control socket has no authentication");
+    }
+    Ok(address)
+}
+
+fn check_keys(table: &Table, table_name: &str, allowed_keys: &[&str]) -> Result<()> {
+    for (key, entry) in &table.entries {
+        if !allowed_keys.contains(&key.as_str()) {
+            match table_name {
+                "" => bail!("line {}: unknown key '{key}'", entry.line),
+                _ => bail!("line {}: unknown key '{key}' in [{table_name}]", entry.line),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn table<'a>(root: &'a Table, name: &str) -> Result<Option<&'a Table>> {
+    match root.entries.get(name) {
+        None => Ok(None),
+        Some(Entry {
+            value: Value::Table(table),
+            ..
+        }) => Ok(Some(table)),
+        Some(entry) => bail!(
+            "line {}: {name} must be a table, found {}",
+            entry.line,
+            entry.value.type_name()
+        ),
+    }
+}
+
+fn array_of_tables<'a>(root: &'a Table, name: &str) -> Result<Vec<&'a Table>> {
+    let Some(entry) = root.entries.get(name) else {
+        return Ok(vec![]);
+    };
+    let Value::Array(values) = &entry.value else {
+        bail!(
+            "line {}: {name} must be an array of tables ([[{name}]])",
+            entry.line
+        );
+    };
+    values
+        .iter()
+        .map(|value| match value {
+            Value::Table(table) => Ok(table),
+            _ => bail!(
+                "line {}: {name} must be an array of tables ([[{name}]])",
+                entry.line
+            ),
+        })
+        .collect()
+}
+
+fn string<'a>(entry: &'a Entry, name: &str) -> Result<&'a str> {
+    match &entry.value {
+        Value::String(value) => Ok(value),
+        value => bail!(
+            "line {}: {name} must be a string, found {}",
+            entry.line,
+            value.type_name()
+        ),
+    }
+}
+
+fn integer(entry: &Entry, name: &str, max: i64) -> Result<i64> {
+    match entry.value {
+        Value::Integer(value) if (0..=max).contains(&value) => Ok(value),
+        _ => bail!(
+            "line {}: {name} must be an integer between 0 and {max}",
+            entry.line
+        ),
+    }
+}
+
+fn required_string<'a>(table: &'a Table, table_name: &str, key: &str) -> Result<&'a str> {
+    match table.entries.get(key) {
+        Some(entry) => string(entry, &format!("{table_name}.{key}")),
+        None => bail!("line {}: missing key '{key}' in [{table_name}]", table.line),
+    }
+}
+
+fn strings<'a>(entry: &'a Entry, name: &str) -> Result<Vec<&'a str>> {
+    let Value::Array(values) = &entry.value else {
+        bail!(
+            "line {}: {name} must be an array of strings, found {}",
+            entry.line,
+            entry.value.type_name()
+        );
+    };
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(value) => Ok(value.as_str()),
+            value => bail!(
+                "line {}: {name} must be an array of strings, found {} in it",
+                entry.line,
+                value.type_name()
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::TempPath;
+
+    fn parse(text: &str) -> Config {
+        Config::parse(text, Path::new("/etc/dns")).unwrap()
+    }
+
+    #[test]
+    fn test_when_listen_and_upstreams_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+listen = ["0.0.0.0:53", "[::1]:53"]
+
+[resolver]
+upstreams = ["8.8.8.8:53", "[2001:4860:4860::8888]:53", "http://127.0.0.1:8053", "http://[::1]/dns"]
+
+[[forward]]
+suffix = "corp.internal"
+upstreams = ["10.0.0.53:53"]
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.upstreams[1].to_string(), "[2001:4860:4860::8888]:53");
+        assert_eq!(
+            config.upstreams[2],
+            Upstream::Http {
+                address: "127.0.0.1:8053".parse().unwrap(),
+                path: "/dns-query".to_string(),
+            }
+        );
+        assert_eq!(config.upstreams[3].to_string(), "http://[::1]:80/dns");
+        assert_eq!(config.forwards[0].suffix, "corp.internal.");
+    }
+
+    #[test]
+    fn test_when_cache_and_local_data_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+[cache]
+size = 0
+
+[static]
+hosts = ["hosts"]
+records = ["router.lan A 192.168.1.1", "router.lan TXT \"gateway\""]
+
+[[zones]]
+name = "Example.com"
+file = "example.com.zone"
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert_eq!(config.cache.size, 0);
+        assert_eq!(
+            config.static_records.hosts,
+            vec![PathBuf::from("/etc/dns/hosts")]
+        );
+        assert_eq!(config.static_records.records[1].label, "router.lan.");
+        assert_eq!(config.zones[0].name, "example.com.");
+        assert_eq!(
+            config.zones[0].file,
+            PathBuf::from("/etc/dns/example.com.zone")
+        );
+    }
+
+    #[test]
+    fn test_when_blocklist_and_policies_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+[blocklist]
+files = ["domains.txt"]
+action = "sinkhole"
+sinkhole = ["192.0.2.1", "2001:db8::1"]
+
+[[policies]]
+name = "kids"
+clients = ["192.168.1.0/24"]
+files = ["domains.txt", "kids.txt"]
+allowlist = ["school.txt"]
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert_eq!(
+            config.blocklist.files,
+            vec![PathBuf::from("/etc/dns/domains.txt")]
+        );
+        assert_eq!(
+            config.blocklist.action,
+            BlockAction::Sinkhole(vec![
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ])
+        );
+        assert_eq!(config.policies[0].name, "kids");
+        assert_eq!(config.policies[0].blocklist.files.len(), 2);
+        assert_eq!(
+            config.policies[0].blocklist.allowlist,
+            vec![PathBuf::from("/etc/dns/school.txt")]
+        );
+        assert_eq!(config.policies[0].blocklist.action, BlockAction::NxDomain);
+    }
+
+    #[test]
+    fn test_when_acl_and_limits_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+[acl]
+query = ["127.0.0.0/8", "::1"]
+transfer = ["127.0.0.1"]
+
+[rrl]
+responses_per_second = 5
+errors_per_second = 1
+slip = 0
+
+[limits]
+queries_per_second = 100
+concurrent_resolutions = 4
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert!(config.acl.allows_query(&"127.0.0.1".parse().unwrap()));
+        assert!(!config.acl.allows_query(&"10.0.0.1".parse().unwrap()));
+        assert!(config.acl.allows_recursion(&"10.0.0.1".parse().unwrap()));
+        assert!(config.acl.allows_transfer(&"127.0.0.1".parse().unwrap()));
+        assert!(!config.acl.allows_update(&"127.0.0.1".parse().unwrap()));
+        assert_eq!(config.rrl.responses_per_second, 5);
+        assert_eq!(config.rrl.nxdomains_per_second, None);
+        assert_eq!(config.rrl.errors_per_second, Some(1));
+        assert_eq!(config.rrl.slip, 0);
+        assert_eq!(config.rrl.ipv4_prefix, 24);
+        assert_eq!(config.limits.queries_per_second, 100);
+        assert_eq!(config.limits.concurrent_resolutions, 4);
+    }
+
+    #[test]
+    fn test_when_logs_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+[log]
+level = "debug"
+
+[query_log]
+format = "json"
+sample = 10
+file = "queries.log"
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert_eq!(config.log.level, Level::Debug);
+        let query_log = config.query_log.unwrap();
+        assert_eq!(query_log.format, QueryLogFormat::Json);
+        assert_eq!(query_log.sample, 10);
+        assert_eq!(query_log.file, Some(PathBuf::from("/etc/dns/queries.log")));
+        assert_eq!(query_log.max_files, 5);
+    }
+
+    #[test]
+    fn test_when_services_are_set_then_they_are_parsed() {
+        // Given
+        let text = r#"
+[control]
+listen = "[::1]:2054"
+
+[metrics]
+listen = "127.0.0.1:9153"
+
+[dnstap]
+socket = "/run/dnstap.sock"
+
+[doh]
+listen = "127.0.0.1:8053"
+"#;
+        // When
+        let config = parse(text);
+        // Then
+        assert_eq!(config.control.unwrap().to_string(), "[::1]:2054");
+        assert_eq!(config.metrics.unwrap().to_string(), "127.0.0.1:9153");
+        let dnstap = config.dnstap.unwrap();
+        assert_eq!(
+            dnstap.output,
+            DnstapOutput::Socket(PathBuf::from("/run/dnstap.sock"))
+        );
+        assert_eq!(dnstap.identity, "dns-starter-rust");
+        let doh = config.doh.unwrap();
+        assert_eq!(doh.listen.to_string(), "127.0.0.1:8053");
+        assert_eq!(doh.path, "/dns-query");
+    }
+
+    #[test]
+    fn test_when_config_file_is_yaml_then_it_is_read_like_toml() {
+        // Given
+        let toml = r#"
+listen = ["127.0.0.1:2053", "[::1]:2053"]
+
+[resolver]
+upstreams = ["8.8.8.8:53", "http://[::1]/dns"]
+
+[[forward]]
+suffix = "corp.internal"
+upstreams = ["10.0.0.53:53"]
+
+[static]
+records = ["router.lan A 192.168.1.1", "nas.lan TXT \"backups\""]
+
+[[zones]]
+name = "example.com"
+file = "example.com.zone"
+
+[acl]
+query = ["127.0.0.0/8", "::1"]
+
+[rrl]
+responses_per_second = 5
+"#;
+        let yaml = r#"
+listen: ["127.0.0.1:2053", "[::1]:2053"]
+
+resolver:
+  upstreams:
+    - 8.8.8.8:53
+    - http://[::1]/dns
+
+forward:
+  - suffix: corp.internal
+    upstreams: [10.0.0.53:53]
+
+static:
+  records:
+    - router.lan A 192.168.1.1
+    - 'nas.lan TXT "backups"'
+
+zones:
+  - name: example.com
+    file: example.com.zone
+
+acl:
+  query: [127.0.0.0/8, "::1"]
+
+rrl:
+  responses_per_second: 5
+"#;
+        let directory = TempPath::directory("config");
+        fs::write(directory.join("dns.toml"), toml).unwrap();
+        fs::write(directory.join("dns.yaml"), yaml).unwrap();
+        // When
+        let toml_config = Config::load(&directory.join("dns.toml"));
+        let yaml_config = Config::load(&directory.join("dns.yaml"));
+        // Then
+        assert_eq!(
+            format!("{:?}", yaml_config.unwrap()),
+            format!("{:?}", toml_config.unwrap())
+        );
+    }
+
+    #[test]
+    fn test_when_config_is_invalid_then_error_is_precise() {
+        // Given
+        let inputs = [
+            (
+                "[cache]\nsise = 1\n",
+                "line 2: unknown key 'sise' in [cache]",
+            ),
+            (
+                "cache = 1\n",
+                "line 1: cache must be a table, found an integer",
+            ),
+            (
+                "listen = [\"localhost:53\"]\n",
+                "line 1: listen: invalid socket address 'localhost:53'",
+            ),
+            (
+                "[[zones]]\nname = \"a.com\"\n",
+                "line 1: missing key 'file' in [zones]",
+            ),
+            (
+                "[static]\nrecords = [\"router.lan A 192.168.1\"]\n",
+                "line 2: static.records: invalid record 'router.lan A 192.168.1'",
+            ),
+            (
+                "[blocklist]\naction = \"sinkhole\"\n",
+                "line 2: blocklist.action sinkhole needs blocklist.sinkhole addresses",
+            ),
+            (
+                "[acl]\nquery = [\"10.0.0.0/40\"]\n",
+                "line 2: acl.query: prefix length of CIDR '10.0.0.0/40' is greater than 32",
+            ),
+            (
+                "[dnstap]\nidentity = \"ns1\"\n",
+                "line 1: [dnstap] needs either a socket or a file",
+            ),
+            (
+                "[control]\nlisten = \"0.0.0.0:2054\"\n",
+                "line 2: control.listen: '0.0.0.0:2054' is not a loopback address",
+            ),
+            (
+                "[rrl]\nipv4_prefix = 33\n",
+                "line 2: rrl.ipv4_prefix must be an integer between 0 and 32",
+            ),
+            (
+                "[doh]\nlisten = \"127.0.0.1:8053\"\npath = \"dns-query\"\n",
+                "line 3: doh.path must start with '/'",
+            ),
+            (
+                "[doh]\npath = \"/dns-query\"\nlisten = \"localhost:8053\"\n",
+                "line 3: doh.listen: invalid socket address 'localhost:8053'",
+            ),
+            (
+                "[resolver]\nupstreams = [\"tls://1.1.1.1:853\"]\n",
+                "line 2: resolver.upstreams: DNS over TLS upstream 'tls://1.1.1.1:853' is not supported",
+            ),
+            (
+                "[[forward]]\nsuffix = \"lan\"\nupstreams = [\"quic://[::1]:853\"]\n",
+                "line 3: forward.upstreams: DNS over QUIC upstream 'quic://[::1]:853' is not supported",
+            ),
+            (
+                "[resolver]\nupstreams = [\"http://dns.google/dns-query\"]\n",
+                "line 2: resolver.upstreams: invalid DNS over HTTP upstream 'http://dns.google/dns-query'",
+            ),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = format!("{:#}", Config::parse(input, Path::new("")).unwrap_err());
+            // Then
+            assert!(error.starts_with(expected_error), "{error}");
+        }
+    }
+}
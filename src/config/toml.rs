@@ -0,0 +1,352 @@
+// A parser for the subset of TOML used by configuration files: tables, arrays of
+// tables, and keys holding strings, integers, booleans or arrays of those.
+use anyhow::{bail, Result};
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not, tag, take_while, take_while1},
+    character::complete::{char, digit1, line_ending, not_line_ending, one_of, space0},
+    combinator::{eof, map, map_res, opt, recognize, value},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub line: usize,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub line: usize,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table> {
+    let line_at =
+        |remaining: &str| input[..input.len() - remaining.len()].matches('\n').count() + 1;
+
+    let mut root = Table {
+        line: 1,
+        entries: BTreeMap::new(),
+    };
+    let mut current_path: Vec<String> = vec![];
+    let mut remaining = input;
+
+    loop {
+        remaining = blank(remaining).map(|(rest, _)| rest).unwrap_or(remaining);
+        if remaining.is_empty() {
+            break;
+        }
+        let line = line_at(remaining);
+
+        if let Ok((rest, path)) = table_header(remaining) {
+            let is_array_of_tables = remaining.starts_with("[[");
+            insert_table(&mut root, &path, is_array_of_tables, line)?;
+            current_path = path;
+            remaining = rest;
+            continue;
+        }
+
+        let (rest, (key, value)) = match key_value(remaining) {
+            Ok(parsed) => parsed,
+            Err(_) => bail!(
+                "line {line}: expected a table header or a 'key = value' pair, found '{}'",
+                remaining.lines().next().unwrap_or_default().trim()
+            ),
+        };
+        let (rest, _) = match end_of_line(rest) {
+            Ok(parsed) => parsed,
+            Err(_) => bail!(
+                "line {}: unexpected '{}' after the value of '{key}'",
+                line_at(rest),
+                rest.lines().next().unwrap_or_default().trim()
+            ),
+        };
+
+        let table = current_table(&mut root, &current_path);
+        if table.entries.contains_key(&key) {
+            bail!("line {line}: duplicate key '{key}'");
+        }
+        table.entries.insert(key, Entry { line, value });
+        remaining = rest;
+    }
+
+    Ok(root)
+}
+
+fn insert_table(
+    root: &mut Table,
+    path: &[String],
+    is_array_of_tables: bool,
+    line: usize,
+) -> Result<()> {
+    let (last, parents) = path.split_last().unwrap();
+    let parent = current_table(root, parents);
+    let new_table = Table {
+        line,
+        entries: BTreeMap::new(),
+    };
+
+    match (parent.entries.get_mut(last), is_array_of_tables) {
+        (None, false) => {
+            let value = Value::Table(new_table);
+            parent.entries.insert(last.clone(), Entry { line, value });
+        }
+        (None, true) => {
+            let value = Value::Array(vec![Value::Table(new_table)]);
+            parent.entries.insert(last.clone(), Entry { line, value });
+        }
+        (Some(entry), true) => match &mut entry.value {
+            Value::Array(tables) if tables.iter().all(|t| matches!(t, Value::Table(_))) => {
+                tables.push(Value::Table(new_table));
+            }
+            _ => bail!("line {line}: '{last}' is not an array of tables"),
+        },
+        (Some(_), false) => bail!("line {line}: table '{}' is defined twice", path.join(".")),
+    }
+    Ok(())
+}
+
+// Follows the path from the root, entering the last table of arrays of tables
+fn current_table<'a>(root: &'a mut Table, path: &[String]) -> &'a mut Table {
+    let mut table = root;
+    for key in path {
+        let entry = table.entries.get_mut(key).unwrap();
+        table = match &mut entry.value {
+            Value::Table(table) => table,
+            Value::Array(values) => match values.last_mut() {
+                Some(Value::Table(table)) => table,
+                _ => unreachable!("table headers only create arrays of tables"),
+            },
+            _ => unreachable!("table headers only create tables"),
+        };
+    }
+    table
+}
+
+fn comment(input: &str) -> IResult<&str, &str> {
+    preceded(char('#'), not_line_ending)(input)
+}
+
+// Whitespace, newlines and comments
+fn blank(input: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        many0(alt((take_while1(|c: char| c.is_whitespace()), comment))),
+    )(input)
+}
+
+fn end_of_line(input: &str) -> IResult<&str, ()> {
+    value((), tuple((space0, opt(comment), alt((line_ending, eof)))))(input)
+}
+
+fn bare_key(input: &str) -> IResult<&str, String> {
+    map(
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+        String::from,
+    )(input)
+}
+
+fn key(input: &str) -> IResult<&str, String> {
+    alt((bare_key, basic_string, literal_string))(input)
+}
+
+fn table_path(input: &str) -> IResult<&str, Vec<String>> {
+    let (input, first) = delimited(space0, key, space0)(input)?;
+    let (input, mut rest) = many0(preceded(char('.'), delimited(space0, key, space0)))(input)?;
+    rest.insert(0, first);
+    Ok((input, rest))
+}
+
+fn table_header(input: &str) -> IResult<&str, Vec<String>> {
+    terminated(
+        alt((
+            delimited(tag("[["), table_path, tag("]]")),
+            delimited(char('['), table_path, char(']')),
+        )),
+        end_of_line,
+    )(input)
+}
+
+fn key_value(input: &str) -> IResult<&str, (String, Value)> {
+    pair(
+        terminated(key, tuple((space0, char('='), space0))),
+        toml_value,
+    )(input)
+}
+
+fn basic_string(input: &str) -> IResult<&str, String> {
+    map(
+        delimited(
+            char('"'),
+            opt(escaped_transform(
+                is_not("\\\"\n"),
+                '\\',
+                alt((
+                    value("\\", char('\\')),
+                    value("\"", char('"')),
+                    value("\n", char('n')),
+                    value("\t", char('t')),
+                )),
+            )),
+            char('"'),
+        ),
+        Option::unwrap_or_default,
+    )(input)
+}
+
+fn literal_string(input: &str) -> IResult<&str, String> {
+    map(
+        delimited(
+            char('\''),
+            take_while(|c| c != '\'' && c != '\n'),
+            char('\''),
+        ),
+        String::from,
+    )(input)
+}
+
+fn integer(input: &str) -> IResult<&str, i64> {
+    map_res(
+        recognize(pair(
+            opt(one_of("+-")),
+            pair(digit1, many0(alt((digit1, tag("_"))))),
+        )),
+        |digits: &str| digits.replace('_', "").parse::<i64>(),
+    )(input)
+}
+
+fn boolean(input: &str) -> IResult<&str, bool> {
+    alt((value(true, tag("true")), value(false, tag("false"))))(input)
+}
+
+fn array(input: &str) -> IResult<&str, Vec<Value>> {
+    let (mut input, _) = pair(char('['), blank)(input)?;
+    let mut values = vec![];
+    loop {
+        if let Ok((rest, _)) = char::<&str, nom::error::Error<&str>>(']')(input) {
+            return Ok((rest, values));
+        }
+        let (rest, value) = terminated(toml_value, blank)(input)?;
+        values.push(value);
+        // Values are separated by commas, and a trailing comma is allowed
+        match pair(char(','), blank)(rest) {
+            Ok((rest, _)) => input = rest,
+            Err(_) => {
+                let (rest, _) = char(']')(rest)?;
+                return Ok((rest, values));
+            }
+        }
+    }
+}
+
+fn toml_value(input: &str) -> IResult<&str, Value> {
+    alt((
+        map(basic_string, Value::String),
+        map(literal_string, Value::String),
+        map(boolean, Value::Boolean),
+        map(integer, Value::Integer),
+        map(array, Value::Array),
+    ))(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# Listeners
+listen = ["127.0.0.1:2053", "[::1]:2053",] # trailing comma
+
+[cache]
+size = 1_024
+
+[[zones]]
+name = "example.com"
+file = 'zones/example.com.zone'
+
+[[zones]]
+name = "corp.internal"
+file = "zones/corp.zone"
+"#;
+
+    #[test]
+    fn test_when_config_has_tables_and_arrays_then_they_are_parsed() {
+        // Given
+        // When
+        let table = parse(CONFIG).unwrap();
+        // Then
+        let listen = &table.entries["listen"];
+        assert_eq!(listen.line, 3);
+        assert_eq!(
+            listen.value,
+            Value::Array(vec![
+                Value::String("127.0.0.1:2053".to_string()),
+                Value::String("[::1]:2053".to_string()),
+            ])
+        );
+        let Value::Table(cache) = &table.entries["cache"].value else {
+            panic!("cache is not a table");
+        };
+        assert_eq!(cache.entries["size"].value, Value::Integer(1024));
+        let Value::Array(zones) = &table.entries["zones"].value else {
+            panic!("zones is not an array");
+        };
+        assert_eq!(zones.len(), 2);
+        let Value::Table(zone) = &zones[1] else {
+            panic!("zone is not a table");
+        };
+        assert_eq!(zone.line, 12);
+        assert_eq!(
+            zone.entries["file"].value,
+            Value::String("zones/corp.zone".to_string())
+        );
+    }
+
+    #[test]
+    fn test_when_config_is_malformed_then_error_has_line_number() {
+        // Given
+        let inputs = [
+            ("[cache]\nsize = \n", "line 2: expected a table header"),
+            ("a = 1\na = 2\n", "line 2: duplicate key 'a'"),
+            (
+                "[cache]\n[cache]\n",
+                "line 2: table 'cache' is defined twice",
+            ),
+            (
+                "a = \"x\" y\n",
+                "line 1: unexpected 'y' after the value of 'a'",
+            ),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = parse(input).unwrap_err().to_string();
+            // Then
+            assert!(error.starts_with(expected_error), "{error}");
+        }
+    }
+}
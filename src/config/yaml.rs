@@ -0,0 +1,396 @@
+// A parser for the subset of YAML used by configuration files: block mappings, block
+// sequences, flow sequences and scalars, read into the same tables as TOML. Plain scalars
+// are integers, booleans or strings; anchors, tags, flow mappings and multi-line scalars
+// aren't supported.
+use super::toml::{Entry, Table, Value};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+pub fn parse(input: &str) -> Result<Table> {
+    let mut lines = lines(input)?;
+    if lines.is_empty() {
+        return Ok(Table {
+            line: 1,
+            entries: BTreeMap::new(),
+        });
+    }
+    if lines[0].indent > 0 {
+        bail!("line {}: unexpected indentation", lines[0].number);
+    }
+    let mut position = 0;
+    let root = match block(&mut lines, &mut position, 0, 1)? {
+        Value::Table(table) => table,
+        _ => bail!("line 1: expected a mapping of keys to values"),
+    };
+    if let Some(line) = lines.get(position) {
+        bail!("line {}: unexpected indentation", line.number);
+    }
+    Ok(root)
+}
+
+// The lines holding something, without their comments, and without the document markers
+fn lines(input: &str) -> Result<Vec<Line>> {
+    let mut lines = vec![];
+    for (index, text) in input.lines().enumerate() {
+        let number = index + 1;
+        let text = without_comment(text).trim_end();
+        let content = text.trim_start_matches(' ');
+        if content.starts_with('\t') {
+            bail!("line {number}: tabs can't be used for indentation");
+        }
+        if content.is_empty() || text == "---" || text == "..." {
+            continue;
+        }
+        if content.starts_with('%') {
+            bail!("line {number}: directives are not supported");
+        }
+        lines.push(Line {
+            number,
+            indent: text.len() - content.len(),
+            text: content.to_string(),
+        });
+    }
+    Ok(lines)
+}
+
+// A comment starts with a '#' at the start of the line or after a blank, outside of quotes
+fn without_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match quote {
+            // A doubled quote is a quote in single quoted strings
+            Some('\'') if c == '\'' && chars.peek().map(|(_, c)| *c) == Some('\'') => {
+                chars.next();
+            }
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' && previous.is_whitespace() => return &text[..index],
+            None if (c == '"' || c == '\'') && " \t[,:-".contains(previous) => quote = Some(c),
+            None => {}
+        }
+        previous = c;
+    }
+    text
+}
+
+// The mapping or sequence whose lines are indented by indent, from the key or the sequence
+// item at line
+fn block(lines: &mut [Line], position: &mut usize, indent: usize, line: usize) -> Result<Value> {
+    if is_sequence_item(&lines[*position].text) {
+        sequence(lines, position, indent)
+    } else {
+        Ok(Value::Table(mapping(lines, position, indent, line)?))
+    }
+}
+
+fn is_sequence_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+fn mapping(lines: &mut [Line], position: &mut usize, indent: usize, line: usize) -> Result<Table> {
+    let mut table = Table {
+        line,
+        entries: BTreeMap::new(),
+    };
+    while let Some(current) = lines.get(*position).cloned() {
+        if current.indent < indent {
+            break;
+        }
+        if current.indent > indent {
+            bail!("line {}: unexpected indentation", current.number);
+        }
+        if is_sequence_item(&current.text) {
+            bail!(
+                "line {}: expected a 'key: value' pair, found a sequence item",
+                current.number
+            );
+        }
+        let Some((key, rest)) = key_value(&current.text) else {
+            bail!(
+                "line {}: expected a 'key: value' pair, found '{}'",
+                current.number,
+                current.text
+            );
+        };
+        *position += 1;
+
+        let value = if rest.is_empty() {
+            // The value is the block below the key, a sequence may be at the key's indentation
+            match lines.get(*position) {
+                Some(next) if next.indent > indent => {
+                    let next_indent = next.indent;
+                    block(lines, position, next_indent, current.number)?
+                }
+                Some(next) if next.indent == indent && is_sequence_item(&next.text) => {
+                    sequence(lines, position, indent)?
+                }
+                _ => bail!("line {}: '{key}' has no value", current.number),
+            }
+        } else {
+            scalar_or_flow(rest, current.number)?
+        };
+
+        if table.entries.contains_key(&key) {
+            bail!("line {}: duplicate key '{key}'", current.number);
+        }
+        table.entries.insert(
+            key,
+            Entry {
+                line: current.number,
+                value,
+            },
+        );
+    }
+    Ok(table)
+}
+
+fn sequence(lines: &mut [Line], position: &mut usize, indent: usize) -> Result<Value> {
+    let mut values = vec![];
+    while let Some(current) = lines.get(*position).cloned() {
+        if current.indent < indent || !is_sequence_item(&current.text) {
+            break;
+        }
+        if current.indent > indent {
+            bail!("line {}: unexpected indentation", current.number);
+        }
+        let content = current.text[1..].trim_start_matches(' ');
+
+        let value = if content.is_empty() {
+            *position += 1;
+            match lines.get(*position) {
+                Some(next) if next.indent > indent => {
+                    let next_indent = next.indent;
+                    block(lines, position, next_indent, current.number)?
+                }
+                _ => bail!("line {}: sequence item without a value", current.number),
+            }
+        } else if is_sequence_item(content) || key_value(content).is_some() {
+            // The item is a block starting on the line of its dash, so its lines are indented
+            // up to the content
+            let content_indent = current.indent + current.text.len() - content.len();
+            lines[*position] = Line {
+                number: current.number,
+                indent: content_indent,
+                text: content.to_string(),
+            };
+            block(lines, position, content_indent, current.number)?
+        } else {
+            *position += 1;
+            scalar_or_flow(content, current.number)?
+        };
+        values.push(value);
+    }
+    Ok(Value::Array(values))
+}
+
+// The key and the rest of the line after it, when the text is a 'key: value' pair
+fn key_value(text: &str) -> Option<(String, &str)> {
+    let (key, rest) = if text.starts_with('"') || text.starts_with('\'') {
+        quoted(text).ok()?
+    } else {
+        let separator = text
+            .find(": ")
+            .or_else(|| text.ends_with(':').then(|| text.len() - 1));
+        let separator = separator?;
+        (text[..separator].trim_end().to_string(), &text[separator..])
+    };
+    match rest.strip_prefix(':') {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => Some((key, rest.trim())),
+        _ => None,
+    }
+}
+
+fn scalar_or_flow(text: &str, line: usize) -> Result<Value> {
+    let (value, rest) = if text.starts_with('[') {
+        flow_sequence(text, line)?
+    } else if text.starts_with('"') || text.starts_with('\'') {
+        let (string, rest) =
+            quoted(text).map_err(|error| anyhow::anyhow!("line {line}: {error}"))?;
+        (Value::String(string), rest)
+    } else {
+        (plain(text, line)?, "")
+    };
+    if !rest.trim().is_empty() {
+        bail!("line {line}: unexpected '{}' after the value", rest.trim());
+    }
+    Ok(value)
+}
+
+// A sequence written on one line, as in TOML: [a, "b", 3]
+fn flow_sequence(text: &str, line: usize) -> Result<(Value, &str)> {
+    let mut values = vec![];
+    let mut rest = text[1..].trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            return Ok((Value::Array(values), after));
+        }
+        if rest.is_empty() {
+            bail!("line {line}: unterminated flow sequence");
+        }
+        let (value, after) = if rest.starts_with('[') {
+            flow_sequence(rest, line)?
+        } else if rest.starts_with('"') || rest.starts_with('\'') {
+            let (string, after) =
+                quoted(rest).map_err(|error| anyhow::anyhow!("line {line}: {error}"))?;
+            (Value::String(string), after)
+        } else {
+            let end = rest.find([',', ']']).unwrap_or(rest.len());
+            (plain(rest[..end].trim_end(), line)?, &rest[end..])
+        };
+        values.push(value);
+        rest = after.trim_start();
+        // Values are separated by commas, and a trailing comma is allowed
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.is_empty() && !rest.starts_with(']') {
+            bail!("line {line}: expected ',' or ']' in flow sequence");
+        }
+    }
+}
+
+// A double quoted string with escapes, or a single quoted one where '' is a quote, and the
+// text after it
+fn quoted(text: &str) -> Result<(String, &str)> {
+    let mut chars = text.char_indices();
+    let Some((_, quote)) = chars.next() else {
+        bail!("expected a quoted string");
+    };
+    let mut string = String::new();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\'' if quote == '\'' => {
+                if text[index + 1..].starts_with('\'') {
+                    chars.next();
+                    string.push('\'');
+                } else {
+                    return Ok((string, &text[index + 1..]));
+                }
+            }
+            '"' if quote == '"' => return Ok((string, &text[index + 1..])),
+            '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                Some('\\') => string.push('\\'),
+                Some('"') => string.push('"'),
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some(c) => bail!("unsupported escape '\\{c}'"),
+                None => break,
+            },
+            c => string.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
+
+fn plain(text: &str, line: usize) -> Result<Value> {
+    if let Some(c) = text.chars().next().filter(|c| "&*!|>{@`".contains(*c)) {
+        bail!("line {line}: unsupported YAML value starting with '{c}'");
+    }
+    if text == "true" || text == "false" {
+        return Ok(Value::Boolean(text == "true"));
+    }
+    let digits = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        return match text.parse() {
+            Ok(integer) => Ok(Value::Integer(integer)),
+            Err(_) => bail!("line {line}: integer '{text}' is out of range"),
+        };
+    }
+    Ok(Value::String(text.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# Listeners
+listen: ["127.0.0.1:2053", "[::1]:2053",] # trailing comma
+
+cache:
+  size: 1024
+
+zones:
+  - name: example.com
+    file: 'zones/example.com.zone'
+  - name: "corp.internal"
+    file: zones/corp.zone
+static:
+  records:
+  - router.lan A 192.168.1.1
+  - 'nas.lan TXT "it''s #1"'
+"#;
+
+    #[test]
+    fn test_when_config_has_mappings_and_sequences_then_they_are_parsed() {
+        // Given
+        // When
+        let table = parse(CONFIG).unwrap();
+        // Then
+        let listen = &table.entries["listen"];
+        assert_eq!(listen.line, 3);
+        assert_eq!(
+            listen.value,
+            Value::Array(vec![
+                Value::String("127.0.0.1:2053".to_string()),
+                Value::String("[::1]:2053".to_string()),
+            ])
+        );
+        let Value::Table(cache) = &table.entries["cache"].value else {
+            panic!("cache is not a table");
+        };
+        assert_eq!(cache.line, 5);
+        assert_eq!(cache.entries["size"].value, Value::Integer(1024));
+        let Value::Array(zones) = &table.entries["zones"].value else {
+            panic!("zones is not an array");
+        };
+        assert_eq!(zones.len(), 2);
+        let Value::Table(zone) = &zones[1] else {
+            panic!("zone is not a table");
+        };
+        assert_eq!(zone.line, 11);
+        assert_eq!(
+            zone.entries["file"].value,
+            Value::String("zones/corp.zone".to_string())
+        );
+        let Value::Table(static_records) = &table.entries["static"].value else {
+            panic!("static is not a table");
+        };
+        assert_eq!(
+            static_records.entries["records"].value,
+            Value::Array(vec![
+                Value::String("router.lan A 192.168.1.1".to_string()),
+                Value::String("nas.lan TXT \"it's #1\"".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_when_config_is_malformed_then_error_has_line_number() {
+        // Given
+        let inputs = [
+            ("cache:\n  size\n", "line 2: expected a 'key: value' pair"),
+            ("a: 1\na: 2\n", "line 2: duplicate key 'a'"),
+            ("cache:\nsize: 1\n", "line 1: 'cache' has no value"),
+            ("a: 1\n  b: 2\n", "line 2: unexpected indentation"),
+            ("a: [1, 2\n", "line 1: unterminated flow sequence"),
+            ("a: \"x\" y\n", "line 1: unexpected 'y' after the value"),
+            ("a: &anchor 1\n", "line 1: unsupported YAML value"),
+            ("- a\n", "line 1: expected a mapping of keys to values"),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = parse(input).unwrap_err().to_string();
+            // Then
+            assert!(error.starts_with(expected_error), "{error}");
+        }
+    }
+}
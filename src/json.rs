@@ -0,0 +1,246 @@
+// A parser and writer for JSON documents, with integers as the only numbers
+use anyhow::{bail, Result};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while_m_n},
+    character::complete::{char, digit1, multispace0, none_of},
+    combinator::{cut, map, map_opt, map_res, opt, recognize, value},
+    multi::{fold_many0, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    IResult,
+};
+use std::fmt::{self, Write as _};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    String(String),
+    Array(Vec<Value>),
+    // Members keep their order, so documents are written as they were built
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Boolean(_) => "a boolean",
+            Value::Integer(_) => "an integer",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+
+    // The value of the first member with this name, when the value is an object
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        let Value::Object(members) = self else {
+            return None;
+        };
+        members
+            .iter()
+            .find(|(member, _)| member == name)
+            .map(|(_, value)| value)
+    }
+}
+
+// Compact JSON, without whitespace between tokens
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Boolean(boolean) => write!(f, "{boolean}"),
+            Value::Integer(integer) => write!(f, "{integer}"),
+            Value::String(string) => write!(f, "{}", quoted(string)),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(","))
+            }
+            Value::Object(members) => {
+                let members: Vec<String> = members
+                    .iter()
+                    .map(|(name, value)| format!("{}:{value}", quoted(name)))
+                    .collect();
+                write!(f, "{{{}}}", members.join(","))
+            }
+        }
+    }
+}
+
+fn quoted(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn parse(input: &str) -> Result<Value> {
+    let line_at =
+        |remaining: &str| input[..input.len() - remaining.len()].matches('\n').count() + 1;
+
+    match delimited(multispace0, json_value, multispace0)(input) {
+        Ok(("", value)) => Ok(value),
+        Ok((rest, _)) => bail!(
+            "line {}: unexpected '{}' after the JSON value",
+            line_at(rest),
+            rest.chars().next().unwrap_or_default()
+        ),
+        Err(nom::Err::Error(error) | nom::Err::Failure(error)) => {
+            bail!("line {}: invalid JSON value", line_at(error.input))
+        }
+        Err(nom::Err::Incomplete(_)) => bail!("incomplete JSON value"),
+    }
+}
+
+fn json_value(input: &str) -> IResult<&str, Value> {
+    alt((
+        value(Value::Null, tag("null")),
+        value(Value::Boolean(true), tag("true")),
+        value(Value::Boolean(false), tag("false")),
+        map(integer, Value::Integer),
+        map(string, Value::String),
+        map(array, Value::Array),
+        map(object, Value::Object),
+    ))(input)
+}
+
+fn integer(input: &str) -> IResult<&str, i64> {
+    map_res(recognize(pair(opt(char('-')), digit1)), str::parse)(input)
+}
+
+// A \uXXXX escape, where characters outside the basic plane are a surrogate pair
+fn unicode_escape(input: &str) -> IResult<&str, char> {
+    let hex = |input| {
+        map_res(
+            preceded(
+                tag("\\u"),
+                take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()),
+            ),
+            |hex| u32::from_str_radix(hex, 16),
+        )(input)
+    };
+    let (rest, high) = hex(input)?;
+    if (0xd800..0xdc00).contains(&high) {
+        map_opt(hex, |low| {
+            char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low.checked_sub(0xdc00)? & 0x3ff))
+        })(rest)
+    } else {
+        map_opt(|input| Ok((input, high)), char::from_u32)(rest)
+    }
+}
+
+fn string(input: &str) -> IResult<&str, String> {
+    let character = alt((
+        unicode_escape,
+        preceded(
+            char('\\'),
+            alt((
+                value('"', char('"')),
+                value('\\', char('\\')),
+                value('/', char('/')),
+                value('\u{8}', char('b')),
+                value('\u{c}', char('f')),
+                value('\n', char('n')),
+                value('\r', char('r')),
+                value('\t', char('t')),
+            )),
+        ),
+        none_of("\"\\"),
+    ));
+    delimited(
+        char('"'),
+        fold_many0(character, String::new, |mut string, c| {
+            string.push(c);
+            string
+        }),
+        char('"'),
+    )(input)
+}
+
+// Values after a comma must be there, so errors point at the value rather than at the array
+fn array(input: &str) -> IResult<&str, Vec<Value>> {
+    let (input, _) = pair(char('['), multispace0)(input)?;
+    if let Ok((rest, _)) = char::<&str, nom::error::Error<&str>>(']')(input) {
+        return Ok((rest, vec![]));
+    }
+    terminated(
+        separated_list1(
+            char(','),
+            cut(delimited(multispace0, json_value, multispace0)),
+        ),
+        cut(char(']')),
+    )(input)
+}
+
+fn object(input: &str) -> IResult<&str, Vec<(String, Value)>> {
+    let (input, _) = pair(char('{'), multispace0)(input)?;
+    if let Ok((rest, _)) = char::<&str, nom::error::Error<&str>>('}')(input) {
+        return Ok((rest, vec![]));
+    }
+    let member = separated_pair(
+        delimited(multispace0, string, multispace0),
+        char(':'),
+        delimited(multispace0, json_value, multispace0),
+    );
+    terminated(separated_list1(char(','), cut(member)), cut(char('}')))(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_document_is_parsed_then_it_is_written_back() {
+        // Given
+        let input = r#" { "ID": 1234, "QR": true, "names": ["a.example.", "é😀 \"\\"],
+            "escaped": "\u00e9\ud83d\ude00\n", "empty": {}, "none": null, "list": [ ] } "#;
+        // When
+        let value = parse(input).unwrap();
+        // Then
+        assert_eq!(value.get("ID"), Some(&Value::Integer(1234)));
+        assert_eq!(
+            value.get("names"),
+            Some(&Value::Array(vec![
+                Value::String("a.example.".to_string()),
+                Value::String("é😀 \"\\".to_string()),
+            ]))
+        );
+        assert_eq!(
+            value.get("escaped"),
+            Some(&Value::String("é😀\n".to_string()))
+        );
+        assert_eq!(
+            value.to_string(),
+            r#"{"ID":1234,"QR":true,"names":["a.example.","é😀 \"\\"],"escaped":"é😀\n","empty":{},"none":null,"list":[]}"#
+        );
+    }
+
+    #[test]
+    fn test_when_document_is_malformed_then_error_has_line_number() {
+        // Given
+        let inputs = [
+            ("{\"a\": 1,\n\"b\": }", "line 2: invalid JSON value"),
+            ("[1, 2] 3", "line 1: unexpected '3' after the JSON value"),
+            ("{\"a\": 1.5}", "line 1: invalid JSON value"),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = parse(input).unwrap_err().to_string();
+            // Then
+            assert_eq!(error, expected_error);
+        }
+    }
+}
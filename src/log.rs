@@ -0,0 +1,85 @@
+use anyhow::{bail, Error};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let level = match string.to_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            _ => bail!("unknown log level '{string}', expected error, warn, info or debug"),
+        };
+        Ok(level)
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn is_enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::is_enabled($level) {
+            eprintln!("[{:?}] {}", $level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { log!($crate::log::Level::Error, $($arg)*) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_level_is_parsed_then_it_orders_from_error_to_debug() {
+        // Given
+        // When
+        let levels: Vec<Level> = ["ERROR", "warn", "Info", "debug"]
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        // Then the levels up to the configured one are enabled
+        assert_eq!(
+            levels,
+            [Level::Error, Level::Warn, Level::Info, Level::Debug]
+        );
+        assert!(levels.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            "trace".parse::<Level>().unwrap_err().to_string(),
+            "unknown log level 'trace', expected error, warn, info or debug"
+        );
+    }
+}
@@ -1,15 +1,47 @@
-use anyhow::Result;
-use cli_params::CliParam;
-use server::{resolver::Resolver, start_server};
-use std::env;
+use cli_params::Command;
+use std::{env, process::ExitCode};
+
+#[macro_use]
+mod log;
 
 mod cli_params;
+mod commands;
+mod config;
+mod json;
 mod server;
+#[cfg(test)]
+mod test_utils;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
-    let cli_params = CliParam::from(&args[1..]);
-    let resolver = Resolver::from(cli_params.as_slice());
+    let command = match Command::from(&args[1..]) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("error: {error:#}\n\n{}", cli_params::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match command {
+        Command::Serve(params) => commands::serve(params),
+        Command::Query(params) => commands::query(&params),
+        Command::CheckZone { origin, file } => commands::check_zone(&origin, &file),
+        Command::Reload { control } => commands::reload(&control),
+        Command::Help(usage) => {
+            println!("{usage}");
+            Ok(())
+        }
+        Command::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    };
 
-    start_server(resolver)
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error:#}");
+            ExitCode::FAILURE
+        }
+    }
 }
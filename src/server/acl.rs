@@ -0,0 +1,190 @@
+use anyhow::{bail, Context, Error, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            // IPv4 clients on dual stack sockets are seen as mapped IPv6 addresses
+            (IpAddr::V4(_), IpAddr::V6(address)) => address
+                .to_ipv4_mapped()
+                .is_some_and(|address| self.contains(&IpAddr::V4(address))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    // Accepts "10.0.0.0/8", "fd00::/8", or a single address
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match string.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (string, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("invalid address in CIDR '{string}'"))?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse::<u8>()
+                .with_context(|| format!("invalid prefix length in CIDR '{string}'"))?,
+            None => max_prefix_length,
+        };
+        if prefix_length > max_prefix_length {
+            bail!("prefix length of CIDR '{string}' is greater than {max_prefix_length}");
+        }
+        Ok(Cidr {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+// Who may query, get recursion, transfer zones and update them. Everyone may query and get
+// recursion by default, while transfers and updates must be allowed explicitly.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    pub query: Vec<Cidr>,
+    pub recursion: Vec<Cidr>,
+    pub transfer: Vec<Cidr>,
+    pub update: Vec<Cidr>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        let everyone: Vec<Cidr> = vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        Acl {
+            query: everyone.clone(),
+            recursion: everyone,
+            transfer: vec![],
+            update: vec![],
+        }
+    }
+}
+
+impl Acl {
+    pub fn allows_query(&self, address: &IpAddr) -> bool {
+        contains(&self.query, address)
+    }
+
+    pub fn allows_recursion(&self, address: &IpAddr) -> bool {
+        contains(&self.recursion, address)
+    }
+
+    pub fn allows_transfer(&self, address: &IpAddr) -> bool {
+        contains(&self.transfer, address)
+    }
+
+    pub fn allows_update(&self, address: &IpAddr) -> bool {
+        contains(&self.update, address)
+    }
+}
+
+fn contains(cidrs: &[Cidr], address: &IpAddr) -> bool {
+    cidrs.iter().any(|cidr| cidr.contains(address))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_address_is_in_cidr_then_it_is_contained() {
+        // Given
+        let cidr: Cidr = "192.168.1.0/24".parse().unwrap();
+        // When
+        // Then
+        assert!(cidr.contains(&"192.168.1.42".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:192.168.1.42".parse().unwrap()));
+        assert!(!cidr.contains(&"192.168.2.1".parse().unwrap()));
+        assert!(!cidr.contains(&"fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_when_cidr_is_malformed_then_parsing_fails() {
+        // Given
+        // When
+        // Then
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("fd00::/x".parse::<Cidr>().is_err());
+        assert_eq!(
+            "::1".parse::<Cidr>().unwrap(),
+            Cidr {
+                address: "::1".parse().unwrap(),
+                prefix_length: 128
+            }
+        );
+    }
+
+    #[test]
+    fn test_when_cidr_is_ipv6_or_everything_then_prefix_decides_containment() {
+        // Given
+        let network: Cidr = "2001:db8::/32".parse().unwrap();
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        // When
+        // Then
+        assert!(network.contains(&"2001:db8:ffff::1".parse().unwrap()));
+        assert!(!network.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(!network.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(everything.contains(&"255.255.255.255".parse().unwrap()));
+        assert!(single.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!single.contains(&"192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_when_acl_is_default_then_everyone_queries_but_nobody_transfers_or_updates() {
+        // Given
+        let acl = Acl::default();
+        // When
+        // Then
+        for address in ["192.0.2.1", "2001:db8::1", "::ffff:10.0.0.1"] {
+            let address = address.parse().unwrap();
+            assert!(acl.allows_query(&address));
+            assert!(acl.allows_recursion(&address));
+            assert!(!acl.allows_transfer(&address));
+            assert!(!acl.allows_update(&address));
+        }
+    }
+
+    #[test]
+    fn test_when_acl_lists_networks_then_only_their_clients_are_allowed() {
+        // Given
+        let acl = Acl {
+            recursion: vec!["10.0.0.0/8".parse().unwrap()],
+            transfer: vec!["192.0.2.53".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..Acl::default()
+        };
+        // When
+        // Then
+        assert!(acl.allows_recursion(&"10.1.2.3".parse().unwrap()));
+        assert!(!acl.allows_recursion(&"192.0.2.53".parse().unwrap()));
+        assert!(acl.allows_query(&"192.0.2.53".parse().unwrap()));
+        assert!(acl.allows_transfer(&"192.0.2.53".parse().unwrap()));
+        assert!(acl.allows_transfer(&"fd12::1".parse().unwrap()));
+        assert!(!acl.allows_transfer(&"10.1.2.3".parse().unwrap()));
+        assert!(!acl.allows_update(&"192.0.2.53".parse().unwrap()));
+    }
+}
@@ -0,0 +1,347 @@
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::rcode::Rcode;
+use super::message::rdata::RData;
+use super::message::record_type::RecordType;
+use crate::config::BlocklistConfig;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+const BLOCKED_TTL: u32 = 60;
+
+// Names that hosts files map to themselves, which are not meant as blocked domains
+const HOSTS_SKIPPED_NAMES: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+// How blocked queries are answered
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockAction {
+    NxDomain,
+    // 0.0.0.0 for A queries and :: for AAAA queries
+    Null,
+    Sinkhole(Vec<IpAddr>),
+}
+
+// A set of domains matching themselves and all their subdomains, stored as a trie of
+// labels from the top level domain down
+#[derive(Debug, Default)]
+pub struct DomainSet {
+    root: TrieNode,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<String, TrieNode>,
+    is_domain: bool,
+}
+
+impl DomainSet {
+    pub fn insert(&mut self, domain: &str) {
+        let mut node = &mut self.root;
+        for label in labels(domain) {
+            // Subdomains of a domain already in the set are covered by it
+            if node.is_domain {
+                return;
+            }
+            node = node.children.entry(label.to_lowercase()).or_default();
+        }
+        if !node.is_domain {
+            // The subdomains already in the set are now covered by the domain
+            let covered = node.domains_count();
+            node.is_domain = true;
+            node.children.clear();
+            self.len = self.len + 1 - covered;
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let mut node = &self.root;
+        for label in labels(name) {
+            if node.is_domain {
+                return true;
+            }
+            match node.children.get(&label.to_lowercase()) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.is_domain
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl TrieNode {
+    fn domains_count(&self) -> usize {
+        self.is_domain as usize
+            + self
+                .children
+                .values()
+                .map(TrieNode::domains_count)
+                .sum::<usize>()
+    }
+}
+
+fn labels(name: &str) -> impl Iterator<Item = &str> {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .rev()
+}
+
+// Names to block and how to answer them, with allowed names overriding blocked ones
+#[derive(Debug)]
+pub struct Blocklist {
+    domains: DomainSet,
+    allowed: DomainSet,
+    action: BlockAction,
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Blocklist {
+            domains: DomainSet::default(),
+            allowed: DomainSet::default(),
+            action: BlockAction::NxDomain,
+        }
+    }
+}
+
+impl Blocklist {
+    pub fn load(config: &BlocklistConfig) -> Result<Self> {
+        Ok(Blocklist {
+            domains: load_lists(&config.files)?,
+            allowed: load_lists(&config.allowlist)?,
+            action: config.action.clone(),
+        })
+    }
+
+    pub fn blocked_count(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn allowed_count(&self) -> usize {
+        self.allowed.len()
+    }
+
+    // Returns the response to a blocked question, or None when the name is not blocked
+    pub fn check(&self, question: &Question) -> Option<(Rcode, Vec<Answer>)> {
+        if !self.domains.matches(&question.label) || self.allowed.matches(&question.label) {
+            return None;
+        }
+        let addresses = match &self.action {
+            BlockAction::NxDomain => return Some((Rcode::NxDomain, vec![])),
+            BlockAction::Null => vec![
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ],
+            BlockAction::Sinkhole(addresses) => addresses.clone(),
+        };
+        // Other record types get an empty answer
+        let answers = addresses
+            .iter()
+            .filter_map(
+                |address| match (RecordType::from(question.qtype), address) {
+                    (RecordType::A, IpAddr::V4(address)) => {
+                        Some((RecordType::A, RData::A(*address)))
+                    }
+                    (RecordType::Aaaa, IpAddr::V6(address)) => {
+                        Some((RecordType::Aaaa, RData::Aaaa(*address)))
+                    }
+                    _ => None,
+                },
+            )
+            .map(|(record_type, rdata)| {
+                Answer::new(
+                    &question.label,
+                    record_type,
+                    question.qclass,
+                    BLOCKED_TTL,
+                    &rdata,
+                )
+            })
+            .collect();
+        Some((Rcode::NoError, answers))
+    }
+}
+
+fn load_lists(paths: &[PathBuf]) -> Result<DomainSet> {
+    let mut domains = DomainSet::default();
+    for path in paths {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read domain list {}", path.display()))?;
+        for domain in parse_list(&text) {
+            domains.insert(domain);
+        }
+    }
+    Ok(domains)
+}
+
+// Reads plain domain lists, hosts files ("0.0.0.0 domain") and adblock rules ("||domain^"),
+// ignoring comments and the lines it does not understand
+fn parse_list(text: &str) -> Vec<&str> {
+    let mut domains = vec![];
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+            continue;
+        }
+
+        // Adblock rules with options or exceptions do not block the whole domain
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = rule.strip_suffix('^').filter(|domain| is_domain(domain)) {
+                domains.push(domain);
+            }
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+        if first.parse::<IpAddr>().is_ok() {
+            domains.extend(fields.filter(|name| {
+                is_domain(name) && !HOSTS_SKIPPED_NAMES.contains(&name.to_lowercase().as_str())
+            }));
+        } else if fields.next().is_none() {
+            // Wildcards in plain lists match the subdomains, like every other entry
+            let domain = first.trim_start_matches("*.");
+            if is_domain(domain) {
+                domains.push(domain);
+            }
+        }
+    }
+
+    domains
+}
+
+fn is_domain(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    !name.is_empty()
+        && name.parse::<IpAddr>().is_err()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::message::record_type::CLASS_IN;
+
+    fn question(label: &str, record_type: RecordType) -> Question {
+        Question {
+            qname: vec![],
+            qtype: record_type.into(),
+            qclass: CLASS_IN,
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_when_lists_have_different_formats_then_domains_are_read() {
+        // Given
+        let text = "\
+# plain list
+ads.example.com
+*.tracker.net
+0.0.0.0 metrics.example.org telemetry.example.org # hosts file
+127.0.0.1 localhost
+||doubleclick.net^
+||cdn.example.com^$third-party
+@@||allowed.example.com^
+[Adblock Plus 2.0]
+! comment
+not a domain
+";
+        // When
+        let domains = parse_list(text);
+        // Then
+        assert_eq!(
+            domains,
+            vec![
+                "ads.example.com",
+                "tracker.net",
+                "metrics.example.org",
+                "telemetry.example.org",
+                "doubleclick.net",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_when_domain_is_in_set_then_subdomains_match() {
+        // Given
+        let mut domains = DomainSet::default();
+        domains.insert("Example.com");
+        domains.insert("www.example.com");
+        domains.insert("ads.test.org.");
+        // When
+        // Then
+        assert_eq!(domains.len(), 2);
+        assert!(domains.matches("example.com."));
+        assert!(domains.matches("a.b.EXAMPLE.com."));
+        assert!(domains.matches("ads.test.org."));
+        assert!(!domains.matches("test.org."));
+        assert!(!domains.matches("notexample.com."));
+        assert!(!domains.matches("com."));
+    }
+
+    #[test]
+    fn test_when_domain_is_inserted_after_its_subdomains_then_they_are_not_counted() {
+        // Given
+        let mut domains = DomainSet::default();
+        domains.insert("www.example.com");
+        domains.insert("a.b.example.com");
+        domains.insert("c.b.example.com");
+        domains.insert("example.org");
+        // When
+        domains.insert("example.com");
+        domains.insert("b.example.com");
+        // Then
+        assert_eq!(domains.len(), 2);
+        assert!(domains.matches("b.example.com."));
+        assert!(domains.matches("example.org."));
+    }
+
+    #[test]
+    fn test_when_name_is_blocked_then_action_decides_the_answer() {
+        // Given
+        let mut blocklist = Blocklist::default();
+        blocklist.domains.insert("ads.example.com");
+        let sinkhole: IpAddr = "192.0.2.1".parse().unwrap();
+        // When
+        let nxdomain = blocklist.check(&question("x.ads.example.com.", RecordType::A));
+        blocklist.action = BlockAction::Null;
+        let null = blocklist.check(&question("ads.example.com.", RecordType::Aaaa));
+        let nodata = blocklist.check(&question("ads.example.com.", RecordType::Mx));
+        blocklist.action = BlockAction::Sinkhole(vec![sinkhole]);
+        let sinkholed = blocklist.check(&question("ads.example.com.", RecordType::A));
+        let allowed = blocklist.check(&question("example.com.", RecordType::A));
+        blocklist.allowed.insert("good.ads.example.com");
+        let overridden = blocklist.check(&question("www.good.ads.example.com.", RecordType::A));
+        // Then
+        assert!(matches!(nxdomain, Some((Rcode::NxDomain, answers)) if answers.is_empty()));
+        let (_, answers) = null.unwrap();
+        assert_eq!(answers[0].rdata(), RData::Aaaa(Ipv6Addr::UNSPECIFIED));
+        assert!(matches!(nodata, Some((Rcode::NoError, answers)) if answers.is_empty()));
+        let (_, answers) = sinkholed.unwrap();
+        assert_eq!(answers[0].rdata(), RData::A("192.0.2.1".parse().unwrap()));
+        assert!(allowed.is_none());
+        assert!(overridden.is_none());
+    }
+}
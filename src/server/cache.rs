@@ -0,0 +1,374 @@
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::rdata::{hex_bytes, hex_string};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type CacheKey = (String, u16, u16);
+
+struct CacheEntry {
+    answers: Vec<Answer>,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+// Keeps upstream answers until their smallest TTL expires
+pub struct Cache {
+    capacity: AtomicUsize,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity: AtomicUsize::new(capacity),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Entries over the new capacity are dropped on the next insertion
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    fn key(question: &Question) -> CacheKey {
+        (
+            question.label.to_lowercase(),
+            question.qtype,
+            question.qclass,
+        )
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    // Returns the cached answers with their TTL lowered by the time spent in the cache
+    pub fn get(&self, question: &Question) -> Option<Vec<Answer>> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = Self::key(question);
+        let now = Instant::now();
+
+        let entry = entries.get(&key)?;
+        if entry.expires_at <= now {
+            entries.remove(&key);
+            return None;
+        }
+        let elapsed = now.duration_since(entry.stored_at).as_secs() as u32;
+        let answers = entry
+            .answers
+            .iter()
+            .map(|answer| Answer {
+                ttl: answer.ttl.saturating_sub(elapsed),
+                ..answer.clone()
+            })
+            .collect();
+        Some(answers)
+    }
+
+    pub fn insert(&self, question: &Question, answers: &[Answer]) {
+        let Some(ttl) = answers.iter().map(|answer| answer.ttl).min() else {
+            return;
+        };
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        if capacity == 0 {
+            entries.clear();
+            return;
+        }
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        if entries.len() >= capacity {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        while entries.len() >= capacity {
+            // Make room by dropping the entry closest to expiring
+            let key = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = key {
+                entries.remove(&key);
+            }
+        }
+
+        entries.insert(
+            Self::key(question),
+            CacheEntry {
+                answers: answers.to_vec(),
+                stored_at: now,
+                expires_at: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    // Writes one line per entry: expiry as a unix timestamp, type, class, hex encoded name,
+    // count of answers and their hex encoded wire format. Names are hex encoded as they can
+    // hold any byte, spaces included.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut text = String::new();
+        let mut count = 0;
+        for ((label, qtype, qclass), entry) in entries.iter() {
+            if entry.expires_at <= now {
+                continue;
+            }
+            let expires_at = unix_now + entry.expires_at.duration_since(now).as_secs();
+            let elapsed = now.duration_since(entry.stored_at).as_secs() as u32;
+            let bytes: Vec<u8> = entry
+                .answers
+                .iter()
+                .flat_map(|answer| {
+                    Vec::<u8>::from(Answer {
+                        ttl: answer.ttl.saturating_sub(elapsed),
+                        ..answer.clone()
+                    })
+                })
+                .collect();
+            text.push_str(&format!(
+                "{expires_at} {qtype} {qclass} {} {} {}\n",
+                hex_string(label.as_bytes()),
+                entry.answers.len(),
+                hex_string(&bytes)
+            ));
+            count += 1;
+        }
+
+        // Write next to the file first, so an interrupted save never leaves half a cache. The
+        // temporary name appends .tmp to the whole name, so it is never the file itself nor the
+        // temporary file of another one.
+        let mut temporary_name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file path", path.display()))?
+            .to_os_string();
+        temporary_name.push(".tmp");
+        let temporary_path = path.with_file_name(temporary_name);
+        fs::write(&temporary_path, text)
+            .with_context(|| format!("failed to write {}", temporary_path.display()))?;
+        fs::rename(&temporary_path, path)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(count)
+    }
+
+    // Restores the entries saved by save, skipping those that expired in the meantime and
+    // the lines that can't be read
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+
+        for (index, line) in text.lines().enumerate() {
+            let (key, expires_at, answers) = match parse_entry(line) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    log_warn!(
+                        "Skipping cache entry at {}: line {}: {error:#}",
+                        path.display(),
+                        index + 1
+                    );
+                    continue;
+                }
+            };
+            if expires_at <= unix_now || entries.len() >= capacity {
+                continue;
+            }
+            entries.insert(
+                key,
+                CacheEntry {
+                    answers,
+                    stored_at: now,
+                    expires_at: now + Duration::from_secs(expires_at - unix_now),
+                },
+            );
+        }
+        Ok(entries.len())
+    }
+}
+
+fn parse_entry(line: &str) -> Result<(CacheKey, u64, Vec<Answer>)> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let [expires_at, qtype, qclass, label, count, hex] = fields[..] else {
+        bail!("expected 6 fields, found {}", fields.len());
+    };
+    let label = hex_bytes(label).map_err(|_| anyhow!("invalid hex encoded name"))?;
+    let label = String::from_utf8(label).context("invalid name")?;
+    let bytes = hex_bytes(hex).map_err(|_| anyhow!("invalid hex encoded answers"))?;
+    let (answers, size) = Answer::from_bytes(&bytes, count.parse()?, 0)?;
+    if size != bytes.len() {
+        bail!("answers do not match their count");
+    }
+
+    let key = (label, qtype.parse()?, qclass.parse()?);
+    Ok((key, expires_at.parse()?, answers))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::message::rdata::RData;
+    use crate::server::message::record_type::{RecordType, CLASS_IN};
+    use crate::test_utils::TempPath;
+
+    #[test]
+    fn test_when_cache_is_saved_then_it_is_loaded_back() {
+        // Given
+        let cache = Cache::new(16);
+        for label in ["example.com.", "my\\032printer.lan.", "expired.com."] {
+            cache.insert(&a_question(label), &[a_answer(label, 300)]);
+        }
+        let expired_key = Cache::key(&a_question("expired.com."));
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&expired_key)
+            .unwrap()
+            .expires_at = Instant::now();
+        let path = TempPath::new("cache");
+        // When
+        let saved_count = cache.save(&path).unwrap();
+        // A corrupted line among the saved ones
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("1 2 3\n{text}")).unwrap();
+        let loaded_cache = Cache::new(16);
+        let count = loaded_cache.load(&path).unwrap();
+        // Then
+        assert_eq!(saved_count, 2);
+        assert_eq!(count, 2);
+        let answers = loaded_cache.get(&a_question("example.com.")).unwrap();
+        assert_eq!(answers[0].label, "example.com.");
+        assert!(answers[0].ttl > 295 && answers[0].ttl <= 300);
+        assert_eq!(answers[0].rdata, vec![192, 0, 2, 1]);
+        let answers = loaded_cache
+            .get(&a_question("my\\032printer.lan."))
+            .unwrap();
+        assert_eq!(answers[0].label, "my\\032printer.lan.");
+    }
+
+    #[test]
+    fn test_when_cache_is_saved_then_files_with_other_extensions_are_left_alone() {
+        // Given a file named like the cache file with a .tmp extension instead
+        let cache = Cache::new(16);
+        cache.insert(
+            &a_question("example.com."),
+            &[a_answer("example.com.", 300)],
+        );
+        let directory = TempPath::directory("cache");
+        let path = directory.join("cache.a");
+        let other_path = directory.join("cache.tmp");
+        fs::write(&other_path, "other").unwrap();
+        // When
+        let saved_count = cache.save(&path).unwrap();
+        // Then
+        let other_text = fs::read_to_string(&other_path).unwrap();
+        let loaded_count = Cache::new(16).load(&path).unwrap();
+        assert_eq!(other_text, "other");
+        assert_eq!((saved_count, loaded_count), (1, 1));
+    }
+
+    fn a_question(label: &str) -> Question {
+        Question {
+            qname: vec![],
+            qtype: RecordType::A.into(),
+            qclass: CLASS_IN,
+            label: label.to_string(),
+        }
+    }
+
+    fn a_answer(label: &str, ttl: u32) -> Answer {
+        let rdata = RData::A("192.0.2.1".parse().unwrap());
+        Answer::new(label, RecordType::A, CLASS_IN, ttl, &rdata)
+    }
+
+    #[test]
+    fn test_when_answers_are_cached_then_ttl_is_lowered_and_name_case_is_ignored() {
+        // Given
+        let cache = Cache::new(16);
+        let answers = [a_answer("example.com.", 300), a_answer("example.com.", 60)];
+        cache.insert(&a_question("example.com."), &answers);
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(|entry| entry.stored_at -= Duration::from_secs(10));
+        // When
+        let cached = cache.get(&a_question("EXAMPLE.com.")).unwrap();
+        // Then
+        assert_eq!(cached[0].ttl, 290);
+        assert_eq!(cached[1].ttl, 50);
+        assert!(cache.get(&a_question("www.example.com.")).is_none());
+    }
+
+    #[test]
+    fn test_when_entry_expires_or_ttl_is_zero_then_it_is_not_returned() {
+        // Given
+        let cache = Cache::new(16);
+        cache.insert(
+            &a_question("expired.com."),
+            &[a_answer("expired.com.", 300)],
+        );
+        cache.insert(&a_question("zero.com."), &[a_answer("zero.com.", 0)]);
+        cache.insert(&a_question("empty.com."), &[]);
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(|entry| entry.expires_at = Instant::now());
+        // When
+        let expired = cache.get(&a_question("expired.com."));
+        // Then
+        assert!(expired.is_none());
+        assert!(cache.get(&a_question("zero.com.")).is_none());
+        assert!(cache.get(&a_question("empty.com.")).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_when_cache_is_full_then_entry_closest_to_expiring_is_dropped() {
+        // Given
+        let cache = Cache::new(2);
+        cache.insert(&a_question("long.com."), &[a_answer("long.com.", 3600)]);
+        cache.insert(&a_question("short.com."), &[a_answer("short.com.", 60)]);
+        // When
+        cache.insert(&a_question("new.com."), &[a_answer("new.com.", 300)]);
+        // Then
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&a_question("short.com.")).is_none());
+        assert!(cache.get(&a_question("long.com.")).is_some());
+        assert!(cache.get(&a_question("new.com.")).is_some());
+    }
+
+    #[test]
+    fn test_when_capacity_is_lowered_to_zero_then_next_insertion_empties_cache() {
+        // Given
+        let cache = Cache::new(16);
+        cache.insert(&a_question("a.com."), &[a_answer("a.com.", 300)]);
+        cache.insert(&a_question("b.com."), &[a_answer("b.com.", 300)]);
+        // When
+        cache.set_capacity(0);
+        cache.insert(&a_question("c.com."), &[a_answer("c.com.", 300)]);
+        // Then
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get(&a_question("a.com.")).is_none());
+    }
+}
@@ -0,0 +1,27 @@
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    // RFC 8484 messages over HTTP
+    Doh,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Doh => write!(f, "doh"),
+        }
+    }
+}
+
+// Who sent a request and how, for the answers depending on the client
+#[derive(Debug, Clone, Copy)]
+pub struct Client {
+    pub address: IpAddr,
+    pub transport: Transport,
+}
@@ -0,0 +1,155 @@
+use super::{Context, POLL_INTERVAL};
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Connections are served one at a time, so a slow client can't hold the others for long
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_COMMAND_SIZE: u64 = 1024;
+// How long a client waits for the reply, reloading large lists can take a while
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const COMMAND_RELOAD: &str = "reload";
+pub const COMMAND_SHUTDOWN: &str = "shutdown";
+pub const COMMAND_STATS: &str = "stats";
+
+// Serves one line commands, answering "ok" (followed by the stats for the stats command)
+// or "error: <reason>"
+pub fn serve(tcp_listener: TcpListener, context: Arc<Context>) -> Result<()> {
+    // Poll for connections to notice a shutdown
+    tcp_listener.set_nonblocking(true)?;
+
+    while !context.shutdown.is_requested() {
+        let stream = match tcp_listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(error) => {
+                log_error!("Failed to accept control connection: {error}");
+                continue;
+            }
+        };
+        if let Err(error) = serve_connection(stream, &context) {
+            log_warn!("Control connection error: {error:#}");
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, context: &Context) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut command = String::new();
+    BufReader::new(&stream)
+        .take(MAX_COMMAND_SIZE)
+        .read_line(&mut command)?;
+
+    let result = match command.trim() {
+        COMMAND_RELOAD => context.reload().map(|()| String::new()),
+        COMMAND_SHUTDOWN => {
+            context.shutdown.request();
+            Ok(String::new())
+        }
+        COMMAND_STATS => {
+            let rrl = context.rrl.stats();
+            let limits = context.limits.stats();
+            Ok(format!(
+                " rrl_slipped={} rrl_dropped={} refused_queries={} refused_resolutions={}",
+                rrl.slipped, rrl.dropped, limits.refused_queries, limits.refused_resolutions
+            ))
+        }
+        command => Err(anyhow!("unknown command '{command}'")),
+    };
+    let reply = match result {
+        Ok(output) => format!("ok{output}\n"),
+        Err(error) => {
+            log_error!("Control command '{}' failed: {error:#}", command.trim());
+            format!("error: {error:#}\n")
+        }
+    };
+    (&stream).write_all(reply.as_bytes())?;
+    Ok(())
+}
+
+// Sends a command to the control socket of a running server, giving up on one that doesn't
+// answer in time
+pub fn send_command(address: &SocketAddr, command: &str) -> Result<String> {
+    let stream = TcpStream::connect_timeout(address, WRITE_TIMEOUT)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    (&stream).write_all(format!("{command}\n").as_bytes())?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    Ok(reply.trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cli_params::CliParam;
+    use crate::config::Upstream;
+    use crate::server::dispatcher::Dispatcher;
+    use crate::test_utils::TempPath;
+    use std::fs;
+
+    // A server reading its upstreams from a config file, with its control socket served
+    fn serve_config(upstream: &str) -> (TempPath, Arc<Context>, SocketAddr) {
+        let config_path = TempPath::new("control-config");
+        fs::write(
+            &config_path,
+            format!("[resolver]\nupstreams = [\"{upstream}\"]\n"),
+        )
+        .unwrap();
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Arc::new(Context::new(params, Dispatcher::default()).unwrap());
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let server_context = context.clone();
+        thread::spawn(move || serve(tcp_listener, server_context));
+        (config_path, context, address)
+    }
+
+    #[test]
+    fn test_when_reload_succeeds_then_new_state_is_used() {
+        // Given
+        let (config_path, context, address) = serve_config("127.0.0.1:5300");
+        fs::write(
+            &config_path,
+            "[resolver]\nupstreams = [\"127.0.0.1:5301\"]\n",
+        )
+        .unwrap();
+        // When
+        let reply = send_command(&address, COMMAND_RELOAD).unwrap();
+        // Then
+        assert_eq!(reply, "ok");
+        assert_eq!(
+            context.state().config.upstreams,
+            vec![Upstream::Dns("127.0.0.1:5301".parse().unwrap())]
+        );
+        context.shutdown.request();
+    }
+
+    #[test]
+    fn test_when_reload_fails_then_old_state_is_kept_and_error_is_replied() {
+        // Given
+        let (config_path, context, address) = serve_config("127.0.0.1:5300");
+        fs::write(&config_path, "[resolver]\nupstreams = [\"nowhere\"]\n").unwrap();
+        // When
+        let reply = send_command(&address, COMMAND_RELOAD).unwrap();
+        // Then
+        assert!(reply.starts_with("error: "), "{reply}");
+        assert!(reply.contains("nowhere"), "{reply}");
+        assert_eq!(
+            context.state().config.upstreams,
+            vec![Upstream::Dns("127.0.0.1:5300".parse().unwrap())]
+        );
+        context.shutdown.request();
+    }
+}
@@ -0,0 +1,391 @@
+use super::client::{Client, Transport};
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::record_type::RecordType;
+use super::message::{opcode::Opcode, rcode::Rcode, Message};
+use super::query_log;
+use super::resolver::resolve_questions;
+use super::rpz::RpzAction;
+use super::{Context, State};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::slice;
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Message, client: &Client, context: &Context) -> Result<Message>;
+}
+
+// Returned by handlers to send no response at all
+#[derive(Debug, thiserror::Error)]
+#[error("response dropped")]
+pub struct DropResponse;
+
+// Answers from the static records first, then from the local zones, then from the blocklist
+// of the client policy, then from the query name triggers of the response policy zones, then
+// from the cache or the resolver, rewritten by the other triggers. Clients without recursion
+// only get the local answers.
+pub struct QueryHandler;
+
+impl Handler for QueryHandler {
+    fn handle(&self, request: &Message, client: &Client, context: &Context) -> Result<Message> {
+        let mut answers = vec![];
+        let mut authorities = vec![];
+        let mut rcode = Rcode::NoError;
+        let mut is_authoritative = false;
+        let mut is_truncated = false;
+        let state = context.state();
+        let policy = state.policies.for_client(&client.address);
+        let acl = &state.config.acl;
+        let allows_recursion = acl.allows_recursion(&client.address);
+
+        for question in &request.questions {
+            // Zone transfers are only sent over TCP, to the allowed clients
+            if RecordType::from(question.qtype) == RecordType::Axfr {
+                let allows_transfer =
+                    acl.allows_transfer(&client.address) && client.transport == Transport::Tcp;
+                match state.zones.transfer(&question.label) {
+                    Some(records) if allows_transfer => {
+                        log_info!("Transferring zone {} to {}", question.label, client.address);
+                        is_authoritative = true;
+                        answers.extend(records);
+                    }
+                    _ => {
+                        log_warn!(
+                            "Refused transfer of {} to {}",
+                            question.label,
+                            client.address
+                        );
+                        rcode = Rcode::Refused;
+                    }
+                }
+                continue;
+            }
+            if let Some((static_rcode, static_answers)) = state.static_records.lookup(question) {
+                rcode = static_rcode;
+                answers.extend(static_answers);
+                continue;
+            }
+            if let Some((zone_rcode, zone_answers, zone_authorities)) = state.zones.lookup(question)
+            {
+                is_authoritative = true;
+                rcode = zone_rcode;
+                answers.extend(zone_answers);
+                authorities.extend(zone_authorities);
+                continue;
+            }
+            if let Some((blocked_rcode, blocked_answers)) = policy.check(question) {
+                rcode = blocked_rcode;
+                answers.extend(blocked_answers);
+                continue;
+            }
+            if !allows_recursion {
+                rcode = Rcode::Refused;
+                continue;
+            }
+
+            // Query name triggers apply before resolving, answer triggers after. The zones
+            // before the one of the query name trigger can still match the answers, and win.
+            let (qname_zones_count, qname_action) = match state.rpz.check_qname(question) {
+                Some((index, action)) => (index, Some(action)),
+                None => (state.rpz.zones_count(), None),
+            };
+            let needs_answers = match qname_action {
+                None | Some(RpzAction::PassThru) => true,
+                Some(RpzAction::TcpOnly) if client.transport != Transport::Udp => true,
+                Some(_) => state.rpz.has_response_triggers(qname_zones_count),
+            };
+            let (resolved_rcode, resolved_answers) = if needs_answers {
+                resolve_cached(question, client, &state, context)?
+            } else {
+                (Rcode::NoError, vec![])
+            };
+            let action = if needs_answers {
+                let resolve = |question: &Question| {
+                    resolve_cached(question, client, &state, context).map(|(_, answers)| answers)
+                };
+                state
+                    .rpz
+                    .check_response(question, &resolved_answers, &resolve, qname_zones_count)
+                    .or(qname_action)
+            } else {
+                qname_action
+            };
+            match action {
+                None | Some(RpzAction::PassThru) => {
+                    rcode = resolved_rcode;
+                    answers.extend(resolved_answers);
+                }
+                Some(RpzAction::NxDomain) => rcode = Rcode::NxDomain,
+                Some(RpzAction::NoData) => {}
+                Some(RpzAction::Drop) => return Err(DropResponse.into()),
+                // Over UDP, a truncated response makes the client retry over TCP
+                Some(RpzAction::TcpOnly) if client.transport == Transport::Udp => {
+                    is_truncated = true;
+                }
+                Some(RpzAction::TcpOnly) => {
+                    rcode = resolved_rcode;
+                    answers.extend(resolved_answers);
+                }
+                Some(RpzAction::LocalData(records)) => {
+                    answers.extend(RpzAction::local_answers(records, question));
+                }
+            }
+        }
+
+        if is_truncated {
+            answers.clear();
+            authorities.clear();
+        }
+        let mut response = request.response_message(answers, rcode);
+        response.authorities = authorities;
+        response.header.aa = is_authoritative as u8;
+        response.header.tc = is_truncated as u8;
+        response.header.ra = allows_recursion as u8;
+        Ok(response)
+    }
+}
+
+// Answers from the cache, or from the resolver, caching its answers. Clients can only
+// have so many resolutions in flight.
+fn resolve_cached(
+    question: &Question,
+    client: &Client,
+    state: &State,
+    context: &Context,
+) -> Result<(Rcode, Vec<Answer>)> {
+    let cached_answers = context.cache.get(question);
+    context.metrics.record_cache(cached_answers.is_some());
+    if let Some(cached_answers) = cached_answers {
+        query_log::trace_cache_hit();
+        return Ok((Rcode::NoError, cached_answers));
+    }
+    // Going over the cap is the client's doing, not a server failure
+    let Some(_resolution) = context
+        .limits
+        .start_resolution(&state.config.limits, &client.address)
+    else {
+        log_debug!("Too many resolutions in flight for {}", client.address);
+        return Ok((Rcode::Refused, vec![]));
+    };
+    let (rcode, resolved_answers) =
+        resolve_questions(slice::from_ref(question), &state.resolver, context)?;
+    if rcode == Rcode::NoError {
+        context.cache.insert(question, &resolved_answers);
+    }
+    Ok((rcode, resolved_answers))
+}
+
+// Dynamic updates aren't supported, but clients outside the update ACL are refused first
+pub struct UpdateHandler;
+
+impl Handler for UpdateHandler {
+    fn handle(&self, request: &Message, client: &Client, context: &Context) -> Result<Message> {
+        let rcode = if context.state().config.acl.allows_update(&client.address) {
+            Rcode::NotImp
+        } else {
+            Rcode::Refused
+        };
+        Ok(request.response_message(vec![], rcode))
+    }
+}
+
+// Answers without resolving anything, for opcodes the server does not support
+pub struct NotImplementedHandler;
+
+impl Handler for NotImplementedHandler {
+    fn handle(&self, request: &Message, _client: &Client, _context: &Context) -> Result<Message> {
+        Ok(request.response_message(vec![], Rcode::NotImp))
+    }
+}
+
+pub struct Dispatcher {
+    handlers: HashMap<Opcode, Box<dyn Handler>>,
+    fallback: Box<dyn Handler>,
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        let mut dispatcher = Dispatcher {
+            handlers: HashMap::new(),
+            fallback: Box::new(NotImplementedHandler),
+        };
+        dispatcher.register(Opcode::Query, Box::new(QueryHandler));
+        dispatcher.register(Opcode::Notify, Box::new(NotImplementedHandler));
+        dispatcher.register(Opcode::Update, Box::new(UpdateHandler));
+        dispatcher.register(Opcode::Status, Box::new(NotImplementedHandler));
+        dispatcher.register(Opcode::Dso, Box::new(NotImplementedHandler));
+        dispatcher
+    }
+}
+
+impl Dispatcher {
+    pub fn register(&mut self, opcode: Opcode, handler: Box<dyn Handler>) {
+        self.handlers.insert(opcode, handler);
+    }
+
+    pub fn dispatch(
+        &self,
+        request: &Message,
+        client: &Client,
+        context: &Context,
+    ) -> Result<Message> {
+        let handler = self
+            .handlers
+            .get(&request.opcode())
+            .unwrap_or(&self.fallback);
+        handler.handle(request, client, context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cli_params::CliParam;
+    use crate::test_utils::TempPath;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+    const CLIENT: Client = Client {
+        address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        transport: Transport::Udp,
+    };
+
+    fn request_with_opcode(opcode: Opcode) -> Message {
+        let mut request = Message::query(1234, vec![]);
+        request.header.opcode = opcode.into();
+        request
+    }
+
+    #[test]
+    fn test_when_query_opcode_then_response_is_noerror() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let request = request_with_opcode(Opcode::Query);
+        // When
+        let response = context
+            .dispatcher
+            .dispatch(&request, &CLIENT, &context)
+            .unwrap();
+        // Then
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.qr, 1);
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::NoError);
+    }
+
+    #[test]
+    fn test_when_unsupported_opcode_then_response_is_notimp_without_answers() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        // When
+        for opcode in [Opcode::Notify, Opcode::Unknown(9)] {
+            let response = context
+                .dispatcher
+                .dispatch(&request_with_opcode(opcode), &CLIENT, &context)
+                .unwrap();
+            // Then
+            assert_eq!(response.opcode(), opcode);
+            assert_eq!(Rcode::from(response.header.rcode), Rcode::NotImp);
+            assert!(response.answers.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_when_handler_is_registered_for_an_opcode_then_its_requests_go_to_it() {
+        // Given a handler refusing status requests
+        struct StatusHandler;
+        impl Handler for StatusHandler {
+            fn handle(&self, request: &Message, _: &Client, _: &Context) -> Result<Message> {
+                Ok(request.response_message(vec![], Rcode::Refused))
+            }
+        }
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.register(Opcode::Status, Box::new(StatusHandler));
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        // When
+        let rcode = |opcode| {
+            let response = dispatcher
+                .dispatch(&request_with_opcode(opcode), &CLIENT, &context)
+                .unwrap();
+            Rcode::from(response.header.rcode)
+        };
+        // Then
+        assert_eq!(rcode(Opcode::Status), Rcode::Refused);
+        assert_eq!(rcode(Opcode::Query), Rcode::NoError);
+        assert_eq!(rcode(Opcode::Unknown(9)), Rcode::NotImp);
+    }
+
+    #[test]
+    fn test_when_client_is_not_allowed_to_update_then_response_is_refused() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let request = request_with_opcode(Opcode::Update);
+        // When
+        let response = context
+            .dispatcher
+            .dispatch(&request, &CLIENT, &context)
+            .unwrap();
+        // Then
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::Refused);
+    }
+
+    #[test]
+    fn test_when_qname_triggers_then_question_is_not_resolved() {
+        // Given an upstream that never answers
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_nonblocking(true).unwrap();
+        let directory = TempPath::directory("rpz");
+        fs::write(
+            directory.join("rpz.zone"),
+            "$TTL 300\n@ IN SOA ns.rpz. admin.rpz. 1 3600 600 86400 60\nads.example.com CNAME .\n",
+        )
+        .unwrap();
+        let config = format!(
+            "[resolver]\nupstreams = [\"{}\"]\n[[rpz]]\nname = \"rpz\"\nfile = \"rpz.zone\"\n",
+            upstream.local_addr().unwrap()
+        );
+        let config_path = directory.join("dns.toml");
+        fs::write(&config_path, config).unwrap();
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let request = Message::query(1234, vec![Question::new("ads.example.com.", RecordType::A)]);
+        // When
+        let response = context
+            .dispatcher
+            .dispatch(&request, &CLIENT, &context)
+            .unwrap();
+        // Then
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::NxDomain);
+        assert!(upstream.recv(&mut [0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_when_client_has_too_many_resolutions_in_flight_then_response_is_refused() {
+        // Given an upstream that never answers, and a client already resolving
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_nonblocking(true).unwrap();
+        let directory = TempPath::directory("limits");
+        let config = format!(
+            "[resolver]\nupstreams = [\"{}\"]\n[limits]\nconcurrent_resolutions = 1\n",
+            upstream.local_addr().unwrap()
+        );
+        let config_path = directory.join("dns.toml");
+        fs::write(&config_path, config).unwrap();
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let limits_config = context.state().config.limits.clone();
+        let _resolution = context
+            .limits
+            .start_resolution(&limits_config, &CLIENT.address)
+            .unwrap();
+        let request = Message::query(1234, vec![Question::new("www.example.com.", RecordType::A)]);
+        // When
+        let response = context
+            .dispatcher
+            .dispatch(&request, &CLIENT, &context)
+            .unwrap();
+        // Then
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::Refused);
+        assert!(upstream.recv(&mut [0; 512]).is_err());
+        assert_eq!(context.limits.stats().refused_resolutions, 1);
+    }
+}
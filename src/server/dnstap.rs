@@ -0,0 +1,423 @@
+// dnstap output: protobuf encoded client and forwarder messages, sent as Frame Streams data
+// frames to a Unix socket or written to a file by a writer thread, so queries never wait on it
+use super::client::Transport;
+use crate::config::{DnstapConfig, DnstapOutput};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+// Frames waiting for the writer, the next ones are dropped
+const QUEUE_SIZE: usize = 4096;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// A reader that stops reading loses the connection, so the writer and the shutdown don't wait
+// for it
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Frame Streams control frames
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+// A DNS message seen by the server, between a client and the server, or the server and
+// an upstream
+pub struct Event<'a> {
+    pub event_type: EventType,
+    pub transport: Transport,
+    pub client: Option<IpAddr>,
+    pub upstream: Option<SocketAddr>,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub message: &'a [u8],
+}
+
+pub struct Dnstap {
+    identity: String,
+    sender: Mutex<Option<SyncSender<Vec<u8>>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl Dnstap {
+    pub fn new(config: &DnstapConfig) -> Result<Self> {
+        let output = match &config.output {
+            DnstapOutput::File(path) => Output::File(open_file(path)?),
+            DnstapOutput::Socket(path) => Output::Socket {
+                path: path.clone(),
+                stream: None,
+                retry_at: Instant::now(),
+            },
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer = thread::spawn(move || write_frames(output, receiver));
+        Ok(Dnstap {
+            identity: config.identity.clone(),
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn send(&self, event: &Event) {
+        let Some(sender) = self.sender.lock().unwrap().clone() else {
+            return;
+        };
+        let frame = encode_dnstap(&self.identity, event);
+        if let Err(TrySendError::Full(_)) = sender.try_send(frame) {
+            // Only warn once, the queue is full for many frames in a row
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                log_warn!("dnstap writer is too slow, dropping frames");
+            }
+        }
+    }
+
+    // Writes the queued frames and the STOP frame, or drops them if the reader stalls
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Output {
+    File(BufWriter<File>),
+    Socket {
+        path: PathBuf,
+        stream: Option<Box<dyn Write + Send>>,
+        retry_at: Instant,
+    },
+}
+
+fn open_file(path: &Path) -> Result<BufWriter<File>> {
+    let file = File::create(path)
+        .with_context(|| format!("failed to create dnstap file {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&control_frame(CONTROL_START))?;
+    Ok(writer)
+}
+
+fn write_frames(mut output: Output, receiver: Receiver<Vec<u8>>) {
+    for frame in receiver {
+        let result = match &mut output {
+            Output::File(writer) => writer.write_all(&data_frame(&frame)).map_err(Into::into),
+            Output::Socket {
+                path,
+                stream,
+                retry_at,
+            } => write_to_socket(path, stream, retry_at, &frame),
+        };
+        if let Err(error) = result {
+            log_warn!("Failed to write dnstap frame: {error:#}");
+        }
+    }
+
+    let stop = control_frame(CONTROL_STOP);
+    let result = match &mut output {
+        Output::File(writer) => writer.write_all(&stop).and_then(|()| writer.flush()),
+        Output::Socket { stream, .. } => match stream {
+            Some(stream) => stream.write_all(&stop).and_then(|()| stream.flush()),
+            None => Ok(()),
+        },
+    };
+    if let Err(error) = result {
+        log_warn!("Failed to close dnstap output: {error}");
+    }
+}
+
+// Connects when needed, waiting a bit between attempts, and drops the frame while the
+// reader is away or stalled
+fn write_to_socket(
+    path: &Path,
+    stream: &mut Option<Box<dyn Write + Send>>,
+    retry_at: &mut Instant,
+    frame: &[u8],
+) -> Result<()> {
+    if stream.is_none() {
+        if Instant::now() < *retry_at {
+            return Ok(());
+        }
+        // Waits from the failure, the handshake with a stalled reader takes a while
+        let connected = connect(path);
+        *retry_at = Instant::now() + RECONNECT_INTERVAL;
+        *stream = Some(connected?);
+        log_info!("dnstap connected to {}", path.display());
+    }
+    let result = stream.as_mut().unwrap().write_all(&data_frame(frame));
+    if result.is_err() {
+        // A timed out write may have sent part of the frame, the stream can't be resumed
+        *stream = None;
+        *retry_at = Instant::now() + RECONNECT_INTERVAL;
+    }
+    Ok(result?)
+}
+
+// Bidirectional Frame Streams: READY, then ACCEPT from the reader, then START
+#[cfg(unix)]
+fn connect(path: &Path) -> Result<Box<dyn Write + Send>> {
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("failed to connect to dnstap socket {}", path.display()))?;
+    stream.set_read_timeout(Some(RECONNECT_INTERVAL))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.write_all(&control_frame(CONTROL_READY))?;
+    if read_control_type(&mut stream)? != CONTROL_ACCEPT {
+        bail!("dnstap reader did not accept the content type");
+    }
+    stream.write_all(&control_frame(CONTROL_START))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect(_path: &Path) -> Result<Box<dyn Write + Send>> {
+    bail!("dnstap sockets are only supported on unix")
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+fn read_control_type(reader: &mut impl Read) -> Result<u32> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != [0; 4] {
+        bail!("expected a control frame from the dnstap reader");
+    }
+    let length = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut control = vec![0; length];
+    reader.read_exact(&mut control)?;
+    if length < 4 {
+        bail!("control frame of {length} bytes is too short");
+    }
+    Ok(u32::from_be_bytes(control[..4].try_into().unwrap()))
+}
+
+fn data_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+    frame
+}
+
+// Escape, length, type, and the content type field for the types that negotiate it
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut control = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP {
+        control.extend(CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        control.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        control.extend(CONTENT_TYPE);
+    }
+    let mut frame = vec![0; 4];
+    frame.extend((control.len() as u32).to_be_bytes());
+    frame.extend(control);
+    frame
+}
+
+// The Dnstap protobuf message, with only the fields the server knows about
+fn encode_dnstap(identity: &str, event: &Event) -> Vec<u8> {
+    let mut message = vec![];
+    put_varint_field(&mut message, 1, event.event_type as u64);
+    let address = event
+        .client
+        .or(event.upstream.map(|upstream| upstream.ip()));
+    if let Some(address) = address {
+        put_varint_field(&mut message, 2, if address.is_ipv4() { 1 } else { 2 });
+    }
+    let protocol = match event.transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Doh => 4,
+    };
+    put_varint_field(&mut message, 3, protocol);
+    if let Some(client) = event.client {
+        put_bytes_field(&mut message, 4, &ip_bytes(&client));
+    }
+    if let Some(upstream) = event.upstream {
+        put_bytes_field(&mut message, 5, &ip_bytes(&upstream.ip()));
+        put_varint_field(&mut message, 7, upstream.port() as u64);
+    }
+    put_time_fields(&mut message, 8, event.query_time);
+    match event.event_type {
+        EventType::ClientQuery | EventType::ForwarderQuery => {
+            put_bytes_field(&mut message, 10, event.message);
+        }
+        EventType::ClientResponse | EventType::ForwarderResponse => {
+            put_time_fields(
+                &mut message,
+                12,
+                event.response_time.unwrap_or(event.query_time),
+            );
+            put_bytes_field(&mut message, 14, event.message);
+        }
+    }
+
+    let mut dnstap = vec![];
+    put_bytes_field(&mut dnstap, 1, identity.as_bytes());
+    put_bytes_field(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+    put_bytes_field(&mut dnstap, 14, &message);
+    // Type MESSAGE
+    put_varint_field(&mut dnstap, 15, 1);
+    dnstap
+}
+
+fn ip_bytes(address: &IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+// Seconds as a varint, then nanoseconds as a fixed32 in the next field
+fn put_time_fields(buffer: &mut Vec<u8>, field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_varint_field(buffer, field, since_epoch.as_secs());
+    put_varint(buffer, ((field + 1) << 3) | 5);
+    buffer.extend(since_epoch.subsec_nanos().to_le_bytes());
+}
+
+fn put_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buffer, field << 3);
+    put_varint(buffer, value);
+}
+
+fn put_bytes_field(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buffer, (field << 3) | 2);
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend(bytes);
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::TempPath;
+    use std::fs;
+
+    #[test]
+    fn test_when_event_is_encoded_then_protobuf_fields_are_set() {
+        // Given
+        let event = Event {
+            event_type: EventType::ForwarderQuery,
+            transport: Transport::Udp,
+            client: None,
+            upstream: Some("192.0.2.53:53".parse().unwrap()),
+            query_time: UNIX_EPOCH + Duration::new(300, 7),
+            response_time: None,
+            message: b"\x12\x34",
+        };
+        // When
+        let dnstap = encode_dnstap("ns1", &event);
+        // Then
+        let message = [
+            0x08, 7, // type FORWARDER_QUERY
+            0x10, 1, // socket_family INET
+            0x18, 1, // socket_protocol UDP
+            0x2a, 4, 192, 0, 2, 53, // response_address
+            0x38, 53, // response_port
+            0x40, 0xac, 0x02, // query_time_sec 300
+            0x4d, 7, 0, 0, 0, // query_time_nsec
+            0x52, 2, 0x12, 0x34, // query_message
+        ];
+        let mut expected = vec![0x0a, 3, b'n', b's', b'1', 0x12];
+        expected.push(env!("CARGO_PKG_VERSION").len() as u8);
+        expected.extend(env!("CARGO_PKG_VERSION").as_bytes());
+        expected.extend([0x72, message.len() as u8]);
+        expected.extend(message);
+        expected.extend([0x78, 1]);
+        assert_eq!(dnstap, expected);
+    }
+
+    #[test]
+    fn test_when_output_is_a_file_then_frames_are_between_start_and_stop() {
+        // Given
+        let path = TempPath::new("dnstap");
+        let config = DnstapConfig {
+            output: DnstapOutput::File(path.to_path_buf()),
+            identity: "ns1".to_string(),
+        };
+        let dnstap = Dnstap::new(&config).unwrap();
+        // When
+        dnstap.send(&Event {
+            event_type: EventType::ClientQuery,
+            transport: Transport::Tcp,
+            client: Some("::1".parse().unwrap()),
+            upstream: None,
+            query_time: SystemTime::now(),
+            response_time: None,
+            message: b"query",
+        });
+        dnstap.close();
+        // Then
+        let bytes = fs::read(&path).unwrap();
+        let start = control_frame(CONTROL_START);
+        let stop = control_frame(CONTROL_STOP);
+        assert!(bytes.starts_with(&start));
+        assert!(bytes.ends_with(&stop));
+        let data = &bytes[start.len()..bytes.len() - stop.len()];
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        assert_eq!(data.len(), 4 + length);
+        assert!(data.ends_with(&[0x78, 1]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_when_socket_reader_stalls_then_close_does_not_hang() {
+        use std::os::unix::net::UnixListener;
+
+        // Given
+        let path = TempPath::new("dnstap-socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let reader = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_control_type(&mut stream).unwrap(), CONTROL_READY);
+            stream.write_all(&control_frame(CONTROL_ACCEPT)).unwrap();
+            assert_eq!(read_control_type(&mut stream).unwrap(), CONTROL_START);
+            // Never reads the data frames
+            thread::sleep(Duration::from_secs(10));
+        });
+        let config = DnstapConfig {
+            output: DnstapOutput::Socket(path.to_path_buf()),
+            identity: "ns1".to_string(),
+        };
+        let dnstap = Dnstap::new(&config).unwrap();
+        let message = vec![0; 65_000];
+        // When
+        for _ in 0..100 {
+            dnstap.send(&Event {
+                event_type: EventType::ClientQuery,
+                transport: Transport::Udp,
+                client: Some("::1".parse().unwrap()),
+                upstream: None,
+                query_time: SystemTime::now(),
+                response_time: None,
+                message: &message,
+            });
+        }
+        let closing = Instant::now();
+        dnstap.close();
+        // Then
+        assert!(closing.elapsed() < Duration::from_secs(5));
+        drop(reader);
+    }
+}
@@ -0,0 +1,628 @@
+// RFC 8484 messages over plain HTTP/1.1: the message is sent base64url encoded in the dns
+// parameter of a GET, or as the body of a POST. There is no TLS, so this isn't DNS over HTTPS
+// on its own: HTTPS needs a proxy in front of the listener.
+use super::client::{Client, Transport};
+use super::message::header::Header;
+use super::message::record_type::RecordType;
+use super::message::Message;
+use super::resolver::{is_response_to, UPSTREAM_TIMEOUT};
+use super::tcp::{ConnectionLimit, MAX_CONNECTIONS};
+use super::{is_timeout, Context, POLL_INTERVAL};
+use anyhow::{bail, Context as _, Result};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEAD_SIZE: u64 = 8192;
+const MAX_BODY_SIZE: usize = 65_535;
+// Idle connections kept open to each upstream
+const MAX_IDLE_CONNECTIONS: usize = 4;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+struct Request {
+    method: String,
+    target: String,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    // How long the response can be cached, the lowest TTL of its records
+    max_age: Option<u32>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            max_age: None,
+            body: format!("{}\n", &status[4..]).to_lowercase().into_bytes(),
+        }
+    }
+}
+
+pub fn serve(tcp_listener: TcpListener, path: String, context: Arc<Context>) -> Result<()> {
+    // Poll for connections to notice a shutdown
+    tcp_listener.set_nonblocking(true)?;
+    let path = Arc::new(path);
+    let connection_limit = ConnectionLimit::new(MAX_CONNECTIONS);
+
+    while !context.shutdown.is_requested() {
+        let (stream, source) = match tcp_listener.accept() {
+            Ok(accepted) => accepted,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(error) => {
+                log_error!("Failed to accept DNS over HTTP connection: {error}");
+                continue;
+            }
+        };
+        let Some(connection) = connection_limit.open() else {
+            log_debug!("Too many DNS over HTTP connections, closing the one from {source}");
+            context.metrics.record_dropped("overload");
+            continue;
+        };
+        let context = context.clone();
+        let path = path.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(error) = serve_connection(stream, &path, context) {
+                log_warn!("DNS over HTTP connection error: {error:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+// Requests are answered in order on the connection until the client closes it
+fn serve_connection(stream: TcpStream, path: &str, context: Arc<Context>) -> Result<()> {
+    let client = Client {
+        address: stream.peer_addr()?.ip(),
+        transport: Transport::Doh,
+    };
+    stream.set_nonblocking(false)?;
+    let mut reader = BufReader::new(&stream);
+    let mut idle_since = Instant::now();
+
+    loop {
+        // Wait for the next request, closing the connection when idle or shutting down
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        match reader.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
+            Err(error) if is_timeout(&error) => {
+                if context.shutdown.is_requested() || idle_since.elapsed() > IDLE_TIMEOUT {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        }
+        let _in_flight = context.shutdown.track();
+
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let request = match read_request(&mut reader) {
+            Ok(request) => request,
+            Err(error) => {
+                // The rest of the connection can't be framed after a malformed request
+                let _ = write_response(&stream, &Response::error("400 Bad Request"), false);
+                return Err(error);
+            }
+        };
+        // Dropped responses close the connection, as there is no HTTP way to not answer
+        let Some(response) = respond(&request, path, client, &context) else {
+            return Ok(());
+        };
+        write_response(&stream, &response, request.keep_alive)?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+        idle_since = Instant::now();
+    }
+}
+
+// The start line and the headers, with lowercase names
+fn read_head(reader: &mut impl BufRead) -> Result<(String, Vec<(String, String)>)> {
+    let mut head = reader.take(MAX_HEAD_SIZE);
+
+    let start_line = read_line(&mut head)?;
+    let mut headers = vec![];
+    loop {
+        let line = read_line(&mut head)?;
+        if line.is_empty() {
+            return Ok((start_line, headers));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            bail!("malformed header '{line}'");
+        };
+        headers.push((name.to_lowercase(), value.trim().to_string()));
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        bail!("incomplete or too large message head");
+    }
+    Ok(line.trim_end().to_string())
+}
+
+// Bodies have a Content-Length, or are sent in chunks (RFC 9112 section 7.1)
+fn read_body(reader: &mut impl BufRead, headers: &[(String, String)]) -> Result<Vec<u8>> {
+    if let Some((_, encoding)) = headers.iter().find(|(name, _)| name == "transfer-encoding") {
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            bail!("unsupported Transfer-Encoding '{encoding}'");
+        }
+        return read_chunked_body(&mut reader.take(MAX_HEAD_SIZE + MAX_BODY_SIZE as u64 * 2));
+    }
+    let mut content_length = 0;
+    for (name, value) in headers {
+        if name == "content-length" {
+            content_length = value
+                .parse()
+                .with_context(|| format!("invalid Content-Length '{value}'"))?;
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        bail!("body of {content_length} bytes is too large");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// Each chunk is its size in hexadecimal and its bytes, until an empty one followed by the
+// trailer fields, which are skipped like the chunk extensions
+fn read_chunked_body(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("invalid chunk size '{size}'"))?;
+        if size == 0 {
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_BODY_SIZE {
+            bail!("body of more than {MAX_BODY_SIZE} bytes is too large");
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.is_empty() {
+            bail!("chunk is longer than its size of {size} bytes");
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let (request_line, headers) = read_head(reader)?;
+    let mut fields = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        bail!("malformed request line '{request_line}'");
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        content_type: None,
+        body: vec![],
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    for (name, value) in &headers {
+        match name.as_str() {
+            "content-type" => request.content_type = Some(value.clone()),
+            "connection" => request.keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+    request.body = read_body(reader, &headers)?;
+    Ok(request)
+}
+
+// None when the DNS response is dropped
+fn respond(request: &Request, path: &str, client: Client, context: &Context) -> Option<Response> {
+    let (target_path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if target_path != path {
+        return Some(Response::error("404 Not Found"));
+    }
+    let request_bytes = match request.method.as_str() {
+        "GET" => {
+            let dns = query
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("dns="))
+                .and_then(base64url_decode);
+            match dns {
+                Some(bytes) => bytes,
+                None => return Some(Response::error("400 Bad Request")),
+            }
+        }
+        "POST" => {
+            if !is_dns_message(request.content_type.as_deref()) {
+                return Some(Response::error("415 Unsupported Media Type"));
+            }
+            request.body.clone()
+        }
+        _ => return Some(Response::error("405 Method Not Allowed")),
+    };
+    // Without a header there is no DNS message to answer with FORMERR
+    if Header::try_from(request_bytes.as_slice()).is_err() {
+        context.metrics.record_parse_error();
+        return Some(Response::error("400 Bad Request"));
+    }
+
+    // Responses are truncated to a single message
+    let response_bytes = context
+        .handle_request(&request_bytes, client)
+        .map(|responses_bytes| responses_bytes.into_iter().next());
+    match response_bytes {
+        Ok(Some(response_bytes)) => {
+            let max_age =
+                Message::try_from(response_bytes.as_slice())
+                    .ok()
+                    .and_then(|response_message| {
+                        response_message
+                            .answers
+                            .iter()
+                            .chain(&response_message.authorities)
+                            .filter(|record| record.record_type() != RecordType::Opt)
+                            .map(|record| record.ttl)
+                            .min()
+                    });
+            Some(Response {
+                status: "200 OK",
+                content_type: DNS_MESSAGE,
+                max_age,
+                body: response_bytes,
+            })
+        }
+        Ok(None) => None,
+        // The request was a DNS message, so the failure is on the server side
+        Err(error) => {
+            log_warn!("Failed to answer DNS over HTTP request: {error:#}");
+            Some(Response::error("500 Internal Server Error"))
+        }
+    }
+}
+
+// The media type may come with parameters, such as a charset, and is compared without case
+fn is_dns_message(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(DNS_MESSAGE))
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response, keep_alive: bool) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    if let Some(max_age) = response.max_age {
+        head.push_str(&format!("Cache-Control: max-age={max_age}\r\n"));
+    }
+    if response.status.starts_with("405") {
+        head.push_str("Allow: GET, POST\r\n");
+    }
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(&[head.as_bytes(), &response.body].concat())?;
+    Ok(())
+}
+
+// Idle connections to DNS over HTTP upstreams, reused by the next queries
+#[derive(Default)]
+pub struct Connections {
+    idle: Mutex<HashMap<SocketAddr, Vec<TcpStream>>>,
+}
+
+impl Connections {
+    fn take(&self, address: SocketAddr) -> Option<TcpStream> {
+        self.idle.lock().unwrap().get_mut(&address)?.pop()
+    }
+
+    fn give_back(&self, address: SocketAddr, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(address).or_default();
+        if streams.len() < MAX_IDLE_CONNECTIONS {
+            streams.push(stream);
+        }
+    }
+}
+
+// Sends the request to a DNS over HTTP upstream with a POST and returns the response message
+pub fn exchange(
+    request_bytes: &[u8],
+    address: SocketAddr,
+    path: &str,
+    connections: &Connections,
+) -> Result<Vec<u8>> {
+    // The upstream may have closed an idle connection since, a new one is tried then
+    if let Some(stream) = connections.take(address) {
+        match exchange_on(stream, request_bytes, address, path, connections) {
+            Ok(response_bytes) => return Ok(response_bytes),
+            Err(error) if timed_out(&error) => return Err(error),
+            Err(_) => {}
+        }
+    }
+    let stream = TcpStream::connect_timeout(&address, UPSTREAM_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+    exchange_on(stream, request_bytes, address, path, connections)
+}
+
+fn exchange_on(
+    mut stream: TcpStream,
+    request_bytes: &[u8],
+    address: SocketAddr,
+    path: &str,
+    connections: &Connections,
+) -> Result<Vec<u8>> {
+    let head = format!(
+        "POST {path} HTTP/1.1\r\nHost: {address}\r\nContent-Type: {DNS_MESSAGE}\r\n\
+         Accept: {DNS_MESSAGE}\r\nContent-Length: {}\r\n\r\n",
+        request_bytes.len()
+    );
+    stream.write_all(&[head.as_bytes(), request_bytes].concat())?;
+
+    let mut reader = BufReader::new(&stream);
+    let (status_line, headers) = read_head(&mut reader)?;
+    let body = read_body(&mut reader, &headers)?;
+    let (version, status) = status_line.split_once(' ').unwrap_or((&status_line, ""));
+    if !status.starts_with("200") {
+        bail!("HTTP status '{status}'");
+    }
+    let header = |name| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.to_lowercase())
+    };
+    if !is_dns_message(header("content-type").as_deref()) {
+        bail!("response is not a {DNS_MESSAGE}");
+    }
+
+    // A connection with a mismatched response is out of step with the upstream, and closed
+    if !is_response_to(request_bytes, &body) {
+        bail!("response doesn't match the query");
+    }
+    let keep_alive = version == "HTTP/1.1" && header("connection").as_deref() != Some("close");
+    if keep_alive && reader.buffer().is_empty() {
+        drop(reader);
+        connections.give_back(address, stream);
+    }
+    Ok(body)
+}
+
+fn timed_out(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(is_timeout)
+}
+
+// The URL safe alphabet without padding (RFC 4648 section 5), padding is tolerated
+fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let value = |char: u8| match char {
+        b'A'..=b'Z' => Some(char - b'A'),
+        b'a'..=b'z' => Some(char - b'a' + 26),
+        b'0'..=b'9' => Some(char - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    };
+    let mut bytes = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for char in text.trim_end_matches('=').bytes() {
+        buffer = (buffer << 6 | value(char)? as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::dispatcher::Dispatcher;
+    use crate::server::message::rcode::Rcode;
+    use std::net::Ipv4Addr;
+
+    // The query for www.example.com A of RFC 8484 section 4.1.1
+    const DNS_PARAMETER: &str = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+
+    const CLIENT: Client = Client {
+        address: std::net::IpAddr::V4(Ipv4Addr::LOCALHOST),
+        transport: Transport::Doh,
+    };
+
+    fn request(method: &str, target: &str, content_type: Option<&str>, body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            content_type: content_type.map(String::from),
+            body: body.to_vec(),
+            keep_alive: true,
+        }
+    }
+
+    #[test]
+    fn test_when_query_is_sent_with_get_or_post_then_it_is_answered() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let query = base64url_decode(DNS_PARAMETER).unwrap();
+        let requests = [
+            request("GET", &format!("/dns-query?dns={DNS_PARAMETER}"), None, &[]),
+            request("POST", "/dns-query", Some(DNS_MESSAGE), &query),
+            request(
+                "POST",
+                "/dns-query",
+                Some("Application/DNS-Message; charset=utf-8"),
+                &query,
+            ),
+        ];
+        for request in requests {
+            // When
+            let response = respond(&request, "/dns-query", CLIENT, &context).unwrap();
+            // Then
+            assert_eq!(response.status, "200 OK");
+            assert_eq!(response.content_type, DNS_MESSAGE);
+            let message = Message::try_from(response.body.as_slice()).unwrap();
+            assert_eq!(message.questions[0].label, "www.example.com.");
+            assert_eq!(Rcode::from(message.header.rcode), Rcode::NxDomain);
+        }
+    }
+
+    #[test]
+    fn test_when_request_is_not_a_dns_query_then_http_error_is_returned() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let requests = [
+            (request("GET", "/other", None, &[]), "404 Not Found"),
+            (
+                request("GET", "/dns-query?dns=@@", None, &[]),
+                "400 Bad Request",
+            ),
+            (
+                request("GET", "/dns-query?dns=AAAB", None, &[]),
+                "400 Bad Request",
+            ),
+            (
+                request("POST", "/dns-query", Some("text/plain"), &[]),
+                "415 Unsupported Media Type",
+            ),
+            (
+                request("POST", "/dns-query", Some("application/dns-messages"), &[]),
+                "415 Unsupported Media Type",
+            ),
+            (
+                request("PUT", "/dns-query", None, &[]),
+                "405 Method Not Allowed",
+            ),
+        ];
+        for (request, expected_status) in requests {
+            // When
+            let response = respond(&request, "/dns-query", CLIENT, &context).unwrap();
+            // Then
+            assert_eq!(response.status, expected_status);
+        }
+    }
+
+    #[test]
+    fn test_when_http_request_is_malformed_then_400_is_answered_and_connection_closed() {
+        // Given
+        let context = Arc::new(Context::new(vec![], Dispatcher::default()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_context = context.clone();
+        thread::spawn(move || serve(listener, "/dns-query".to_string(), server_context));
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        // When
+        stream.write_all(b"GET /dns-query\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        // Then
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+        context.shutdown.request();
+    }
+
+    #[test]
+    fn test_when_upstream_is_queried_over_http_then_connection_is_reused() {
+        // Given
+        let context = Arc::new(Context::new(vec![], Dispatcher::default()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_context = context.clone();
+        thread::spawn(move || serve(listener, "/dns-query".to_string(), server_context));
+        let connections = Connections::default();
+        let query = base64url_decode(DNS_PARAMETER).unwrap();
+        for _ in 0..2 {
+            // When
+            let response_bytes = exchange(&query, address, "/dns-query", &connections).unwrap();
+            // Then
+            let message = Message::try_from(response_bytes.as_slice()).unwrap();
+            assert_eq!(message.questions[0].label, "www.example.com.");
+            assert_eq!(connections.idle.lock().unwrap()[&address].len(), 1);
+        }
+        let error = exchange(&query, address, "/other", &connections).unwrap_err();
+        assert_eq!(error.to_string(), "HTTP status '404 Not Found'");
+        context.shutdown.request();
+    }
+
+    #[test]
+    fn test_when_upstream_answers_in_chunks_or_mismatched_then_only_matching_connections_are_kept()
+    {
+        // Given an upstream answering in chunks, the second time with another ID
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            for id_offset in [0, 1] {
+                let (_, headers) = read_head(&mut reader).unwrap();
+                let body = read_body(&mut reader, &headers).unwrap();
+                let mut message = Message::try_from(body.as_slice()).unwrap();
+                message.header.qr = 1;
+                message.header.id += id_offset;
+                let bytes = Vec::<u8>::try_from(message).unwrap();
+                let (first, second) = bytes.split_at(10);
+                let response = [
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {DNS_MESSAGE}\r\n\
+                         Transfer-Encoding: chunked\r\n\r\n{:x};ext=1\r\n",
+                        first.len()
+                    )
+                    .as_bytes(),
+                    first,
+                    format!("\r\n{:x}\r\n", second.len()).as_bytes(),
+                    second,
+                    b"\r\n0\r\nTrailer: 1\r\n\r\n",
+                ]
+                .concat();
+                (&stream).write_all(&response).unwrap();
+            }
+        });
+        let connections = Connections::default();
+        let query = base64url_decode(DNS_PARAMETER).unwrap();
+        // When
+        let response_bytes = exchange(&query, address, "/dns-query", &connections).unwrap();
+        // Then
+        let message = Message::try_from(response_bytes.as_slice()).unwrap();
+        assert_eq!(message.questions[0].label, "www.example.com.");
+        assert_eq!(connections.idle.lock().unwrap()[&address].len(), 1);
+        // When
+        let stream = connections.take(address).unwrap();
+        let error = exchange_on(stream, &query, address, "/dns-query", &connections).unwrap_err();
+        // Then
+        assert_eq!(error.to_string(), "response doesn't match the query");
+        assert!(connections.idle.lock().unwrap()[&address].is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+// Static records from hosts files and the configuration, answered before anything else
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::rcode::Rcode;
+use super::message::rdata::{parse_name, RData};
+use super::message::record_type::{RecordType, CLASS_IN};
+use super::zone::record_tokens;
+use crate::config::StaticConfig;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+
+const STATIC_TTL: u32 = 300;
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Default)]
+pub struct StaticRecords {
+    records: HashMap<String, Vec<Answer>>,
+}
+
+impl StaticRecords {
+    // Records from the configuration come first, so hosts files can't conflict with them
+    pub fn load(config: &StaticConfig) -> Result<Self> {
+        let mut static_records = StaticRecords::default();
+        for record in &config.records {
+            static_records.insert(record.clone())?;
+        }
+        for path in &config.hosts {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("failed to read hosts file {}", path.display()))?;
+            static_records
+                .add_hosts(&text)
+                .with_context(|| format!("invalid hosts file {}", path.display()))?;
+        }
+        if !static_records.records.is_empty() {
+            log_info!(
+                "Loaded {} static records",
+                static_records.records.values().map(Vec::len).sum::<usize>()
+            );
+        }
+        Ok(static_records)
+    }
+
+    fn insert(&mut self, record: Answer) -> Result<()> {
+        let records = self.records.entry(record.label.to_lowercase()).or_default();
+        let has_cname = records.iter().any(|r| r.record_type() == RecordType::Cname);
+        if (has_cname || record.record_type() == RecordType::Cname) && !records.is_empty() {
+            bail!("'{}' has a CNAME record and other data", record.label);
+        }
+        if !records.contains(&record) {
+            records.push(record);
+        }
+        Ok(())
+    }
+
+    // Lines are "address name [aliases...]", and every address gets a PTR record to the
+    // first name it was given
+    fn add_hosts(&mut self, text: &str) -> Result<()> {
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let address: IpAddr = address
+                .parse()
+                .with_context(|| format!("line {}: invalid address '{address}'", index + 1))?;
+            let names = fields
+                .map(|name| parse_name(name, ""))
+                .collect::<Result<Vec<String>>>()
+                .with_context(|| format!("line {}: invalid name", index + 1))?;
+            if names.is_empty() {
+                bail!("line {}: address {address} without a name", index + 1);
+            }
+
+            for name in &names {
+                let (record_type, rdata) = match address {
+                    IpAddr::V4(address) => (RecordType::A, RData::A(address)),
+                    IpAddr::V6(address) => (RecordType::Aaaa, RData::Aaaa(address)),
+                };
+                self.insert(Answer::new(name, record_type, CLASS_IN, STATIC_TTL, &rdata))
+                    .with_context(|| format!("line {}", index + 1))?;
+            }
+            let reverse_name = reverse_name(&address);
+            if !self.records.contains_key(&reverse_name) {
+                let rdata = RData::Ptr(names[0].clone());
+                let record =
+                    Answer::new(&reverse_name, RecordType::Ptr, CLASS_IN, STATIC_TTL, &rdata);
+                self.insert(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    // None when the name has no static records, so it is answered by the next sources
+    pub fn lookup(&self, question: &Question) -> Option<(Rcode, Vec<Answer>)> {
+        let qtype = RecordType::from(question.qtype);
+        let mut name = question.label.to_lowercase();
+        let mut answers = vec![];
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = self.records.get(&name) else {
+                break;
+            };
+            let matching: Vec<Answer> = records
+                .iter()
+                .filter(|record| qtype == RecordType::Any || record.record_type() == qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+            match records.first().map(|record| (record, record.rdata())) {
+                Some((record, RData::Cname(target))) => {
+                    answers.push(record.clone());
+                    name = target.to_lowercase();
+                }
+                _ => break,
+            }
+        }
+
+        if answers.is_empty() && !self.records.contains_key(&question.label.to_lowercase()) {
+            return None;
+        }
+        Some((Rcode::NoError, answers))
+    }
+}
+
+// Parses "name [ttl] TYPE rdata...", with names always absolute
+pub fn parse_static_record(text: &str) -> Result<Answer> {
+    let tokens = record_tokens(text)?;
+    let [name, rest @ ..] = tokens.as_slice() else {
+        bail!("empty record");
+    };
+    let (ttl, rest) = match rest.first().map(|token| token.parse::<u32>()) {
+        Some(Ok(ttl)) => (ttl, &rest[1..]),
+        _ => (STATIC_TTL, rest),
+    };
+    let Some((record_type, fields)) = rest.split_first() else {
+        bail!("record '{text}' has no type");
+    };
+    let record_type: RecordType = record_type.parse()?;
+    if !matches!(
+        record_type,
+        RecordType::A | RecordType::Aaaa | RecordType::Cname | RecordType::Txt | RecordType::Ptr
+    ) {
+        bail!("record type {record_type:?} can't be a static record");
+    }
+    let rdata = RData::parse(record_type, fields, "")
+        .with_context(|| format!("invalid record '{text}'"))?;
+    let name = parse_name(name, "").with_context(|| format!("invalid record '{text}'"))?;
+    Ok(Answer::new(&name, record_type, CLASS_IN, ttl, &rdata))
+}
+
+// The in-addr.arpa or ip6.arpa name of an address
+pub fn reverse_name(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa.")
+        }
+        IpAddr::V6(address) => {
+            let mut name: String = address
+                .octets()
+                .iter()
+                .rev()
+                .map(|byte| format!("{:x}.{:x}.", byte & 0x0f, byte >> 4))
+                .collect();
+            name.push_str("ip6.arpa.");
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::message::name::labels_bytes;
+
+    fn question(label: &str, record_type: RecordType) -> Question {
+        Question {
+            qname: labels_bytes(label),
+            qtype: record_type.into(),
+            qclass: CLASS_IN,
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_when_hosts_file_is_loaded_then_names_and_reverse_names_are_answered() {
+        // Given
+        let mut static_records = StaticRecords::default();
+        let hosts = "\
+127.0.0.1   localhost
+192.168.1.10 printer.lan printer  # office printer
+192.168.1.10 scanner.lan
+fd00::10    printer.lan
+";
+        // When
+        static_records.add_hosts(hosts).unwrap();
+        // Then
+        let (_, answers) = static_records
+            .lookup(&question("Printer.lan.", RecordType::A))
+            .unwrap();
+        assert_eq!(
+            answers[0].rdata(),
+            RData::A("192.168.1.10".parse().unwrap())
+        );
+        let (_, answers) = static_records
+            .lookup(&question("10.1.168.192.in-addr.arpa.", RecordType::Ptr))
+            .unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rdata(), RData::Ptr("printer.lan.".to_string()));
+        let reverse_name = reverse_name(&"fd00::10".parse().unwrap());
+        assert!(reverse_name.starts_with("0.1.0.0.0.0"));
+        assert!(reverse_name.ends_with(".0.0.d.f.ip6.arpa."));
+        assert!(static_records
+            .lookup(&question(&reverse_name, RecordType::Ptr))
+            .is_some());
+        let nodata = static_records.lookup(&question("scanner.lan.", RecordType::Aaaa));
+        assert!(matches!(nodata, Some((Rcode::NoError, answers)) if answers.is_empty()));
+        assert!(static_records
+            .lookup(&question("other.lan.", RecordType::A))
+            .is_none());
+    }
+
+    #[test]
+    fn test_when_static_records_are_parsed_then_cnames_are_followed() {
+        // Given
+        let mut static_records = StaticRecords::default();
+        let records = [
+            "nas.lan 60 A 192.168.1.20",
+            "files.lan CNAME nas.lan",
+            "nas.lan TXT \"backups at night\"",
+        ];
+        // When
+        for record in records {
+            static_records
+                .insert(parse_static_record(record).unwrap())
+                .unwrap();
+        }
+        // Then
+        let (_, answers) = static_records
+            .lookup(&question("files.lan.", RecordType::A))
+            .unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].rdata(), RData::Cname("nas.lan.".to_string()));
+        assert_eq!(answers[1].ttl, 60);
+        let error = static_records.insert(parse_static_record("files.lan A 10.0.0.1").unwrap());
+        assert!(error.is_err());
+        assert!(parse_static_record("nas.lan MX 10 mail.lan").is_err());
+        assert!(parse_static_record("nas.lan A 300.0.0.1").is_err());
+    }
+}
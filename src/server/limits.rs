@@ -0,0 +1,279 @@
+// Per client limits, so a single host can't use up the server: a query budget, and a cap on
+// the upstream resolutions in flight
+use crate::config::LimitsConfig;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Above this many buckets, the oldest ones are forgotten
+pub const MAX_BUCKETS: usize = 10_000;
+// Idle buckets are forgotten this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Holds up to one second of tokens, refilled continuously at the rate per second
+pub struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate,
+            updated_at: now,
+        }
+    }
+
+    pub fn take(&mut self, rate: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Buckets idle for a second are full again, the same as new ones
+    pub fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.updated_at) >= Duration::from_secs(1)
+    }
+}
+
+pub trait Idle {
+    fn is_idle(&self, now: Instant) -> bool;
+}
+
+impl Idle for TokenBucket {
+    fn is_idle(&self, now: Instant) -> bool {
+        TokenBucket::is_idle(self, now)
+    }
+}
+
+// Buckets by key, at most MAX_BUCKETS of them. The idle ones are swept on a timer rather than
+// on every packet, and a flood of new keys evicts the oldest buckets first.
+pub struct Buckets<K, V> {
+    buckets: HashMap<K, V>,
+    // The keys of the buckets, oldest first
+    order: VecDeque<K>,
+    swept_at: Option<Instant>,
+}
+
+impl<K, V> Default for Buckets<K, V> {
+    fn default() -> Self {
+        Buckets {
+            buckets: HashMap::new(),
+            order: VecDeque::new(),
+            swept_at: None,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Idle> Buckets<K, V> {
+    pub fn get_or_insert_with(&mut self, key: K, now: Instant, new: impl FnOnce() -> V) -> &mut V {
+        let is_sweep_due = !matches!(self.swept_at,
+            Some(swept_at) if now.saturating_duration_since(swept_at) < SWEEP_INTERVAL);
+        if is_sweep_due {
+            self.buckets.retain(|_, bucket| !bucket.is_idle(now));
+            let buckets = &self.buckets;
+            self.order.retain(|key| buckets.contains_key(key));
+            self.swept_at = Some(now);
+        }
+        if !self.buckets.contains_key(&key) {
+            while self.buckets.len() >= MAX_BUCKETS {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.buckets.remove(&oldest);
+            }
+            self.order.push_back(key.clone());
+        }
+        self.buckets.entry(key).or_insert_with(new)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LimitsStats {
+    pub refused_queries: u64,
+    pub refused_resolutions: u64,
+}
+
+#[derive(Default)]
+pub struct ClientLimits {
+    buckets: Mutex<Buckets<IpAddr, TokenBucket>>,
+    resolutions: Mutex<HashMap<IpAddr, usize>>,
+    refused_queries: AtomicU64,
+    refused_resolutions: AtomicU64,
+}
+
+// Frees the resolution slot of the client when dropped
+pub struct ResolutionGuard<'a> {
+    limits: &'a ClientLimits,
+    client: IpAddr,
+}
+
+impl Drop for ResolutionGuard<'_> {
+    fn drop(&mut self) {
+        let mut resolutions = self.limits.resolutions.lock().unwrap();
+        if let Some(count) = resolutions.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                resolutions.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl ClientLimits {
+    pub fn allows_query(&self, config: &LimitsConfig, client: &IpAddr) -> bool {
+        self.allows_query_at(config, client, Instant::now())
+    }
+
+    fn allows_query_at(&self, config: &LimitsConfig, client: &IpAddr, now: Instant) -> bool {
+        let rate = config.queries_per_second as f64;
+        if rate == 0.0 {
+            return true;
+        }
+        let allowed = self
+            .buckets
+            .lock()
+            .unwrap()
+            .get_or_insert_with(*client, now, || TokenBucket::new(rate, now))
+            .take(rate, now);
+        if !allowed {
+            self.refused_queries.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    // None when the client already has as many resolutions in flight as allowed
+    pub fn start_resolution(
+        &self,
+        config: &LimitsConfig,
+        client: &IpAddr,
+    ) -> Option<ResolutionGuard<'_>> {
+        let mut resolutions = self.resolutions.lock().unwrap();
+        let count = resolutions.entry(*client).or_default();
+        if config.concurrent_resolutions > 0 && *count >= config.concurrent_resolutions {
+            self.refused_resolutions.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *count += 1;
+        Some(ResolutionGuard {
+            limits: self,
+            client: *client,
+        })
+    }
+
+    pub fn stats(&self) -> LimitsStats {
+        LimitsStats {
+            refused_queries: self.refused_queries.load(Ordering::Relaxed),
+            refused_resolutions: self.refused_resolutions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_client_exceeds_limits_then_it_is_refused() {
+        // Given
+        let config = LimitsConfig {
+            queries_per_second: 2,
+            concurrent_resolutions: 1,
+        };
+        let limits = ClientLimits::default();
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        // When
+        let queries: Vec<bool> = [client, client, client, other]
+            .iter()
+            .map(|address| limits.allows_query_at(&config, address, now))
+            .collect();
+        let guard = limits.start_resolution(&config, &client);
+        // Then
+        assert_eq!(queries, [true, true, false, true]);
+        assert!(limits.allows_query_at(&config, &client, now + Duration::from_secs(1)));
+        assert!(guard.is_some());
+        assert!(limits.start_resolution(&config, &client).is_none());
+        assert!(limits.start_resolution(&config, &other).is_some());
+        drop(guard);
+        assert!(limits.start_resolution(&config, &client).is_some());
+        let stats = limits.stats();
+        assert_eq!((stats.refused_queries, stats.refused_resolutions), (1, 1));
+    }
+
+    #[test]
+    fn test_when_buckets_are_full_or_idle_then_they_are_forgotten() {
+        // Given
+        let mut buckets: Buckets<usize, TokenBucket> = Buckets::default();
+        let now = Instant::now();
+        let mut take = |key, now| {
+            buckets
+                .get_or_insert_with(key, now, || TokenBucket::new(1.0, now))
+                .take(1.0, now)
+        };
+        // When
+        let taken = (0..=MAX_BUCKETS).filter(|&key| take(key, now)).count();
+        // Then the oldest bucket was forgotten, and is full again
+        assert_eq!(taken, MAX_BUCKETS + 1);
+        assert!(take(0, now));
+        assert!(!take(MAX_BUCKETS, now));
+        take(1, now + Duration::from_secs(1));
+        assert_eq!(buckets.buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_when_bucket_is_emptied_then_it_refills_at_the_rate_up_to_one_second() {
+        // Given
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, now);
+        let taken = (0..5).filter(|_| bucket.take(4.0, now)).count();
+        // When
+        let after_quarter = bucket.take(4.0, now + Duration::from_millis(250));
+        let too_soon = bucket.take(4.0, now + Duration::from_millis(300));
+        let later = now + Duration::from_secs(10);
+        let after_long_idle = (0..5).filter(|_| bucket.take(4.0, later)).count();
+        // Then
+        assert_eq!(taken, 4);
+        assert!(after_quarter);
+        assert!(!too_soon);
+        assert_eq!(after_long_idle, 4);
+        assert!(!bucket.is_idle(later + Duration::from_millis(999)));
+        assert!(bucket.is_idle(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_when_limits_are_zero_then_clients_are_unlimited() {
+        // Given
+        let config = LimitsConfig {
+            queries_per_second: 0,
+            concurrent_resolutions: 0,
+        };
+        let limits = ClientLimits::default();
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        // When
+        let queries = (0..1000)
+            .filter(|_| limits.allows_query_at(&config, &client, now))
+            .count();
+        let guards: Vec<_> = (0..100)
+            .filter_map(|_| limits.start_resolution(&config, &client))
+            .collect();
+        // Then
+        assert_eq!(queries, 1000);
+        assert_eq!(guards.len(), 100);
+        drop(guards);
+        assert!(limits.resolutions.lock().unwrap().is_empty());
+        let stats = limits.stats();
+        assert_eq!((stats.refused_queries, stats.refused_resolutions), (0, 0));
+    }
+}
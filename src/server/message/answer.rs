@@ -1,7 +1,14 @@
+use super::bytes_at;
+use super::name::{labels_bytes, presentation_name, read_name};
+#[cfg(test)]
 use super::question::Question;
-use std::collections::BTreeMap;
+use super::rdata::{parse_name, presentation_fields, RData};
+use super::record_type::{class_name, parse_class, RecordType};
+use anyhow::{bail, Context, Error, Result};
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Answer {
     pub name: Vec<u8>,
     pub atype: u16,
@@ -12,139 +19,208 @@ pub struct Answer {
     pub label: String,
 }
 
-impl Default for Answer {
-    fn default() -> Self {
+impl Answer {
+    pub fn new(label: &str, atype: RecordType, class: u16, ttl: u32, rdata: &RData) -> Self {
+        let rdata: Vec<u8> = rdata.into();
         Answer {
-            name: vec![],
-            atype: 0,
-            class: 0,
-            ttl: 0,
-            rdlength: 0,
-            rdata: vec![],
-            label: String::new(),
+            name: labels_bytes(label),
+            atype: atype.into(),
+            class,
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+            label: label.to_string(),
         }
     }
-}
 
-impl Answer {
+    // An answer owned by the question name, with the question type and class and no rdata yet,
+    // used by the tests of the codec
+    #[cfg(test)]
     pub fn for_question(question: &Question) -> Self {
         Answer {
             name: question.uncompressed_question().qname,
-            rdata: vec![0, 0, 0, 0], // random IP address
+            atype: question.qtype,
+            class: question.qclass,
             label: question.label.clone(),
             ..Default::default()
         }
     }
+
+    pub fn record_type(&self) -> RecordType {
+        RecordType::from(self.atype)
+    }
+
+    // Names in the stored rdata are never compressed, so it can be read on its own. Stored
+    // rdata was encoded from a valid RData, malformed bytes are only kept as unknown rdata.
+    pub fn rdata(&self) -> RData {
+        RData::from_bytes(&self.rdata, self.record_type(), 0, self.rdata.len())
+            .unwrap_or_else(|_| RData::Unknown(self.rdata.clone()))
+    }
 }
 
 impl Answer {
-    pub fn from_bytes(bytes: &[u8], ancount: u16, initial_offset: usize) -> (Vec<Self>, usize) {
+    // Names are stored uncompressed, so answers can be copied into other messages. The rdata
+    // is copied as is, except for the names it holds that may be compressed.
+    pub fn from_bytes(
+        message: &[u8],
+        ancount: u16,
+        initial_offset: usize,
+    ) -> Result<(Vec<Self>, usize)> {
         let mut answers: Vec<Answer> = vec![];
-        let mut labels_tree: BTreeMap<usize, String> = BTreeMap::new();
-        let mut offset = 0;
+        let mut offset = initial_offset;
 
         for _ in 0..ancount {
-            let label_start_offset = offset + initial_offset;
-            let mut is_label_ending_with_pointer = false;
-            loop {
-                let length_byte = bytes[offset] as usize;
-
-                let is_null_byte = length_byte == 0;
-                if is_null_byte {
-                    // Add end of current question token (empty string)
-                    labels_tree.insert(offset + initial_offset, String::new());
-                    offset += 1;
-                    break;
-                }
-
-                // Just the initial two bits are used to indicate the length of the label
-                let label_type = length_byte & 0b11000000;
-
-                let is_label_pointer = label_type == 0b11000000;
-                if is_label_pointer {
-                    // Get the pointed label
-                    let offset_pointer =
-                        u16::from_be_bytes([bytes[offset] & 0b00111111, bytes[offset + 1]])
-                            as usize;
-                    let label = Self::get_labels_from_btree(&labels_tree, offset_pointer);
-                    let label = label[..label.len() - 1].to_string(); // Remove the trailing dot
-
-                    // Add the label to the tree
-                    labels_tree.insert(offset + initial_offset, label);
-                    offset += 2;
-
-                    // Add end of current question token (empty string)
-                    labels_tree.insert(offset + initial_offset, String::new());
-                    is_label_ending_with_pointer = true;
-                    break;
-                }
-
-                // Add the label to the tree
-                let label =
-                    String::from_utf8(bytes[(offset + 1)..(offset + length_byte + 1)].to_vec())
-                        .unwrap();
-                labels_tree.insert(offset + initial_offset, label);
-                offset += length_byte + 1;
-            }
-
-            let label = Self::get_labels_from_btree(&labels_tree, label_start_offset);
-            let name = if is_label_ending_with_pointer {
-                bytes[(label_start_offset - initial_offset)..offset].to_vec()
-            } else {
-                bytes[(label_start_offset - initial_offset)..offset - 1].to_vec()
+            let (label, name, name_end_offset) = read_name(message, offset)?;
+            offset = name_end_offset;
+
+            let fields = bytes_at(message, offset, 10)?;
+            let atype = u16::from_be_bytes([fields[0], fields[1]]);
+            let class = u16::from_be_bytes([fields[2], fields[3]]);
+            let ttl = u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]);
+            let rdlength = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+            let rdata = RData::from_bytes(message, RecordType::from(atype), offset + 10, rdlength)
+                .with_context(|| format!("invalid rdata of '{label}'"))?;
+            let rdata = match rdata {
+                RData::Ns(_)
+                | RData::Cname(_)
+                | RData::Ptr(_)
+                | RData::Mx { .. }
+                | RData::Soa { .. } => Vec::from(&rdata),
+                _ => message[(offset + 10)..(offset + 10 + rdlength)].to_vec(),
             };
-            let atype = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
-            let class = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]);
-            let ttl = u32::from_be_bytes([
-                bytes[offset + 4],
-                bytes[offset + 5],
-                bytes[offset + 6],
-                bytes[offset + 7],
-            ]);
-            let rdlength = u16::from_be_bytes([bytes[offset + 8], bytes[offset + 9]]);
-            let rdata = bytes[(offset + 10)..(offset + 10 + rdlength as usize)].to_vec();
-            offset += 10 + rdlength as usize;
+            offset += 10 + rdlength;
 
             answers.push(Answer {
                 name,
                 atype,
                 class,
                 ttl,
-                rdlength,
+                rdlength: rdata.len() as u16,
                 rdata,
                 label,
             });
         }
 
-        return (answers, offset);
-    }
-
-    fn get_labels_from_btree(labels_tree: &BTreeMap<usize, String>, from_offset: usize) -> String {
-        let mut complete_label = String::new();
-        for (offset, label) in labels_tree {
-            if *offset < from_offset {
-                continue;
-            }
-            if label.is_empty() {
-                break;
-            }
-            complete_label.push_str(label);
-            complete_label.push_str(".");
-        }
-        complete_label
+        Ok((answers, offset))
     }
 }
 
-impl Into<Vec<u8>> for Answer {
-    fn into(self) -> Vec<u8> {
+impl From<Answer> for Vec<u8> {
+    fn from(answer: Answer) -> Self {
         let mut bytes = vec![];
-        bytes.extend(self.name);
+        bytes.extend(answer.name);
         bytes.push(0);
-        bytes.extend(self.atype.to_be_bytes());
-        bytes.extend(self.class.to_be_bytes());
-        bytes.extend(self.ttl.to_be_bytes());
-        bytes.extend(self.rdlength.to_be_bytes());
-        bytes.extend(self.rdata);
+        bytes.extend(answer.atype.to_be_bytes());
+        bytes.extend(answer.class.to_be_bytes());
+        bytes.extend(answer.ttl.to_be_bytes());
+        bytes.extend(answer.rdlength.to_be_bytes());
+        bytes.extend(answer.rdata);
         bytes
     }
 }
+
+// The record in presentation format: name, TTL, class, type and rdata
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            presentation_name(&self.label),
+            self.ttl,
+            class_name(self.class),
+            self.record_type(),
+            self.rdata()
+        )
+    }
+}
+
+// Parses a record as displayed, with every field given and names relative to the root
+impl FromStr for Answer {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        let fields = presentation_fields(string)?;
+        let [name, ttl, class, record_type, rdata_fields @ ..] = fields.as_slice() else {
+            bail!("a record needs a name, a TTL, a class, a type and rdata");
+        };
+        let ttl = ttl
+            .parse()
+            .with_context(|| format!("invalid TTL '{ttl}'"))?;
+        let class = parse_class(class)?;
+        let record_type: RecordType = record_type.parse()?;
+        let rdata = RData::parse(record_type, rdata_fields, "")
+            .with_context(|| format!("{record_type} record"))?;
+        Ok(Answer::new(
+            &parse_name(name, "")?,
+            record_type,
+            class,
+            ttl,
+            &rdata,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_records_are_displayed_then_they_parse_back() {
+        // Given
+        let lines = [
+            "example.com.\t300\tIN\tA\t192.0.2.1",
+            "example.com.\t300\tIN\tAAAA\t2001:db8::1",
+            "example.com.\t300\tIN\tNS\tns1.example.com.",
+            "www.example.com.\t60\tIN\tCNAME\texample.com.",
+            "1.2.0.192.in-addr.arpa.\t60\tIN\tPTR\texample.com.",
+            "example.com.\t300\tIN\tMX\t10 mail.example.com.",
+            "example.com.\t300\tIN\tTXT\t\"v=spf1 -all\" \"say \\\"hi\\\" \\\\o/\" \"caf\\195\\169\"",
+            "example.com.\t300\tIN\tSOA\tns1.example.com. hostmaster.example.com. 1 3600 900 604800 60",
+            "a\\.b\\032c.example.\t60\tIN\tTXT\t\"\\255\\000 \\\"\"",
+            "example.com.\t300\tCH\tTYPE65\t\\# 3 0A0B0C",
+            ".\t0\tIN\tTYPE99\t\\# 0",
+        ];
+        for line in lines {
+            // When
+            let answer: Answer = line.parse().unwrap();
+            // Then
+            assert_eq!(answer.to_string(), line);
+        }
+        let answer: Answer = "example.com. 300 IN TXT \"caf\\195\\169\"".parse().unwrap();
+        assert_eq!(answer.rdata(), RData::Txt(vec!["café".as_bytes().to_vec()]));
+    }
+
+    #[test]
+    fn test_when_records_are_malformed_then_parsing_fails() {
+        // Given
+        let inputs = [
+            (
+                "example.com. 300 IN",
+                "a record needs a name, a TTL, a class, a type and rdata",
+            ),
+            (
+                "example.com. soon IN A 192.0.2.1",
+                "invalid TTL 'soon': invalid digit found in string",
+            ),
+            ("example.com. 300 XX A 192.0.2.1", "unsupported class 'XX'"),
+            (
+                "example.com. 300 IN TXT \"open",
+                "unterminated quoted string",
+            ),
+            (
+                "example.com. 300 IN TYPE65 0A0B",
+                "TYPE65 record: rdata of unknown types must start with \\#",
+            ),
+            (
+                "example.com. 300 IN TYPE65 \\# 3 0A0B",
+                "TYPE65 record: rdata has 2 bytes instead of 3",
+            ),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = input.parse::<Answer>().unwrap_err();
+            // Then
+            assert_eq!(format!("{error:#}"), expected_error);
+        }
+    }
+}
@@ -1,6 +1,11 @@
+use anyhow::{bail, Error, Result};
+
 pub const HEADER_SIZE: usize = 12;
+// The authentic data and checking disabled bits of the Z field (RFC 4035)
+pub const Z_AD_FLAG: u8 = 0b010;
+pub const Z_CD_FLAG: u8 = 0b001;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Header {
     pub id: u16,
     pub qr: u8,
@@ -17,29 +22,33 @@ pub struct Header {
     pub arcount: u16,
 }
 
-impl Default for Header {
-    fn default() -> Self {
-        Header {
-            id: 0,
-            qr: 0,
-            opcode: 0,
-            aa: 0,
-            tc: 0,
-            rd: 0,
-            ra: 0,
-            z: 0,
-            rcode: 0,
-            qdcount: 0,
-            ancount: 0,
-            nscount: 0,
-            arcount: 0,
-        }
+impl Header {
+    // The flags that are set, in the order dig prints them
+    pub fn flags(&self) -> Vec<&'static str> {
+        [
+            ("qr", self.qr),
+            ("aa", self.aa),
+            ("tc", self.tc),
+            ("rd", self.rd),
+            ("ra", self.ra),
+            ("ad", self.z & Z_AD_FLAG),
+            ("cd", self.z & Z_CD_FLAG),
+        ]
+        .iter()
+        .filter(|(_, value)| *value != 0)
+        .map(|(flag, _)| *flag)
+        .collect()
     }
 }
 
-impl From<&[u8]> for Header {
-    fn from(bytes: &[u8]) -> Self {
-        Header {
+impl TryFrom<&[u8]> for Header {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            bail!("message of {} bytes is shorter than a header", bytes.len());
+        }
+        Ok(Header {
             id: u16::from_be_bytes([bytes[0], bytes[1]]),
             qr: bytes[2] >> 7,
             opcode: (bytes[2] >> 3) & 0b00001111,
@@ -53,25 +62,29 @@ impl From<&[u8]> for Header {
             ancount: u16::from_be_bytes([bytes[6], bytes[7]]),
             nscount: u16::from_be_bytes([bytes[8], bytes[9]]),
             arcount: u16::from_be_bytes([bytes[10], bytes[11]]),
-        }
+        })
     }
 }
 
-impl Into<Vec<u8>> for Header {
-    fn into(self) -> Vec<u8> {
+impl From<Header> for Vec<u8> {
+    fn from(header: Header) -> Self {
         vec![
-            (self.id >> 8) as u8,
-            self.id as u8,
-            (self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd,
-            (self.ra << 7) | (self.z << 4) | self.rcode,
-            (self.qdcount >> 8) as u8,
-            self.qdcount as u8,
-            (self.ancount >> 8) as u8,
-            self.ancount as u8,
-            (self.nscount >> 8) as u8,
-            self.nscount as u8,
-            (self.arcount >> 8) as u8,
-            self.arcount as u8,
+            (header.id >> 8) as u8,
+            header.id as u8,
+            (header.qr << 7)
+                | (header.opcode << 3)
+                | (header.aa << 2)
+                | (header.tc << 1)
+                | header.rd,
+            (header.ra << 7) | (header.z << 4) | header.rcode,
+            (header.qdcount >> 8) as u8,
+            header.qdcount as u8,
+            (header.ancount >> 8) as u8,
+            header.ancount as u8,
+            (header.nscount >> 8) as u8,
+            header.nscount as u8,
+            (header.arcount >> 8) as u8,
+            header.arcount as u8,
         ]
     }
 }
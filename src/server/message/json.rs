@@ -0,0 +1,326 @@
+// Messages as JSON objects, with the member names of RFC 8427
+use super::answer::Answer;
+use super::header::{Header, Z_AD_FLAG, Z_CD_FLAG};
+use super::name::{labels_bytes, presentation_name};
+use super::question::Question;
+use super::rdata::{hex_bytes, hex_string, parse_name, presentation_fields, RData};
+use super::record_type::{class_name, parse_class, RecordType, CLASS_IN};
+use super::Message;
+use crate::json::Value;
+use anyhow::{bail, Context, Result};
+
+const SECTIONS: [&str; 3] = ["answerRRs", "authorityRRs", "additionalRRs"];
+
+impl Message {
+    // A single question is given by the QNAME, QTYPE and QCLASS members, as in the examples
+    // of the RFC, several are given as questionRRs
+    pub fn to_json(&self) -> Value {
+        let header = &self.header;
+        let flag = |value: u8| Value::Boolean(value != 0);
+        let mut members = vec![
+            ("ID", Value::Integer(header.id.into())),
+            ("QR", flag(header.qr)),
+            ("Opcode", Value::Integer(header.opcode.into())),
+            ("AA", flag(header.aa)),
+            ("TC", flag(header.tc)),
+            ("RD", flag(header.rd)),
+            ("RA", flag(header.ra)),
+            ("AD", flag(header.z & Z_AD_FLAG)),
+            ("CD", flag(header.z & Z_CD_FLAG)),
+            ("RCODE", Value::Integer(header.rcode.into())),
+            ("QDCOUNT", Value::Integer(self.questions.len() as i64)),
+            ("ANCOUNT", Value::Integer(self.answers.len() as i64)),
+            ("NSCOUNT", Value::Integer(self.authorities.len() as i64)),
+            ("ARCOUNT", Value::Integer(self.additionals.len() as i64)),
+        ];
+        match self.questions.as_slice() {
+            [] => {}
+            [question] => members.extend(question_members(question, "Q")),
+            questions => members.push((
+                "questionRRs",
+                Value::Array(
+                    questions
+                        .iter()
+                        .map(|question| object(question_members(question, "")))
+                        .collect(),
+                ),
+            )),
+        }
+        let sections = [&self.answers, &self.authorities, &self.additionals];
+        for (name, records) in SECTIONS.into_iter().zip(sections) {
+            if !records.is_empty() {
+                members.push((name, Value::Array(records.iter().map(record).collect())));
+            }
+        }
+        object(members)
+    }
+
+    // Members that are missing are zero, or false for the flags. The counts are ignored, as
+    // they are the lengths of the sections.
+    pub fn from_json(value: &Value) -> Result<Self> {
+        if !matches!(value, Value::Object(_)) {
+            bail!("a message must be an object, not {}", value.type_name());
+        }
+        let flag = |name| -> Result<u8> {
+            match value.get(name) {
+                None => Ok(0),
+                Some(Value::Boolean(flag)) => Ok((*flag).into()),
+                Some(_) => bail!("{name} must be a boolean"),
+            }
+        };
+        let mut z = 0;
+        if flag("AD")? != 0 {
+            z |= Z_AD_FLAG;
+        }
+        if flag("CD")? != 0 {
+            z |= Z_CD_FLAG;
+        }
+        let header = Header {
+            id: integer(value, "ID", u16::MAX.into())?.unwrap_or_default() as u16,
+            qr: flag("QR")?,
+            opcode: integer(value, "Opcode", 15)?.unwrap_or_default() as u8,
+            aa: flag("AA")?,
+            tc: flag("TC")?,
+            rd: flag("RD")?,
+            ra: flag("RA")?,
+            z,
+            rcode: integer(value, "RCODE", 15)?.unwrap_or_default() as u8,
+            ..Header::default()
+        };
+
+        let questions = match (value.get("QNAME"), value.get("questionRRs")) {
+            (Some(_), Some(_)) => bail!("a message can't have both QNAME and questionRRs"),
+            (Some(_), None) => vec![question(value, "Q")?],
+            (None, Some(Value::Array(questions))) => questions
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    question(value, "").with_context(|| format!("questionRRs[{index}]"))
+                })
+                .collect::<Result<_>>()?,
+            (None, Some(_)) => bail!("questionRRs must be an array"),
+            (None, None) => vec![],
+        };
+        let mut sections = SECTIONS.into_iter().map(|name| match value.get(name) {
+            None => Ok(vec![]),
+            Some(Value::Array(records)) => records
+                .iter()
+                .enumerate()
+                .map(|(index, value)| answer(value).with_context(|| format!("{name}[{index}]")))
+                .collect(),
+            Some(_) => bail!("{name} must be an array"),
+        });
+
+        Ok(Message {
+            header,
+            questions,
+            answers: sections.next().unwrap()?,
+            authorities: sections.next().unwrap()?,
+            additionals: sections.next().unwrap()?,
+        })
+    }
+}
+
+fn object(members: Vec<(&str, Value)>) -> Value {
+    Value::Object(owned(members))
+}
+
+fn owned(members: Vec<(&str, Value)>) -> Vec<(String, Value)> {
+    members
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+// The members of a question are prefixed with Q at the top level of a message
+fn question_members(question: &Question, prefix: &str) -> Vec<(&'static str, Value)> {
+    let names: [&'static str; 5] = if prefix.is_empty() {
+        ["NAME", "TYPE", "TYPEname", "CLASS", "CLASSname"]
+    } else {
+        ["QNAME", "QTYPE", "QTYPEname", "QCLASS", "QCLASSname"]
+    };
+    let qtype = RecordType::from(question.qtype);
+    let values = [
+        Value::String(presentation_name(&question.label).to_string()),
+        Value::Integer(question.qtype.into()),
+        Value::String(qtype.to_string()),
+        Value::Integer(question.qclass.into()),
+        Value::String(class_name(question.qclass)),
+    ];
+    names.into_iter().zip(values).collect()
+}
+
+// The rdata is given both in presentation format, as rdata followed by the type, and as
+// hex. Rdata of unknown types, or that can't be read as its type, is only given as hex.
+fn record(answer: &Answer) -> Value {
+    let record_type = answer.record_type();
+    let mut members = owned(vec![
+        (
+            "NAME",
+            Value::String(presentation_name(&answer.label).to_string()),
+        ),
+        ("TYPE", Value::Integer(answer.atype.into())),
+        ("TYPEname", Value::String(record_type.to_string())),
+        ("CLASS", Value::Integer(answer.class.into())),
+        ("CLASSname", Value::String(class_name(answer.class))),
+        ("TTL", Value::Integer(answer.ttl.into())),
+        ("RDLENGTH", Value::Integer(answer.rdlength.into())),
+        ("RDATAHEX", Value::String(hex_string(&answer.rdata))),
+    ]);
+    let rdata = answer.rdata();
+    if !matches!(rdata, RData::Unknown(_)) {
+        members.push((
+            format!("rdata{record_type}"),
+            Value::String(rdata.to_string()),
+        ));
+    }
+    Value::Object(members)
+}
+
+fn question(value: &Value, prefix: &str) -> Result<Question> {
+    let member = |name: &str| format!("{prefix}{name}");
+    let label = name(value, &member("NAME"))?;
+    let qtype = record_type(value, &member("TYPE"))?;
+    let qclass = class(value, &member("CLASS"))?;
+    Ok(Question {
+        qname: labels_bytes(&label),
+        qtype: qtype.into(),
+        qclass,
+        label,
+    })
+}
+
+fn answer(value: &Value) -> Result<Answer> {
+    let label = name(value, "NAME")?;
+    let record_type = record_type(value, "TYPE")?;
+    let class = class(value, "CLASS")?;
+    let ttl = integer(value, "TTL", u32::MAX.into())?.unwrap_or_default() as u32;
+
+    let presentation = format!("rdata{record_type}");
+    let rdata = match (value.get(&presentation), value.get("RDATAHEX")) {
+        (Some(Value::String(text)), _) => {
+            RData::parse(record_type, &presentation_fields(text)?, "")?
+        }
+        (Some(_), _) => bail!("{presentation} must be a string"),
+        // The bytes of known types are read from their presentation format only, so
+        // malformed rdata can't get into a message
+        (None, Some(Value::String(hex))) if matches!(record_type, RecordType::Unknown(_)) => {
+            RData::Unknown(hex_bytes(hex)?)
+        }
+        (None, Some(Value::String(_))) => bail!("{record_type} records need {presentation}"),
+        (None, Some(_)) => bail!("RDATAHEX must be a string"),
+        (None, None) if record_type == RecordType::Opt => RData::Unknown(vec![]),
+        (None, None) => bail!("missing {presentation} or RDATAHEX"),
+    };
+    Ok(Answer::new(&label, record_type, class, ttl, &rdata))
+}
+
+fn name(value: &Value, member: &str) -> Result<String> {
+    match value.get(member) {
+        Some(Value::String(name)) => parse_name(name, ""),
+        Some(_) => bail!("{member} must be a string"),
+        None => bail!("missing {member}"),
+    }
+}
+
+// The type is given as a number, or by name with the name suffix
+fn record_type(value: &Value, member: &str) -> Result<RecordType> {
+    if let Some(number) = integer(value, member, u16::MAX.into())? {
+        return Ok(RecordType::from(number as u16));
+    }
+    match value.get(&format!("{member}name")) {
+        Some(Value::String(name)) => name.parse(),
+        Some(_) => bail!("{member}name must be a string"),
+        None => bail!("missing {member}"),
+    }
+}
+
+// The class is IN when it isn't given
+fn class(value: &Value, member: &str) -> Result<u16> {
+    if let Some(number) = integer(value, member, u16::MAX.into())? {
+        return Ok(number as u16);
+    }
+    match value.get(&format!("{member}name")) {
+        Some(Value::String(name)) => parse_class(name),
+        Some(_) => bail!("{member}name must be a string"),
+        None => Ok(CLASS_IN),
+    }
+}
+
+fn integer(value: &Value, member: &str, max: i64) -> Result<Option<i64>> {
+    match value.get(member) {
+        None => Ok(None),
+        Some(Value::Integer(integer)) if (0..=max).contains(integer) => Ok(Some(*integer)),
+        Some(_) => bail!("{member} must be an integer between 0 and {max}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn test_when_message_is_written_as_json_then_it_reads_back() {
+        // Given
+        let request = json::parse(
+            r#"{"ID": 19678, "QR": false, "Opcode": 0, "RD": true, "CD": true,
+                "QNAME": "example.com.", "QTYPEname": "MX"}"#,
+        )
+        .unwrap();
+        let mut message = Message::from_json(&request).unwrap();
+        message.header.qr = 1;
+        message.answers = vec![
+            "example.com. 300 IN MX 10 mail.example.com."
+                .parse()
+                .unwrap(),
+            "example.com. 300 IN TYPE65 \\# 2 0A0B".parse().unwrap(),
+        ];
+        // When
+        let json = message.to_json().to_string();
+        let bytes =
+            Vec::<u8>::try_from(Message::from_json(&json::parse(&json).unwrap()).unwrap()).unwrap();
+        // Then
+        assert_eq!(
+            json,
+            "{\"ID\":19678,\"QR\":true,\"Opcode\":0,\"AA\":false,\"TC\":false,\"RD\":true,\
+             \"RA\":false,\"AD\":false,\"CD\":true,\"RCODE\":0,\"QDCOUNT\":1,\"ANCOUNT\":2,\
+             \"NSCOUNT\":0,\"ARCOUNT\":0,\"QNAME\":\"example.com.\",\"QTYPE\":15,\
+             \"QTYPEname\":\"MX\",\"QCLASS\":1,\"QCLASSname\":\"IN\",\"answerRRs\":[\
+             {\"NAME\":\"example.com.\",\"TYPE\":15,\"TYPEname\":\"MX\",\"CLASS\":1,\
+             \"CLASSname\":\"IN\",\"TTL\":300,\"RDLENGTH\":20,\
+             \"RDATAHEX\":\"000A046D61696C076578616D706C6503636F6D00\",\
+             \"rdataMX\":\"10 mail.example.com.\"},\
+             {\"NAME\":\"example.com.\",\"TYPE\":65,\"TYPEname\":\"TYPE65\",\"CLASS\":1,\
+             \"CLASSname\":\"IN\",\"TTL\":300,\"RDLENGTH\":2,\"RDATAHEX\":\"0A0B\"}]}"
+        );
+        assert_eq!(bytes, Vec::<u8>::try_from(message).unwrap());
+    }
+
+    #[test]
+    fn test_when_json_message_is_malformed_then_error_names_the_member() {
+        // Given
+        let inputs = [
+            ("[]", "a message must be an object, not an array"),
+            (
+                "{\"ID\": 70000}",
+                "ID must be an integer between 0 and 65535",
+            ),
+            ("{\"RD\": 1}", "RD must be a boolean"),
+            (
+                "{\"questionRRs\": [{\"TYPE\": 1}]}",
+                "questionRRs[0]: missing NAME",
+            ),
+            (
+                "{\"answerRRs\": [{\"NAME\": \"a.\", \"TYPE\": 1, \"RDATAHEX\": \"C0000201\"}]}",
+                "answerRRs[0]: A records need rdataA",
+            ),
+        ];
+        for (input, expected_error) in inputs {
+            // When
+            let error = Message::from_json(&json::parse(input).unwrap()).unwrap_err();
+            // Then
+            assert_eq!(format!("{error:#}"), expected_error);
+        }
+    }
+}
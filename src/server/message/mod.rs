@@ -1,50 +1,167 @@
 use self::answer::Answer;
 use self::header::{Header, HEADER_SIZE};
+use self::opcode::Opcode;
 use self::question::Question;
+use self::rcode::Rcode;
+use self::rdata::RData;
+use self::record_type::RecordType;
+use anyhow::{bail, Context, Error, Result};
+use std::fmt;
+use std::mem;
 
 pub mod answer;
 pub mod header;
+pub mod json;
+pub mod name;
+pub mod opcode;
 pub mod question;
+pub mod rcode;
+pub mod rdata;
+pub mod record_type;
 
-#[derive(Debug)]
+// The DNSSEC OK bit of the EDNS flags, in the TTL of the OPT record (RFC 3225)
+pub const EDNS_DO_FLAG: u32 = 0x8000;
+// The largest UDP message without EDNS (RFC 1035 section 2.3.4)
+pub const UDP_MESSAGE_SIZE: usize = 512;
+// The UDP buffer size advertised with EDNS, which avoids IP fragmentation (DNS flag day 2020)
+pub const EDNS_BUFFER_SIZE: u16 = 1232;
+// The largest message over TCP, whose length prefix is a two bytes integer
+pub const TCP_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone)]
 pub struct Message {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<Answer>,
+    pub authorities: Vec<Answer>,
+    pub additionals: Vec<Answer>,
 }
 
-impl From<&[u8]> for Message {
-    fn from(bytes: &[u8]) -> Self {
-        let header = Header::from(&bytes[0..HEADER_SIZE]);
-        let mut initial_offset = HEADER_SIZE;
+impl TryFrom<&[u8]> for Message {
+    type Error = Error;
 
-        let (questions, questions_offset) =
-            Question::from_bytes(&bytes[initial_offset..], header.qdcount, initial_offset);
-        initial_offset += questions_offset;
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let header = Header::try_from(bytes)?;
 
-        let (answers, _) =
-            Answer::from_bytes(&bytes[initial_offset..], header.ancount, initial_offset);
+        let (questions, answers_offset) = Question::from_bytes(bytes, header.qdcount, HEADER_SIZE)?;
+        let (answers, authorities_offset) =
+            Answer::from_bytes(bytes, header.ancount, answers_offset)?;
+        let (authorities, additionals_offset) =
+            Answer::from_bytes(bytes, header.nscount, authorities_offset)?;
+        let (additionals, _) = Answer::from_bytes(bytes, header.arcount, additionals_offset)?;
 
-        Message {
+        Ok(Message {
             header,
             questions,
             answers,
-        }
+            authorities,
+            additionals,
+        })
+    }
+}
+
+// The length bytes at offset, or an error when the message ends before them
+pub fn bytes_at(message: &[u8], offset: usize, length: usize) -> Result<&[u8]> {
+    match message.get(offset..offset + length) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("message is truncated at offset {offset}"),
     }
 }
 
 impl Message {
-    pub fn response_message(&self, answers: Vec<Answer>) -> Self {
-        let rcode: u8 = match self.header.opcode {
-            0 => 0,
-            _ => 4,
+    pub fn opcode(&self) -> Opcode {
+        Opcode::from(self.header.opcode)
+    }
+
+    // The OPT record of the additional section, when the message uses EDNS (RFC 6891)
+    pub fn edns(&self) -> Option<&Answer> {
+        self.additionals
+            .iter()
+            .find(|record| record.record_type() == RecordType::Opt)
+    }
+
+    // The largest UDP response the requester accepts, capped by the buffer of this server
+    pub fn udp_response_size(&self) -> usize {
+        match self.edns() {
+            Some(opt) => (opt.class as usize).clamp(UDP_MESSAGE_SIZE, EDNS_BUFFER_SIZE as usize),
+            None => UDP_MESSAGE_SIZE,
+        }
+    }
+
+    // A response that doesn't fit keeps its question and OPT record with the tc flag set, so
+    // the client asks again over TCP
+    pub fn truncate(&mut self, max_size: usize) {
+        if Vec::<u8>::try_from(self.clone()).is_ok_and(|bytes| bytes.len() <= max_size) {
+            return;
+        }
+        self.answers.clear();
+        self.authorities.clear();
+        self.additionals
+            .retain(|record| record.record_type() == RecordType::Opt);
+        self.header.tc = 1;
+    }
+
+    // A response too large for one message, like a zone transfer, is sent as several messages
+    // with the same header and questions and the answers spread over them (RFC 5936 section
+    // 2.2). The authority and additional records stay in the first one.
+    pub fn split(mut self, max_size: usize) -> Vec<Self> {
+        let answers = mem::take(&mut self.answers);
+        let mut size = Vec::<u8>::try_from(self.clone()).map_or(0, |bytes| bytes.len());
+        let next_message = Message {
+            header: self.header.clone(),
+            questions: self.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
         };
+        let mut messages = vec![self];
+
+        for answer in answers {
+            let answer_size = Vec::<u8>::from(answer.clone()).len();
+            let message = messages.last_mut().unwrap();
+            if size + answer_size > max_size && !message.answers.is_empty() {
+                size = Vec::<u8>::try_from(next_message.clone()).map_or(0, |bytes| bytes.len());
+                messages.push(next_message.clone());
+            }
+            size += answer_size;
+            messages.last_mut().unwrap().answers.push(answer);
+        }
+        messages
+    }
+
+    // A recursive query, as stub resolvers send them
+    #[cfg(test)]
+    pub fn query(id: u16, questions: Vec<Question>) -> Self {
+        Message {
+            header: Header {
+                id,
+                rd: 1,
+                ..Header::default()
+            },
+            questions,
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+
+    // Requests with EDNS get an OPT record advertising this server's buffer, with the DNSSEC
+    // OK bit copied (RFC 3225 section 3)
+    pub fn response_message(&self, answers: Vec<Answer>, rcode: Rcode) -> Self {
         let response_header = Header {
             qr: 1,
-            ancount: answers.len() as u16,
-            rcode,
+            rcode: rcode.into(),
             ..self.header
         };
+        let additionals = self
+            .edns()
+            .map(|opt| {
+                let flags = opt.ttl & EDNS_DO_FLAG;
+                let rdata = RData::Unknown(vec![]);
+                Answer::new(".", RecordType::Opt, EDNS_BUFFER_SIZE, flags, &rdata)
+            })
+            .into_iter()
+            .collect();
 
         Message {
             header: response_header,
@@ -53,37 +170,119 @@ impl Message {
                 .iter()
                 .map(Question::uncompressed_question)
                 .collect(),
-            answers: answers,
+            answers,
+            authorities: vec![],
+            additionals,
         }
     }
 }
 
-impl Into<Vec<u8>> for Message {
-    fn into(self) -> Vec<u8> {
-        let header_bytes: Vec<u8> = self.header.into();
+// The section counts of the header are the lengths of the sections, which fail to encode
+// when they don't fit in them
+impl TryFrom<Message> for Vec<u8> {
+    type Error = Error;
 
-        let questions_bytes: Vec<u8> = self
+    fn try_from(message: Message) -> Result<Self> {
+        let count = |section: &str, length: usize| {
+            u16::try_from(length)
+                .with_context(|| format!("{length} records don't fit in the {section} section"))
+        };
+        let header = Header {
+            qdcount: count("question", message.questions.len())?,
+            ancount: count("answer", message.answers.len())?,
+            nscount: count("authority", message.authorities.len())?,
+            arcount: count("additional", message.additionals.len())?,
+            ..message.header
+        };
+        let header_bytes: Vec<u8> = header.into();
+
+        let questions_bytes: Vec<u8> = message
             .questions
-            .iter()
-            .map(|q| Into::<Vec<u8>>::into(q.clone()))
-            .flatten()
+            .into_iter()
+            .flat_map(Into::<Vec<u8>>::into)
             .collect();
 
-        let answers_bytes: Vec<u8> = self
-            .answers
-            .iter()
-            .map(|a| Into::<Vec<u8>>::into(a.clone()))
+        let records_bytes: Vec<u8> = [message.answers, message.authorities, message.additionals]
+            .into_iter()
             .flatten()
+            .flat_map(Into::<Vec<u8>>::into)
             .collect();
 
-        [header_bytes, questions_bytes, answers_bytes].concat()
+        Ok([header_bytes, questions_bytes, records_bytes].concat())
+    }
+}
+
+// The message the way dig prints it, with records in presentation format
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            self.opcode(),
+            Rcode::from(header.rcode),
+            header.id
+        )?;
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            header.flags().join(" "),
+            header.qdcount,
+            header.ancount,
+            header.nscount,
+            header.arcount
+        )?;
+
+        // The OPT record is shown as a pseudosection rather than as a record
+        let (opt, additionals): (Vec<&Answer>, Vec<&Answer>) = self
+            .additionals
+            .iter()
+            .partition(|answer| answer.record_type() == RecordType::Opt);
+        if let Some(opt) = opt.first() {
+            let version = (opt.ttl >> 16) & 0xff;
+            let flags = if opt.ttl & EDNS_DO_FLAG != 0 {
+                " do"
+            } else {
+                ""
+            };
+            write!(
+                f,
+                "\n\n;; OPT PSEUDOSECTION:\n; EDNS: version: {version}, flags:{flags}; udp: {}",
+                opt.class
+            )?;
+        }
+
+        if !self.questions.is_empty() {
+            write!(f, "\n\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                write!(f, "\n{question}")?;
+            }
+        }
+        let sections = [
+            ("ANSWER", self.answers.iter().collect()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additionals),
+        ];
+        for (section, records) in sections {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n\n;; {section} SECTION:")?;
+            for record in records {
+                write!(f, "\n{record}")?;
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::net::Ipv4Addr;
     use std::vec;
 
+    use super::rdata::RData;
+    use super::record_type::{RecordType::A, CLASS_IN};
     use super::*;
 
     const MESSAGE_BYTES: &[u8] = &[
@@ -96,7 +295,7 @@ mod test {
     fn test_when_two_questions_with_compression_then_they_are_decompressed_correctly() {
         // Given
         // When
-        let request_message = Message::from(MESSAGE_BYTES);
+        let request_message = Message::try_from(MESSAGE_BYTES).unwrap();
         // Then
         assert_eq!(request_message.header.qdcount, 2);
         assert_eq!(request_message.questions.len(), 2);
@@ -124,7 +323,7 @@ mod test {
     #[test]
     fn test_when_request_with_compressed_questions_then_response_questions_are_uncompressed() {
         // Given
-        let request_message = Message::from(MESSAGE_BYTES);
+        let request_message = Message::try_from(MESSAGE_BYTES).unwrap();
         // When
         let uncompressed_question = request_message.questions[0].uncompressed_question();
         // Then
@@ -150,7 +349,7 @@ mod test {
     #[test]
     fn test_when_response_answer_questions_are_uncompressed() {
         // Given
-        let request_message = Message::from(MESSAGE_BYTES);
+        let request_message = Message::try_from(MESSAGE_BYTES).unwrap();
         // When
         let answer = Answer::for_question(&request_message.questions[0]);
         // Then
@@ -172,4 +371,155 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_when_message_is_displayed_then_it_looks_like_dig_output() {
+        // Given
+        let request_message = Message::try_from(MESSAGE_BYTES).unwrap();
+        let answer: Answer = "abc.longassdomainname.com. 60 IN A 192.0.2.1"
+            .parse()
+            .unwrap();
+        let mut response_message = request_message.response_message(vec![answer], Rcode::NoError);
+        response_message.header.ra = 1;
+        response_message.additionals = vec![Answer::new(
+            ".",
+            RecordType::Opt,
+            1232,
+            EDNS_DO_FLAG,
+            &RData::Unknown(vec![]),
+        )];
+        // When
+        let bytes = Vec::<u8>::try_from(response_message).unwrap();
+        let output = Message::try_from(bytes.as_slice()).unwrap().to_string();
+        // Then
+        assert_eq!(
+            output,
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 37019\n\
+             ;; flags: qr rd ra; QUERY: 2, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags: do; udp: 1232\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;abc.longassdomainname.com.\t\tIN\tA\n\
+             ;def.longassdomainname.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             abc.longassdomainname.com.\t60\tIN\tA\t192.0.2.1"
+        );
+    }
+
+    #[test]
+    fn test_when_message_is_malformed_then_parsing_fails() {
+        // Given a valid response, cut at every length and with rdata running past its end
+        let answer: Answer = "a.example. 60 IN TXT \"text\"".parse().unwrap();
+        let mut response_message = Message::try_from(MESSAGE_BYTES)
+            .unwrap()
+            .response_message(vec![answer], Rcode::NoError);
+        response_message.questions.truncate(1);
+        let bytes = Vec::<u8>::try_from(response_message).unwrap();
+        let mut overrun_txt = bytes.clone();
+        *overrun_txt.iter_mut().rev().nth(4).unwrap() = 200;
+        let pointer_loop = [&MESSAGE_BYTES[..12], &[0b11000000, 12, 0, 1, 0, 1]].concat();
+        let pointer_past_end = [&MESSAGE_BYTES[..12], &[0b11000000, 200, 0, 1, 0, 1]].concat();
+        // When
+        // Then
+        for length in 0..bytes.len() {
+            assert!(Message::try_from(&bytes[..length]).is_err(), "{length}");
+        }
+        assert_eq!(
+            format!(
+                "{:#}",
+                Message::try_from(overrun_txt.as_slice()).unwrap_err()
+            ),
+            "invalid rdata of 'a.example.': TXT string of 200 bytes runs past the rdata"
+        );
+        assert_eq!(
+            Message::try_from(pointer_loop.as_slice())
+                .unwrap_err()
+                .to_string(),
+            "name at offset 12 has a compression pointers loop"
+        );
+        assert_eq!(
+            Message::try_from(pointer_past_end.as_slice())
+                .unwrap_err()
+                .to_string(),
+            "name at offset 12 runs past the end of the message"
+        );
+    }
+
+    #[test]
+    fn test_when_labels_and_strings_are_not_text_then_they_are_copied_unchanged() {
+        // Given a question for a name with a dot in its first label and a second label of 63
+        // bytes that aren't UTF-8, answered by a TXT string of 255 such bytes and by a CNAME
+        // compressed against the question
+        let qname = [&[3, b'a', b'.', b'b', 63][..], &[0xe9; 63]].concat();
+        let txt_rdata = [&[255][..], &[0xe9; 255]].concat();
+        let bytes = [
+            &[0, 1, 0b10000001, 0, 0, 1, 0, 2, 0, 0, 0, 0][..],
+            &qname,
+            &[0, 0, 16, 0, 1],
+            &[0b11000000, 12, 0, 16, 0, 1, 0, 0, 0, 60, 1, 0],
+            &txt_rdata,
+            &[
+                0b11000000, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0b11000000, 12,
+            ],
+        ]
+        .concat();
+        // When
+        let message = Message::try_from(bytes.as_slice()).unwrap();
+        let encoded = Vec::<u8>::try_from(message.clone()).unwrap();
+        let decoded = Message::try_from(encoded.as_slice()).unwrap();
+        // Then
+        let label = format!("a\\.b.{}.", "\\233".repeat(63));
+        assert_eq!(message.questions[0].label, label);
+        assert_eq!(message.questions[0].uncompressed_question().qname, qname);
+        assert_eq!(message.answers[0].name, qname);
+        assert_eq!(message.answers[0].rdata, txt_rdata);
+        assert_eq!(message.answers[1].rdata, [&qname[..], &[0]].concat());
+        assert_eq!(decoded.questions[0].label, label);
+        assert_eq!(Vec::<u8>::try_from(decoded).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_when_answers_do_not_fit_in_one_message_then_they_are_split() {
+        // Given a response of 5000 records, about 150 KB
+        let request_message = Message::try_from(MESSAGE_BYTES).unwrap();
+        let answers: Vec<Answer> = (0..5000)
+            .map(|index| {
+                let rdata = RData::A(Ipv4Addr::from(index));
+                Answer::new("abc.longassdomainname.com.", A, CLASS_IN, 60, &rdata)
+            })
+            .collect();
+        let response_message = request_message.response_message(answers.clone(), Rcode::NoError);
+        // When
+        let messages = response_message.split(TCP_MESSAGE_SIZE);
+        // Then
+        assert!(messages.len() > 1);
+        let mut split_answers = vec![];
+        for message in messages {
+            let bytes = Vec::<u8>::try_from(message).unwrap();
+            assert!(bytes.len() <= TCP_MESSAGE_SIZE);
+            let message = Message::try_from(bytes.as_slice()).unwrap();
+            assert_eq!(message.header.id, 37019);
+            assert_eq!(message.questions.len(), 2);
+            split_answers.extend(message.answers);
+        }
+        assert_eq!(split_answers, answers);
+    }
+
+    #[test]
+    fn test_when_a_section_has_too_many_records_then_encoding_fails() {
+        // Given
+        let answer = Answer::new(".", A, CLASS_IN, 60, &RData::A(Ipv4Addr::UNSPECIFIED));
+        let mut message = Message::try_from(MESSAGE_BYTES).unwrap();
+        message.additionals = vec![answer; 65536];
+        // When
+        let result = Vec::<u8>::try_from(message);
+        // Then
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "65536 records don't fit in the additional section"
+        );
+    }
 }
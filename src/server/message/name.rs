@@ -0,0 +1,201 @@
+use anyhow::{bail, Context, Result};
+use std::fmt::Write as _;
+use std::mem;
+
+pub const MAX_LABEL_LENGTH: usize = 63;
+pub const MAX_NAME_LENGTH: usize = 255;
+// A name can't be longer than 255 bytes, so more jumps than this is a pointers loop
+const MAX_POINTER_JUMPS: usize = 128;
+
+// Reads the name starting at offset, following compression pointers anywhere in the
+// message. Returns the dotted label ("abc.example.com."), the labels as they are on the
+// wire but uncompressed and without the null byte, and the offset right after the name as
+// it is stored at offset.
+pub fn read_name(message: &[u8], offset: usize) -> Result<(String, Vec<u8>, usize)> {
+    let mut label = String::new();
+    let mut labels = vec![];
+    let mut position = offset;
+    let mut end_offset = None;
+    let mut jumps = 0;
+    // The length of the name once uncompressed, with its length bytes and null byte
+    let mut name_length = 1;
+
+    loop {
+        let Some(&length_byte) = message.get(position) else {
+            bail!("name at offset {offset} runs past the end of the message");
+        };
+        let length_byte = length_byte as usize;
+
+        let is_null_byte = length_byte == 0;
+        if is_null_byte {
+            position += 1;
+            break;
+        }
+
+        // Just the initial two bits are used to indicate the type of the label
+        let is_label_pointer = length_byte & 0b11000000 == 0b11000000;
+        if is_label_pointer {
+            let Some(&pointer_low) = message.get(position + 1) else {
+                bail!("name at offset {offset} runs past the end of the message");
+            };
+            let pointer = u16::from_be_bytes([message[position] & 0b00111111, pointer_low]);
+            end_offset.get_or_insert(position + 2);
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                bail!("name at offset {offset} has a compression pointers loop");
+            }
+            position = pointer as usize;
+            continue;
+        }
+        if length_byte > MAX_LABEL_LENGTH {
+            bail!("name at offset {offset} has an unsupported label type");
+        }
+
+        let Some(label_bytes) = message.get((position + 1)..(position + length_byte + 1)) else {
+            bail!("name at offset {offset} runs past the end of the message");
+        };
+        name_length += length_byte + 1;
+        if name_length > MAX_NAME_LENGTH {
+            bail!("name at offset {offset} is longer than {MAX_NAME_LENGTH} bytes");
+        }
+        labels.push(length_byte as u8);
+        labels.extend(label_bytes);
+        escape_label(label_bytes, &mut label);
+        label.push('.');
+        position += length_byte + 1;
+    }
+
+    Ok((label, labels, end_offset.unwrap_or(position)))
+}
+
+// Writes a label as text that reads back to the same bytes: dots, backslashes and the
+// characters special in zone files are escaped as \X, and bytes that aren't printable ASCII
+// as \DDD (RFC 1035 section 5.1)
+fn escape_label(bytes: &[u8], text: &mut String) {
+    for &byte in bytes {
+        match byte {
+            b'.' | b'\\' | b'"' | b';' | b'(' | b')' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x21..=0x7e => text.push(byte as char),
+            _ => {
+                let _ = write!(text, "\\{byte:03}");
+            }
+        }
+    }
+}
+
+// Reads the byte escaped after a backslash: \DDD is the byte of decimal value DDD, and \X
+// stands for X
+pub fn escaped_byte(bytes: &mut impl Iterator<Item = u8>) -> Result<u8> {
+    let byte = bytes
+        .next()
+        .context("escape '\\' without a character after it")?;
+    if !byte.is_ascii_digit() {
+        return Ok(byte);
+    }
+    let digits: Vec<u8> = [Some(byte), bytes.next(), bytes.next()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let digits = String::from_utf8_lossy(&digits);
+    digits
+        .parse::<u8>()
+        .ok()
+        .filter(|_| digits.len() == 3 && digits.bytes().all(|digit| digit.is_ascii_digit()))
+        .with_context(|| format!("invalid escape '\\{digits}'"))
+}
+
+// Splits a dotted name in the bytes of its labels, reading the escapes of escape_label. The
+// root name has no labels.
+pub fn name_labels(name: &str) -> Result<Vec<Vec<u8>>> {
+    if name.is_empty() || name == "." {
+        return Ok(vec![]);
+    }
+    let mut labels = vec![];
+    let mut label = vec![];
+    let mut bytes = name.bytes();
+    let mut ends_with_dot = false;
+    while let Some(byte) = bytes.next() {
+        ends_with_dot = byte == b'.';
+        match byte {
+            b'.' => labels.push(mem::take(&mut label)),
+            b'\\' => label.push(escaped_byte(&mut bytes)?),
+            _ => label.push(byte),
+        }
+    }
+    if !ends_with_dot {
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+// The name written the way read_name writes it, so equal names compare equal as text
+pub fn canonical_name(labels: &[Vec<u8>]) -> String {
+    if labels.is_empty() {
+        return ".".to_string();
+    }
+    let mut name = String::new();
+    for label in labels {
+        escape_label(label, &mut name);
+        name.push('.');
+    }
+    name
+}
+
+// Checks that a dotted name read from text can be encoded: valid escapes, no empty label,
+// labels of at most 63 bytes, and at most 255 bytes once encoded. Returns its labels.
+pub fn check_name(name: &str) -> Result<Vec<Vec<u8>>> {
+    let labels = name_labels(name).with_context(|| format!("invalid name '{name}'"))?;
+    let mut name_length = 1;
+    for label in &labels {
+        if label.is_empty() {
+            bail!("name '{name}' has an empty label");
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            bail!(
+                "label '{}' is longer than {MAX_LABEL_LENGTH} bytes",
+                String::from_utf8_lossy(label)
+            );
+        }
+        name_length += label.len() + 1;
+    }
+    if name_length > MAX_NAME_LENGTH {
+        bail!("name '{name}' is longer than {MAX_NAME_LENGTH} bytes");
+    }
+    Ok(labels)
+}
+
+// Encodes a dotted label as length prefixed labels, without the ending null byte. Names
+// from text are checked with check_name, and names from messages are escaped when read, so
+// their labels fit in a length byte.
+pub fn labels_bytes(label: &str) -> Vec<u8> {
+    let labels = name_labels(label).unwrap_or_else(|_| {
+        label
+            .split('.')
+            .filter(|label| !label.is_empty())
+            .map(|label| label.as_bytes().to_vec())
+            .collect()
+    });
+    labels
+        .into_iter()
+        .flat_map(|label| [vec![label.len() as u8], label].concat())
+        .collect()
+}
+
+// Encodes a dotted label as an uncompressed name, including the ending null byte
+pub fn name_bytes(label: &str) -> Vec<u8> {
+    let mut bytes = labels_bytes(label);
+    bytes.push(0);
+    bytes
+}
+
+// The root name has no labels, it is written as a single dot
+pub fn presentation_name(label: &str) -> &str {
+    if label.is_empty() {
+        "."
+    } else {
+        label
+    }
+}
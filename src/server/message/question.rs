@@ -1,4 +1,10 @@
-use std::collections::BTreeMap;
+use super::bytes_at;
+use super::name::{labels_bytes, presentation_name, read_name};
+#[cfg(test)]
+use super::record_type::CLASS_IN;
+use super::record_type::{class_name, RecordType};
+use anyhow::Result;
+use std::fmt;
 
 #[derive(Debug, Clone)]
 pub struct Question {
@@ -9,63 +15,22 @@ pub struct Question {
 }
 
 impl Question {
-    pub fn from_bytes(bytes: &[u8], qdcount: u16, initial_offset: usize) -> (Vec<Self>, usize) {
+    pub fn from_bytes(
+        message: &[u8],
+        qdcount: u16,
+        initial_offset: usize,
+    ) -> Result<(Vec<Self>, usize)> {
         let mut questions = vec![];
-        let mut labels_tree: BTreeMap<usize, String> = BTreeMap::new();
-        let mut offset = 0;
+        let mut offset = initial_offset;
 
         for _ in 0..qdcount {
-            let label_start_offset = offset + initial_offset;
-            let mut is_label_ending_with_pointer = false;
-            loop {
-                let length_byte = bytes[offset] as usize;
+            let (label, _, name_end_offset) = read_name(message, offset)?;
+            let qname = Self::qname_bytes(message, offset);
+            offset = name_end_offset;
 
-                let is_null_byte = length_byte == 0;
-                if is_null_byte {
-                    // Add end of current question token (empty string)
-                    labels_tree.insert(offset + initial_offset, String::new());
-                    offset += 1;
-                    break;
-                }
-
-                // Just the initial two bits are used to indicate the length of the label
-                let label_type = length_byte & 0b11000000;
-
-                let is_label_pointer = label_type == 0b11000000;
-                if is_label_pointer {
-                    // Get the pointed label
-                    let offset_pointer =
-                        u16::from_be_bytes([bytes[offset] & 0b00111111, bytes[offset + 1]])
-                            as usize;
-                    let label = Self::get_labels_from_btree(&labels_tree, offset_pointer);
-                    let label = label[..label.len() - 1].to_string(); // Remove the trailing dot
-
-                    // Add the label to the tree
-                    labels_tree.insert(offset + initial_offset, label);
-                    offset += 2;
-
-                    // Add end of current question token (empty string)
-                    labels_tree.insert(offset + initial_offset, String::new());
-                    is_label_ending_with_pointer = true;
-                    break;
-                }
-
-                // Add the label to the tree
-                let label =
-                    String::from_utf8(bytes[(offset + 1)..(offset + length_byte + 1)].to_vec())
-                        .unwrap();
-                labels_tree.insert(offset + initial_offset, label);
-                offset += length_byte + 1;
-            }
-
-            let label = Self::get_labels_from_btree(&labels_tree, label_start_offset);
-            let qname = if is_label_ending_with_pointer {
-                bytes[(label_start_offset - initial_offset)..offset].to_vec()
-            } else {
-                bytes[(label_start_offset - initial_offset)..offset - 1].to_vec()
-            };
-            let qtype = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
-            let qclass = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]);
+            let fields = bytes_at(message, offset, 4)?;
+            let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+            let qclass = u16::from_be_bytes([fields[2], fields[3]]);
             offset += 4;
 
             questions.push(Question {
@@ -76,40 +41,40 @@ impl Question {
             });
         }
 
-        return (questions, offset);
+        Ok((questions, offset))
     }
 
-    fn get_labels_from_btree(labels_tree: &BTreeMap<usize, String>, from_offset: usize) -> String {
-        let mut complete_label = String::new();
-        for (offset, label) in labels_tree {
-            if *offset < from_offset {
-                continue;
+    // The qname as stored in the message: it keeps a trailing pointer but not the null byte.
+    // The name must have been read already, so it is known to be within the message.
+    fn qname_bytes(message: &[u8], offset: usize) -> Vec<u8> {
+        let mut position = offset;
+        loop {
+            let length_byte = message[position] as usize;
+            if length_byte == 0 {
+                return message[offset..position].to_vec();
             }
-            if label.is_empty() {
-                break;
+            if length_byte & 0b11000000 == 0b11000000 {
+                return message[offset..(position + 2)].to_vec();
             }
-            complete_label.push_str(label);
-            complete_label.push_str(".");
+            position += length_byte + 1;
         }
-        complete_label
     }
 }
 
 impl Question {
+    #[cfg(test)]
+    pub fn new(label: &str, record_type: RecordType) -> Self {
+        Question {
+            qname: labels_bytes(label),
+            qtype: record_type.into(),
+            qclass: CLASS_IN,
+            label: label.to_string(),
+        }
+    }
+
     pub fn uncompressed_question(&self) -> Self {
         let label = self.label.clone();
-        let qname = label
-            .split(".")
-            .map(|label| {
-                let mut label_bytes = label.as_bytes().to_vec();
-                let length = label_bytes.len() as u8;
-                if length > 0 {
-                    label_bytes.insert(0, length);
-                }
-                label_bytes
-            })
-            .flatten()
-            .collect();
+        let qname = labels_bytes(&label);
 
         Question {
             qname,
@@ -120,13 +85,26 @@ impl Question {
     }
 }
 
-impl Into<Vec<u8>> for Question {
-    fn into(self) -> Vec<u8> {
+impl From<Question> for Vec<u8> {
+    fn from(question: Question) -> Self {
         let mut bytes = vec![];
-        bytes.extend(self.qname);
+        bytes.extend(question.qname);
         bytes.push(0);
-        bytes.extend(self.qtype.to_be_bytes());
-        bytes.extend(self.qclass.to_be_bytes());
+        bytes.extend(question.qtype.to_be_bytes());
+        bytes.extend(question.qclass.to_be_bytes());
         bytes
     }
 }
+
+// A commented out record without TTL nor rdata, as dig prints questions
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            presentation_name(&self.label),
+            class_name(self.qclass),
+            RecordType::from(self.qtype)
+        )
+    }
+}
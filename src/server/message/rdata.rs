@@ -0,0 +1,388 @@
+use super::bytes_at;
+use super::name::{canonical_name, check_name, escaped_byte, name_bytes, read_name};
+use super::record_type::RecordType;
+use anyhow::{bail, Context, Result};
+use std::fmt::{self, Write as _};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(String),
+    Cname(String),
+    Ptr(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Txt(Vec<Vec<u8>>),
+    Soa {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    // Reads the rdata at offset, resolving compressed names against the whole message
+    pub fn from_bytes(
+        message: &[u8],
+        record_type: RecordType,
+        offset: usize,
+        rdlength: usize,
+    ) -> Result<Self> {
+        let bytes = bytes_at(message, offset, rdlength)?;
+        let end_offset = offset + rdlength;
+        // Names are read from the whole message, but must start within the rdata
+        let name_at = |at: usize| -> Result<(String, usize)> {
+            if at >= end_offset {
+                bail!("rdata of {rdlength} bytes is too short");
+            }
+            let (name, _, end_offset) = read_name(message, at)?;
+            Ok((name, end_offset))
+        };
+        let u32_at = |at: usize| -> Result<u32> {
+            match bytes.get((at - offset)..(at - offset + 4)) {
+                Some(number) => Ok(u32::from_be_bytes(number.try_into().unwrap())),
+                None => bail!("rdata of {rdlength} bytes is too short"),
+            }
+        };
+
+        let rdata = match (record_type, rdlength) {
+            (RecordType::A, 4) => RData::A(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+            (RecordType::Aaaa, 16) => {
+                let octets: [u8; 16] = bytes.try_into().unwrap();
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            (RecordType::Ns, _) => RData::Ns(name_at(offset)?.0),
+            (RecordType::Cname, _) => RData::Cname(name_at(offset)?.0),
+            (RecordType::Ptr, _) => RData::Ptr(name_at(offset)?.0),
+            (RecordType::Mx, _) if rdlength >= 2 => RData::Mx {
+                preference: u16::from_be_bytes([bytes[0], bytes[1]]),
+                exchange: name_at(offset + 2)?.0,
+            },
+            (RecordType::Txt, _) => {
+                let mut strings = vec![];
+                let mut position = 0;
+                while position < bytes.len() {
+                    let length = bytes[position] as usize;
+                    let Some(string) = bytes.get((position + 1)..(position + 1 + length)) else {
+                        bail!("TXT string of {length} bytes runs past the rdata");
+                    };
+                    strings.push(string.to_vec());
+                    position += 1 + length;
+                }
+                RData::Txt(strings)
+            }
+            (RecordType::Soa, _) => {
+                let (mname, rname_offset) = name_at(offset)?;
+                let (rname, numbers_offset) = name_at(rname_offset)?;
+                RData::Soa {
+                    mname,
+                    rname,
+                    serial: u32_at(numbers_offset)?,
+                    refresh: u32_at(numbers_offset + 4)?,
+                    retry: u32_at(numbers_offset + 8)?,
+                    expire: u32_at(numbers_offset + 12)?,
+                    minimum: u32_at(numbers_offset + 16)?,
+                }
+            }
+            _ => RData::Unknown(bytes.to_vec()),
+        };
+        Ok(rdata)
+    }
+
+    // Parses the rdata fields of a record in presentation format, as found in zone files
+    pub fn parse(record_type: RecordType, fields: &[String], origin: &str) -> Result<Self> {
+        let field = |index: usize| -> Result<&str> {
+            fields
+                .get(index)
+                .map(String::as_str)
+                .with_context(|| format!("missing rdata field {}", index + 1))
+        };
+        let number = |index: usize| -> Result<u32> {
+            let value = field(index)?;
+            value
+                .parse::<u32>()
+                .with_context(|| format!("invalid number '{value}'"))
+        };
+
+        // Unknown types have no presentation format of their own
+        if let RecordType::Unknown(_) = record_type {
+            return generic_rdata(fields);
+        }
+
+        let rdata = match record_type {
+            RecordType::A => {
+                let value = field(0)?;
+                RData::A(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid IPv4 address '{value}'"))?,
+                )
+            }
+            RecordType::Aaaa => {
+                let value = field(0)?;
+                RData::Aaaa(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid IPv6 address '{value}'"))?,
+                )
+            }
+            RecordType::Ns => RData::Ns(parse_name(field(0)?, origin)?),
+            RecordType::Cname => RData::Cname(parse_name(field(0)?, origin)?),
+            RecordType::Ptr => RData::Ptr(parse_name(field(0)?, origin)?),
+            RecordType::Mx => {
+                let preference = number(0)?;
+                if preference > u16::MAX as u32 {
+                    bail!("invalid MX preference '{preference}'");
+                }
+                RData::Mx {
+                    preference: preference as u16,
+                    exchange: parse_name(field(1)?, origin)?,
+                }
+            }
+            RecordType::Txt => {
+                if fields.is_empty() {
+                    bail!("missing rdata field 1");
+                }
+                RData::Txt(
+                    fields
+                        .iter()
+                        .map(|field| character_string(field))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            RecordType::Soa => RData::Soa {
+                mname: parse_name(field(0)?, origin)?,
+                rname: parse_name(field(1)?, origin)?,
+                serial: number(2)?,
+                refresh: number(3)?,
+                retry: number(4)?,
+                expire: number(5)?,
+                minimum: number(6)?,
+            },
+            RecordType::Opt | RecordType::Any | RecordType::Axfr | RecordType::Unknown(_) => {
+                bail!("record type {record_type:?} can't be used in zone data")
+            }
+        };
+
+        let expected_fields = match rdata {
+            RData::Mx { .. } => 2,
+            RData::Soa { .. } => 7,
+            RData::Txt(_) => fields.len(),
+            _ => 1,
+        };
+        if fields.len() > expected_fields {
+            bail!("unexpected rdata field '{}'", fields[expected_fields]);
+        }
+        Ok(rdata)
+    }
+}
+
+impl From<&RData> for Vec<u8> {
+    fn from(rdata: &RData) -> Self {
+        match rdata {
+            RData::A(address) => address.octets().to_vec(),
+            RData::Aaaa(address) => address.octets().to_vec(),
+            RData::Ns(name) | RData::Cname(name) | RData::Ptr(name) => name_bytes(name),
+            RData::Mx {
+                preference,
+                exchange,
+            } => [preference.to_be_bytes().to_vec(), name_bytes(exchange)].concat(),
+            RData::Txt(strings) => strings
+                .iter()
+                // Strings are at most 255 bytes, checked when parsed or read
+                .flat_map(|string| [vec![string.len() as u8], string.clone()].concat())
+                .collect(),
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => [
+                name_bytes(mname),
+                name_bytes(rname),
+                [serial, refresh, retry, expire, minimum]
+                    .iter()
+                    .flat_map(|number| number.to_be_bytes())
+                    .collect(),
+            ]
+            .concat(),
+            RData::Unknown(bytes) => bytes.clone(),
+        }
+    }
+}
+
+// Parses "\# length hex" rdata (RFC 3597 section 5), where the hex can be split in fields
+fn generic_rdata(fields: &[String]) -> Result<RData> {
+    if fields.first().map(String::as_str) != Some("\\#") {
+        bail!("rdata of unknown types must start with \\#");
+    }
+    let length = fields.get(1).context("missing rdata length")?;
+    let length: usize = length
+        .parse()
+        .with_context(|| format!("invalid rdata length '{length}'"))?;
+    let bytes = hex_bytes(&fields[2..].concat())?;
+    if bytes.len() != length {
+        bail!("rdata has {} bytes instead of {length}", bytes.len());
+    }
+    Ok(RData::Unknown(bytes))
+}
+
+pub fn hex_bytes(hex: &str) -> Result<Vec<u8>> {
+    let digit = |char: &u8| (*char as char).to_digit(16);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((digit(high)? << 4 | digit(low)?) as u8),
+            _ => None,
+        })
+        .collect::<Option<_>>()
+        .with_context(|| format!("invalid rdata hex '{hex}'"))
+}
+
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+// Splits presentation format text into fields. Quoted strings are one field without their
+// quotes. Escapes are kept, to be read by the field they belong to: character strings keep
+// their bytes and names their labels.
+pub fn presentation_fields(text: &str) -> Result<Vec<String>> {
+    let mut fields = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            ' ' | '\t' | '\r' | '\n' => {}
+            '"' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        None => bail!("unterminated quoted string"),
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = chars.next().context("unterminated quoted string")?;
+                            field.push('\\');
+                            field.push(escaped);
+                        }
+                        Some(char) => field.push(char),
+                    }
+                }
+                fields.push(field);
+            }
+            _ => {
+                let mut field = String::new();
+                let mut next = Some(char);
+                while let Some(char) = next {
+                    field.push(char);
+                    if char == '\\' {
+                        field.extend(chars.next());
+                    }
+                    next = chars.next_if(|char| !char.is_whitespace() && *char != '"');
+                }
+                fields.push(field);
+            }
+        }
+    }
+    Ok(fields)
+}
+
+// The bytes of a character string field, at most 255 of them (RFC 1035 section 3.3)
+fn character_string(field: &str) -> Result<Vec<u8>> {
+    let mut string = vec![];
+    let mut bytes = field.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => string.push(escaped_byte(&mut bytes)?),
+            _ => string.push(byte),
+        }
+    }
+    if string.len() > 255 {
+        bail!(
+            "TXT string of {} bytes is longer than 255 bytes",
+            string.len()
+        );
+    }
+    Ok(string)
+}
+
+// The rdata in presentation format, as found in zone files. Unknown rdata uses the generic
+// \# format (RFC 3597).
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{address}"),
+            RData::Aaaa(address) => write!(f, "{address}"),
+            RData::Ns(name) | RData::Cname(name) | RData::Ptr(name) => write!(f, "{name}"),
+            RData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{preference} {exchange}"),
+            RData::Txt(strings) => {
+                let strings: Vec<String> = strings.iter().map(|string| quoted(string)).collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{mname} {rname} {serial} {refresh} {retry} {expire} {minimum}"
+            ),
+            RData::Unknown(bytes) if bytes.is_empty() => write!(f, "\\# 0"),
+            RData::Unknown(bytes) => write!(f, "\\# {} {}", bytes.len(), hex_string(bytes)),
+        }
+    }
+}
+
+// Quotes a character string, escaping quotes, backslashes and non printable bytes
+fn quoted(string: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            0x20..=0x7e => quoted.push(byte as char),
+            _ => {
+                let _ = write!(quoted, "\\{byte:03}");
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Names not ending with a dot are relative to the origin
+pub fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        return origin.to_string();
+    }
+    if name.ends_with('.') {
+        return name.to_string();
+    }
+    format!("{name}.{origin}")
+}
+
+// The absolute name, checked to fit in a message and with its escapes written the way names
+// read from messages are
+pub fn parse_name(name: &str, origin: &str) -> Result<String> {
+    let labels = check_name(&absolute_name(name, origin))?;
+    Ok(canonical_name(&labels))
+}
@@ -0,0 +1,133 @@
+use anyhow::{bail, Error};
+use std::fmt;
+use std::str::FromStr;
+
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_CH: u16 = 3;
+pub const CLASS_HS: u16 = 4;
+pub const CLASS_ANY: u16 = 255;
+
+// Parses a class mnemonic, or CLASSnnn for the others (RFC 3597)
+pub fn parse_class(string: &str) -> Result<u16, Error> {
+    let upper = string.to_uppercase();
+    let class = match upper.as_str() {
+        "IN" => CLASS_IN,
+        "CH" => CLASS_CH,
+        "HS" => CLASS_HS,
+        "ANY" => CLASS_ANY,
+        _ => match upper.strip_prefix("CLASS").map(str::parse::<u16>) {
+            Some(Ok(value)) => value,
+            _ => bail!("unsupported class '{string}'"),
+        },
+    };
+    Ok(class)
+}
+
+pub fn class_name(class: u16) -> String {
+    match class {
+        CLASS_IN => "IN".to_string(),
+        CLASS_CH => "CH".to_string(),
+        CLASS_HS => "HS".to_string(),
+        CLASS_ANY => "ANY".to_string(),
+        _ => format!("CLASS{class}"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    Ns,
+    Cname,
+    Soa,
+    Ptr,
+    Mx,
+    Txt,
+    Aaaa,
+    Opt,
+    Axfr,
+    Any,
+    Unknown(u16),
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => RecordType::A,
+            2 => RecordType::Ns,
+            5 => RecordType::Cname,
+            6 => RecordType::Soa,
+            12 => RecordType::Ptr,
+            15 => RecordType::Mx,
+            16 => RecordType::Txt,
+            28 => RecordType::Aaaa,
+            41 => RecordType::Opt,
+            252 => RecordType::Axfr,
+            255 => RecordType::Any,
+            _ => RecordType::Unknown(value),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(record_type: RecordType) -> Self {
+        match record_type {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Soa => 6,
+            RecordType::Ptr => 12,
+            RecordType::Mx => 15,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+            RecordType::Opt => 41,
+            RecordType::Axfr => 252,
+            RecordType::Any => 255,
+            RecordType::Unknown(value) => value,
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let upper = string.to_uppercase();
+        let record_type = match upper.as_str() {
+            "A" => RecordType::A,
+            "NS" => RecordType::Ns,
+            "CNAME" => RecordType::Cname,
+            "SOA" => RecordType::Soa,
+            "PTR" => RecordType::Ptr,
+            "MX" => RecordType::Mx,
+            "TXT" => RecordType::Txt,
+            "AAAA" => RecordType::Aaaa,
+            "AXFR" => RecordType::Axfr,
+            "ANY" => RecordType::Any,
+            _ => match upper.strip_prefix("TYPE").map(str::parse::<u16>) {
+                Some(Ok(value)) => RecordType::from(value),
+                _ => bail!("unsupported record type '{string}'"),
+            },
+        };
+        Ok(record_type)
+    }
+}
+
+// The mnemonic of the type, or TYPEnnn for unknown ones (RFC 3597)
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordType::A => write!(f, "A"),
+            RecordType::Ns => write!(f, "NS"),
+            RecordType::Cname => write!(f, "CNAME"),
+            RecordType::Soa => write!(f, "SOA"),
+            RecordType::Ptr => write!(f, "PTR"),
+            RecordType::Mx => write!(f, "MX"),
+            RecordType::Txt => write!(f, "TXT"),
+            RecordType::Aaaa => write!(f, "AAAA"),
+            RecordType::Opt => write!(f, "OPT"),
+            RecordType::Axfr => write!(f, "AXFR"),
+            RecordType::Any => write!(f, "ANY"),
+            RecordType::Unknown(value) => write!(f, "TYPE{value}"),
+        }
+    }
+}
@@ -0,0 +1,524 @@
+// Counters of the server, served in the Prometheus text format on /metrics
+use super::client::Transport;
+use super::message::rcode::Rcode;
+use super::message::record_type::RecordType;
+use super::tcp::ConnectionLimit;
+use super::{Context, POLL_INTERVAL};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The whole request must arrive within this time, however slowly it is sent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: u64 = 8192;
+// Scrapers are few, each connection is served on its own thread up to this many
+const MAX_CONNECTIONS: usize = 16;
+
+// Upper bounds of the upstream latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket, not cumulative
+    counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Types without a name share one series, so clients can't create one per type number
+type QueryKey = (Option<RecordType>, Rcode, Transport);
+
+#[derive(Default)]
+pub struct Metrics {
+    queries: Mutex<HashMap<QueryKey, u64>>,
+    upstream_latencies: Mutex<HashMap<SocketAddr, Histogram>>,
+    upstream_errors: Mutex<HashMap<SocketAddr, u64>>,
+    dropped_responses: Mutex<HashMap<&'static str, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_query(&self, qtype: RecordType, rcode: Rcode, transport: Transport) {
+        let qtype = match qtype {
+            RecordType::Unknown(_) => None,
+            qtype => Some(qtype),
+        };
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((qtype, rcode, transport))
+            .or_default() += 1;
+    }
+
+    pub fn record_upstream(&self, upstream: SocketAddr, latency: Duration) {
+        self.upstream_latencies
+            .lock()
+            .unwrap()
+            .entry(upstream)
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn record_upstream_error(&self, upstream: SocketAddr) {
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry(upstream)
+            .or_default() += 1;
+    }
+
+    pub fn record_dropped(&self, reason: &'static str) {
+        *self
+            .dropped_responses
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    pub fn record_cache(&self, is_hit: bool) {
+        let counter = if is_hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The metrics of the server, with series sorted so the output is stable
+pub fn render(context: &Context) -> String {
+    let metrics = &context.metrics;
+    let mut output = String::new();
+
+    header(
+        &mut output,
+        "dns_queries_total",
+        "counter",
+        "Answered queries",
+    );
+    let queries = metrics.queries.lock().unwrap();
+    let mut series: Vec<String> = queries
+        .iter()
+        .map(|((qtype, rcode, transport), count)| {
+            let qtype = qtype.map_or("OTHER".to_string(), |qtype| qtype.to_string());
+            format!(
+                "dns_queries_total{{type=\"{qtype}\",rcode=\"{rcode}\",transport=\"{transport}\"}} {count}\n"
+            )
+        })
+        .collect();
+    drop(queries);
+    series.sort();
+    output.extend(series);
+
+    header(
+        &mut output,
+        "dns_upstream_latency_seconds",
+        "histogram",
+        "Latency of the upstream resolvers",
+    );
+    let latencies = metrics.upstream_latencies.lock().unwrap();
+    let mut upstreams: Vec<&SocketAddr> = latencies.keys().collect();
+    upstreams.sort();
+    for upstream in upstreams {
+        let histogram = &latencies[upstream];
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                output,
+                "dns_upstream_latency_seconds_bucket{{upstream=\"{upstream}\",le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            output,
+            "dns_upstream_latency_seconds_bucket{{upstream=\"{upstream}\",le=\"+Inf\"}} {}\n\
+             dns_upstream_latency_seconds_sum{{upstream=\"{upstream}\"}} {}\n\
+             dns_upstream_latency_seconds_count{{upstream=\"{upstream}\"}} {}",
+            histogram.count, histogram.sum, histogram.count
+        );
+    }
+    drop(latencies);
+
+    header(
+        &mut output,
+        "dns_upstream_errors_total",
+        "counter",
+        "Failed upstream queries",
+    );
+    labeled_counters(
+        &mut output,
+        "dns_upstream_errors_total",
+        "upstream",
+        &metrics.upstream_errors,
+    );
+    header(
+        &mut output,
+        "dns_dropped_responses_total",
+        "counter",
+        "Responses not sent, by reason",
+    );
+    labeled_counters(
+        &mut output,
+        "dns_dropped_responses_total",
+        "reason",
+        &metrics.dropped_responses,
+    );
+
+    let rrl = context.rrl.stats();
+    let limits = context.limits.stats();
+    let counters = [
+        (
+            "dns_cache_hits_total",
+            "Questions answered from the cache",
+            metrics.cache_hits.load(Ordering::Relaxed),
+        ),
+        (
+            "dns_cache_misses_total",
+            "Questions not found in the cache",
+            metrics.cache_misses.load(Ordering::Relaxed),
+        ),
+        (
+            "dns_parse_errors_total",
+            "Requests that could not be parsed",
+            metrics.parse_errors.load(Ordering::Relaxed),
+        ),
+        (
+            "dns_rrl_slipped_total",
+            "Rate limited responses sent truncated",
+            rrl.slipped,
+        ),
+        (
+            "dns_limited_queries_total",
+            "Queries refused over the client query budget",
+            limits.refused_queries,
+        ),
+        (
+            "dns_limited_resolutions_total",
+            "Resolutions refused over the client concurrency cap",
+            limits.refused_resolutions,
+        ),
+    ];
+    for (name, help, value) in counters {
+        header(&mut output, name, "counter", help);
+        let _ = writeln!(output, "{name} {value}");
+    }
+
+    let gauges = [
+        (
+            "dns_in_flight_requests",
+            "Requests being answered",
+            context.shutdown.in_flight(),
+        ),
+        (
+            "dns_cache_entries",
+            "Entries in the cache",
+            context.cache.len(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut output, name, "gauge", help);
+        let _ = writeln!(output, "{name} {value}");
+    }
+    output
+}
+
+fn header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} {metric_type}");
+}
+
+fn labeled_counters<K: ToString>(
+    output: &mut String,
+    name: &str,
+    label: &str,
+    counters: &Mutex<HashMap<K, u64>>,
+) {
+    let mut series: Vec<String> = counters
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, count)| format!("{name}{{{label}=\"{}\"}} {count}\n", key.to_string()))
+        .collect();
+    series.sort();
+    output.extend(series);
+}
+
+// Serves GET /metrics over HTTP/1.1, one request per connection
+pub fn serve(tcp_listener: TcpListener, context: Arc<Context>) -> Result<()> {
+    // Poll for connections to notice a shutdown
+    tcp_listener.set_nonblocking(true)?;
+    let connection_limit = ConnectionLimit::new(MAX_CONNECTIONS);
+
+    while !context.shutdown.is_requested() {
+        let (stream, source) = match tcp_listener.accept() {
+            Ok(accepted) => accepted,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(error) => {
+                log_error!("Failed to accept metrics connection: {error}");
+                continue;
+            }
+        };
+        let Some(connection) = connection_limit.open() else {
+            log_debug!("Too many metrics connections, closing the one from {source}");
+            continue;
+        };
+        let context = context.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(error) = serve_connection(stream, &context) {
+                log_warn!("Metrics connection error: {error:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, context: &Context) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut reader = BufReader::new(&stream).take(MAX_REQUEST_SIZE);
+    let request_line = read_line(&mut reader, &stream, deadline)?;
+    // Skip the headers, there is no body to read
+    while !read_line(&mut reader, &stream, deadline)?.trim().is_empty() {}
+
+    let mut fields = request_line.split_whitespace();
+    let (status, body) = match (fields.next(), fields.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(context)),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    (&stream).write_all(response.as_bytes())?;
+    Ok(())
+}
+
+// Reads a line before the deadline. Bytes are taken one read at a time, so a client sending
+// them slowly can't keep a read waiting past it.
+fn read_line(reader: &mut impl BufRead, stream: &TcpStream, deadline: Instant) -> Result<String> {
+    let mut line = vec![];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!("request not received within {REQUEST_TIMEOUT:?}");
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            bail!("incomplete or too large request");
+        }
+        match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => {
+                line.extend_from_slice(&available[..=index]);
+                reader.consume(index + 1);
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+            None => {
+                let size = available.len();
+                line.extend_from_slice(available);
+                reader.consume(size);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::dispatcher::Dispatcher;
+
+    #[test]
+    fn test_when_metrics_are_recorded_then_they_are_rendered() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let upstream: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        // When
+        context
+            .metrics
+            .record_query(RecordType::Aaaa, Rcode::NxDomain, Transport::Udp);
+        context
+            .metrics
+            .record_upstream(upstream, Duration::from_millis(20));
+        context.metrics.record_cache(true);
+        context.metrics.record_dropped("rrl");
+        let output = render(&context);
+        // Then
+        assert!(output
+            .contains("dns_queries_total{type=\"AAAA\",rcode=\"NXDOMAIN\",transport=\"udp\"} 1\n"));
+        assert!(output.contains(
+            "dns_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:53\",le=\"0.01\"} 0\n"
+        ));
+        assert!(output.contains(
+            "dns_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:53\",le=\"0.025\"} 1\n"
+        ));
+        assert!(
+            output.contains("dns_upstream_latency_seconds_count{upstream=\"192.0.2.53:53\"} 1\n")
+        );
+        assert!(output.contains("dns_cache_hits_total 1\n"));
+        assert!(output.contains("dns_dropped_responses_total{reason=\"rrl\"} 1\n"));
+        assert!(output.contains("# TYPE dns_in_flight_requests gauge\n"));
+    }
+
+    #[test]
+    fn test_when_latency_is_over_every_bound_then_only_inf_bucket_counts_it() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let upstream: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        // When
+        context
+            .metrics
+            .record_upstream(upstream, Duration::from_millis(1));
+        context
+            .metrics
+            .record_upstream(upstream, Duration::from_secs(5));
+        context.metrics.record_upstream_error(upstream);
+        context.metrics.record_parse_error();
+        context.metrics.record_cache(false);
+        let output = render(&context);
+        // Then
+        assert!(output.contains(
+            "dns_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:53\",le=\"0.001\"} 1\n"
+        ));
+        assert!(output.contains(
+            "dns_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:53\",le=\"2.5\"} 1\n"
+        ));
+        assert!(output.contains(
+            "dns_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:53\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(
+            output.contains("dns_upstream_latency_seconds_sum{upstream=\"192.0.2.53:53\"} 5.001\n")
+        );
+        assert!(output.contains("dns_upstream_errors_total{upstream=\"192.0.2.53:53\"} 1\n"));
+        assert!(output.contains("dns_parse_errors_total 1\n"));
+        assert!(output.contains("dns_cache_misses_total 1\n"));
+        assert!(output.contains("dns_cache_hits_total 0\n"));
+    }
+
+    #[test]
+    fn test_when_series_are_recorded_in_any_order_then_they_are_rendered_sorted() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        // When
+        for reason in ["rrl", "overload", "acl"] {
+            context.metrics.record_dropped(reason);
+        }
+        let output = render(&context);
+        // Then
+        let acl = output.find("{reason=\"acl\"}").unwrap();
+        let overload = output.find("{reason=\"overload\"}").unwrap();
+        let rrl = output.find("{reason=\"rrl\"}").unwrap();
+        assert!(acl < overload && overload < rrl);
+    }
+
+    #[test]
+    fn test_when_query_types_have_no_name_then_they_share_one_series() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        // When
+        for qtype in [
+            RecordType::Unknown(65280),
+            RecordType::Unknown(65281),
+            RecordType::A,
+        ] {
+            context
+                .metrics
+                .record_query(qtype, Rcode::NoError, Transport::Udp);
+        }
+        let output = render(&context);
+        // Then
+        assert!(output
+            .contains("dns_queries_total{type=\"OTHER\",rcode=\"NOERROR\",transport=\"udp\"} 2\n"));
+        assert!(output
+            .contains("dns_queries_total{type=\"A\",rcode=\"NOERROR\",transport=\"udp\"} 1\n"));
+        assert!(!output.contains("TYPE65280"));
+    }
+
+    #[test]
+    fn test_when_a_client_is_slow_or_sends_too_much_then_other_scrapes_are_answered() {
+        // Given a client sending part of a request, and one sending a request line too long
+        let context = Arc::new(Context::new(vec![], Dispatcher::default()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_context = context.clone();
+        thread::spawn(move || serve(listener, server_context));
+        let mut slow_client = TcpStream::connect(address).unwrap();
+        slow_client.write_all(b"GET /met").unwrap();
+        let mut large_client = TcpStream::connect(address).unwrap();
+        let _ = large_client.write_all(&vec![b'G'; MAX_REQUEST_SIZE as usize + 1]);
+        // When
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        // Then
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let mut large_response = vec![];
+        large_client
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .unwrap();
+        let _ = large_client.read_to_end(&mut large_response);
+        assert!(large_response.is_empty());
+        context.shutdown.request();
+    }
+
+    #[test]
+    fn test_when_http_request_is_not_get_metrics_then_status_tells_why() {
+        // Given
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = |request: &str| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &context).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        // When
+        let metrics = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let not_found = request("GET / HTTP/1.1\r\n\r\n");
+        let not_allowed = request("POST /metrics HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        // Then
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(metrics.contains("# TYPE dns_queries_total counter\n"));
+        assert!(not_found.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(not_found.ends_with("\r\n\r\nnot found\n"));
+        assert!(not_allowed.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
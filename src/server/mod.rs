@@ -1,31 +1,837 @@
-use self::resolver::{resolve_questions, Resolver};
-use crate::server::message::Message;
-use anyhow::Result;
-use std::net::UdpSocket;
+use self::cache::Cache;
+use self::client::{Client, Transport};
+use self::dispatcher::{Dispatcher, DropResponse};
+use self::dnstap::{Dnstap, Event, EventType};
+use self::hosts::StaticRecords;
+use self::limits::ClientLimits;
+use self::message::header::Header;
+use self::message::rcode::Rcode;
+use self::message::record_type::RecordType;
+use self::metrics::Metrics;
+use self::policy::Policies;
+use self::query_log::{QueryEntry, QueryLog};
+use self::resolver::Resolver;
+use self::rpz::ResponsePolicyZones;
+use self::rrl::{RateLimiter, Verdict};
+use self::shutdown::Shutdown;
+use self::zone::Zones;
+use crate::cli_params::CliParam;
+use crate::config::{Config, Upstream};
+use crate::log;
+use crate::server::message::{Message, TCP_MESSAGE_SIZE};
+use anyhow::{anyhow, Context as _, Result};
+use std::io::{self, ErrorKind, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-mod message;
+pub mod acl;
+pub mod blocklist;
+mod cache;
+pub mod client;
+pub mod control;
+pub mod dispatcher;
+pub mod dnstap;
+mod doh;
+pub mod hosts;
+pub mod limits;
+pub mod message;
+pub mod metrics;
+pub mod policy;
+pub mod query_log;
 pub mod resolver;
+pub mod rpz;
+pub mod rrl;
+mod shutdown;
+mod signals;
+mod tcp;
+mod udp;
+pub mod zone;
 
-pub fn start_server(resolver: Resolver) -> Result<()> {
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053")?;
-    let mut buffer = [0; 512];
+// How often blocked threads wake up to check for a shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    loop {
-        // Read request message
-        let (size, source) = udp_socket.recv_from(&mut buffer)?;
-        let bytes = &buffer[..size];
+// Everything built from the configuration, replaced as a whole on reload
+pub struct State {
+    pub config: Config,
+    pub resolver: Resolver,
+    pub static_records: StaticRecords,
+    pub zones: Zones,
+    pub policies: Policies,
+    pub rpz: ResponsePolicyZones,
+}
+
+impl State {
+    pub fn from_config(config: Config) -> Result<Self> {
+        Ok(State {
+            resolver: Resolver::from(&config),
+            static_records: StaticRecords::load(&config.static_records)?,
+            zones: Zones::load(&config.zones)?,
+            policies: Policies::load(&config)?,
+            rpz: ResponsePolicyZones::load(&config.rpz)?,
+            config,
+        })
+    }
+}
+
+pub struct Context {
+    state: RwLock<Arc<State>>,
+    pub cache: Cache,
+    pub rrl: RateLimiter,
+    pub limits: ClientLimits,
+    pub metrics: Metrics,
+    pub query_log: QueryLog,
+    // Only set up on start, a reload doesn't change it
+    pub dnstap: Option<Dnstap>,
+    pub dispatcher: Dispatcher,
+    pub shutdown: Arc<Shutdown>,
+    params: Vec<CliParam>,
+    // SIGHUP and the control socket can both ask for a reload, they run one at a time
+    reload_lock: Mutex<()>,
+}
+
+impl Context {
+    pub fn new(params: Vec<CliParam>, dispatcher: Dispatcher) -> Result<Self> {
+        let config = Config::from_params(&params)?;
+        log::set_level(config.log.level);
+        let cache = Cache::new(config.cache.size);
+        if let Some(path) = config.cache.file.as_ref().filter(|path| path.exists()) {
+            match cache.load(path) {
+                Ok(count) => log_info!("Restored {count} cache entries from {}", path.display()),
+                Err(error) => log_warn!("Starting with an empty cache: {error:#}"),
+            }
+        }
+        let dnstap = config.dnstap.as_ref().map(Dnstap::new).transpose()?;
+        let state = State::from_config(config)?;
+
+        Ok(Context {
+            state: RwLock::new(Arc::new(state)),
+            cache,
+            rrl: RateLimiter::default(),
+            limits: ClientLimits::default(),
+            metrics: Metrics::default(),
+            query_log: QueryLog::default(),
+            dnstap,
+            dispatcher,
+            shutdown: Arc::default(),
+            params,
+            reload_lock: Mutex::new(()),
+        })
+    }
+
+    // The active state, which stays the same for a request even if a reload happens
+    pub fn state(&self) -> Arc<State> {
+        self.state.read().unwrap().clone()
+    }
+
+    // Loads the configuration and zones again, keeping the active state if they fail. Nothing
+    // is applied until the new state is fully built.
+    pub fn reload(&self) -> Result<()> {
+        let _reloading = self.reload_lock.lock().unwrap();
+        let config = Config::from_params(&self.params)?;
+        let active_config = &self.state().config;
+        if config.listen != active_config.listen {
+            log_warn!("Listen addresses changed, a restart is needed to use them");
+        }
+        // Cached answers came from the previous upstreams
+        let upstreams_changed = config.upstreams != active_config.upstreams
+            || config.forwards != active_config.forwards;
+        let (level, cache_size) = (config.log.level, config.cache.size);
+        let state = State::from_config(config)?;
+
+        log::set_level(level);
+        self.cache.set_capacity(cache_size);
+        *self.state.write().unwrap() = Arc::new(state);
+        if upstreams_changed {
+            self.cache.clear();
+        }
+        log_info!("Configuration reloaded");
+        Ok(())
+    }
+
+    // Returns no message when the response is dropped, and several when a response over TCP
+    // doesn't fit in one, like a zone transfer
+    pub fn handle_request(&self, bytes: &[u8], client: Client) -> Result<Vec<Vec<u8>>> {
+        let source = client.address;
+        let received_at = (SystemTime::now(), Instant::now());
+        query_log::take_trace();
+        self.tap(EventType::ClientQuery, &client, received_at.0, bytes);
+        let request_message = match Message::try_from(bytes) {
+            Ok(request_message) => request_message,
+            Err(error) => {
+                log_debug!("Malformed request message from {source}: {error:#}");
+                self.metrics.record_parse_error();
+                return Ok(self
+                    .format_error(bytes, &client, received_at.0)
+                    .into_iter()
+                    .collect());
+            }
+        };
+        log_debug!("Request message from {source}: {:?}", request_message);
+
+        // Prepare response message with the handler for the request opcode
+        let config = &self.state().config;
+        let mut response_message = if !config.acl.allows_query(&source) {
+            request_message.response_message(vec![], Rcode::Refused)
+        } else if !self.limits.allows_query(&config.limits, &source) {
+            log_debug!("Query budget of {source} exceeded");
+            request_message.response_message(vec![], Rcode::Refused)
+        } else {
+            match self.dispatcher.dispatch(&request_message, &client, self) {
+                Ok(response_message) => response_message,
+                Err(error) if error.is::<DropResponse>() => {
+                    log_debug!("Dropped response to {source}");
+                    self.metrics.record_dropped("rpz");
+                    return Ok(vec![]);
+                }
+                Err(error) => {
+                    log_warn!("Failed to answer {source}: {error:#}");
+                    request_message.response_message(vec![], Rcode::ServFail)
+                }
+            }
+        };
+
+        // Only UDP responses can be reflected to a spoofed source
+        if client.transport == Transport::Udp {
+            match self.rrl.check(&config.rrl, &source, &response_message) {
+                Verdict::Send => {}
+                Verdict::Slip => {
+                    log_debug!("Rate limited {source}, sending a truncated response");
+                    response_message.answers.clear();
+                    response_message.authorities.clear();
+                    response_message.header.tc = 1;
+                }
+                Verdict::Drop => {
+                    log_debug!("Rate limited {source}, dropping the response");
+                    self.metrics.record_dropped("rrl");
+                    return Ok(vec![]);
+                }
+            }
+            response_message.truncate(request_message.udp_response_size());
+        } else if client.transport == Transport::Doh {
+            response_message.truncate(TCP_MESSAGE_SIZE);
+        }
+        log_debug!("Response message to {source}: {:?}", response_message);
+        if let Some(question) = response_message.questions.first() {
+            let qtype = RecordType::from(question.qtype);
+            let rcode = Rcode::from(response_message.header.rcode);
+            self.metrics.record_query(qtype, rcode, client.transport);
+            if let Some(query_log_config) = &config.query_log {
+                let entry = QueryEntry {
+                    time: received_at.0,
+                    client,
+                    qname: question.label.clone(),
+                    qtype,
+                    rcode,
+                    answers: response_message.answers.len(),
+                    trace: query_log::take_trace(),
+                    latency: received_at.1.elapsed(),
+                };
+                self.query_log.log(query_log_config, &entry);
+            }
+        }
 
-        let request_message = Message::from(bytes);
-        // println!("Request message: {:?}", request_message);
+        let mut responses_bytes = vec![];
+        for response_message in response_message.split(TCP_MESSAGE_SIZE) {
+            let response_bytes = Vec::<u8>::try_from(response_message)?;
+            self.tap(
+                EventType::ClientResponse,
+                &client,
+                received_at.0,
+                &response_bytes,
+            );
+            responses_bytes.push(response_bytes);
+        }
+        Ok(responses_bytes)
+    }
+
+    fn tap(&self, event_type: EventType, client: &Client, query_time: SystemTime, message: &[u8]) {
+        if let Some(dnstap) = &self.dnstap {
+            let response_time = match event_type {
+                EventType::ClientResponse => Some(SystemTime::now()),
+                _ => None,
+            };
+            dnstap.send(&Event {
+                event_type,
+                transport: client.transport,
+                client: Some(client.address),
+                upstream: None,
+                query_time,
+                response_time,
+                message,
+            });
+        }
+    }
+
+    // Messages that can't be parsed get FORMERR when their header can be read, echoing its ID
+    // and opcode (RFC 1035 section 4.1.1), and are dropped otherwise
+    fn format_error(
+        &self,
+        bytes: &[u8],
+        client: &Client,
+        query_time: SystemTime,
+    ) -> Option<Vec<u8>> {
+        let request_header = Header::try_from(bytes).ok()?;
+        if request_header.qr == 1 {
+            return None;
+        }
+        let response_message = Message {
+            header: Header {
+                id: request_header.id,
+                qr: 1,
+                opcode: request_header.opcode,
+                rd: request_header.rd,
+                rcode: Rcode::FormErr.into(),
+                ..Header::default()
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let response_bytes = Vec::<u8>::try_from(response_message).ok()?;
+        self.tap(
+            EventType::ClientResponse,
+            client,
+            query_time,
+            &response_bytes,
+        );
+        Some(response_bytes)
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Serves until SIGINT, SIGTERM or the shutdown control command, then waits for the
+// requests in flight before returning
+pub fn start_server(context: Arc<Context>) -> Result<()> {
+    let config = context.state().config.clone();
+    let mut handles = vec![];
+
+    // This isn't DNS over HTTPS, queries to http:// upstreams can be read on the way
+    let forwarded_upstreams = config
+        .forwards
+        .iter()
+        .flat_map(|forward| &forward.upstreams);
+    for upstream in config.upstreams.iter().chain(forwarded_upstreams) {
+        if matches!(upstream, Upstream::Http { address, .. } if !address.ip().is_loopback()) {
+            log_warn!(
+                "Queries to the upstream {upstream} are not encrypted, \
+                forward through a local DNS over HTTPS proxy instead"
+            );
+        }
+    }
+
+    // Bind every socket before serving, so a bad address fails the start without leaving
+    // the listeners of the others running
+    let mut listeners = vec![];
+    for address in &config.listen {
+        let udp_socket = UdpSocket::bind(address)
+            .with_context(|| format!("failed to bind UDP socket on {address}"))?;
+        let tcp_listener = TcpListener::bind(address)
+            .with_context(|| format!("failed to bind TCP listener on {address}"))?;
+        listeners.push((*address, udp_socket, tcp_listener));
+    }
+    let control_listener = config
+        .control
+        .map(|address| {
+            TcpListener::bind(address)
+                .with_context(|| format!("failed to bind control socket on {address}"))
+        })
+        .transpose()?;
+    let metrics_listener = config
+        .metrics
+        .map(|address| {
+            TcpListener::bind(address)
+                .with_context(|| format!("failed to bind metrics listener on {address}"))
+        })
+        .transpose()?;
+    let doh_listener = config
+        .doh
+        .as_ref()
+        .map(|doh| {
+            TcpListener::bind(doh.listen)
+                .with_context(|| format!("failed to bind DNS over HTTP listener on {}", doh.listen))
+        })
+        .transpose()?;
+
+    for (address, udp_socket, tcp_listener) in listeners {
+        log_info!("Listening on {address} over UDP and TCP");
+        let udp_context = context.clone();
+        handles.push(thread::spawn(move || udp::serve(udp_socket, udp_context)));
+        let tcp_context = context.clone();
+        handles.push(thread::spawn(move || tcp::serve(tcp_listener, tcp_context)));
+    }
+
+    if let (Some(address), Some(control_listener)) = (config.control, control_listener) {
+        log_info!("Control socket listening on {address}");
+        let control_context = context.clone();
+        handles.push(thread::spawn(move || {
+            control::serve(control_listener, control_context)
+        }));
+    }
 
-        // Get answers from resolver
-        let answers = resolve_questions(request_message.questions.as_slice(), &resolver)?;
-        // Prepare response message
-        let response_message = request_message.response_message(answers);
-        // println!("Response message: {:?}", response_message);
+    if let (Some(address), Some(metrics_listener)) = (config.metrics, metrics_listener) {
+        log_info!("Serving metrics on http://{address}/metrics");
+        let metrics_context = context.clone();
+        handles.push(thread::spawn(move || {
+            metrics::serve(metrics_listener, metrics_context)
+        }));
+    }
+
+    if let (Some(doh), Some(doh_listener)) = (&config.doh, doh_listener) {
+        log_info!("Serving DNS over HTTP on http://{}{}", doh.listen, doh.path);
+        if !doh.listen.ip().is_loopback() {
+            log_warn!(
+                "DNS over HTTP on {} is not encrypted, serve HTTPS through a TLS proxy",
+                doh.listen
+            );
+        }
+        let doh_context = context.clone();
+        let path = doh.path.clone();
+        handles.push(thread::spawn(move || {
+            doh::serve(doh_listener, path, doh_context)
+        }));
+    }
+
+    // SIGHUP reloads the configuration, SIGINT and SIGTERM stop the server
+    signals::install();
+    while !context.shutdown.is_requested() {
+        thread::sleep(POLL_INTERVAL);
+        if signals::take_reload_request() {
+            if let Err(error) = context.reload() {
+                log_error!("Failed to reload, keeping the active configuration: {error:#}");
+            }
+        }
+        // A listener only returns early on failure, which stops the server too
+        if signals::is_shutdown_requested() || handles.iter().any(|handle| handle.is_finished()) {
+            context.shutdown.request();
+        }
+    }
+    log_info!("Shutting down, no new requests are accepted");
+
+    let mut result = Ok(());
+    for handle in handles {
+        let handle_result = handle
+            .join()
+            .map_err(|_| anyhow!("listener thread panicked"))
+            .and_then(|handle_result| handle_result);
+        if result.is_ok() {
+            result = handle_result;
+        }
+    }
+
+    let state = context.state();
+    let in_flight = context
+        .shutdown
+        .wait_in_flight(state.config.shutdown.timeout);
+    if in_flight > 0 {
+        log_warn!("Stopped with {in_flight} requests still in flight");
+    }
+    if let Some(path) = &state.config.cache.file {
+        match context.cache.save(path) {
+            Ok(count) => log_info!("Saved {count} cache entries to {}", path.display()),
+            Err(error) => log_error!("Failed to save the cache: {error:#}"),
+        }
+    }
+    if let Some(dnstap) = &context.dnstap {
+        dnstap.close();
+    }
+    log_info!("Server stopped");
+    io::stderr().flush()?;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::message::answer::Answer;
+    use crate::server::message::question::Question;
+    use crate::server::message::rdata::RData;
+    use crate::server::message::record_type::{RecordType, CLASS_IN};
+    use crate::server::message::{EDNS_BUFFER_SIZE, EDNS_DO_FLAG};
+    use crate::server::resolver::{exchange_tcp, exchange_udp};
+    use crate::test_utils::TempPath;
+    use std::fs;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    // An upstream answering every question with addresses after a delay
+    fn upstream(delay: Duration, address_count: u8) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buffer = [0; 512];
+            let (size, source) = socket.recv_from(&mut buffer).unwrap();
+            let request = Message::try_from(&buffer[..size]).unwrap();
+            let answers = request
+                .questions
+                .iter()
+                .flat_map(|question| {
+                    (1..=address_count).map(|index| {
+                        let rdata = RData::A(Ipv4Addr::new(10, 0, 0, index));
+                        Answer::new(&question.label, RecordType::A, CLASS_IN, 60, &rdata)
+                    })
+                })
+                .collect();
+            let response = request.response_message(answers, Rcode::NoError);
+            thread::sleep(delay);
+            socket
+                .send_to(&Vec::<u8>::try_from(response).unwrap(), source)
+                .unwrap();
+        });
+        address
+    }
+
+    fn send_query(server: SocketAddr, id: u16) -> UdpSocket {
+        let question = Question::new(&format!("host{id}.example.com."), RecordType::A);
+        let request = Message::query(id, vec![question]);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket
+            .send_to(&Vec::<u8>::try_from(request).unwrap(), server)
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_when_server_listens_on_several_addresses_then_each_answers_over_udp_and_tcp() {
+        // Given
+        let upstream = upstream(Duration::ZERO, 1);
+        let free_address = || {
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let listens = [free_address(), free_address()];
+        let mut params: Vec<CliParam> = listens
+            .iter()
+            .map(|&listen| CliParam::Listen(listen))
+            .collect();
+        params.push(CliParam::Resolver(upstream));
+        let context = Arc::new(Context::new(params, Dispatcher::default()).unwrap());
+        let server_context = context.clone();
+        let server = thread::spawn(move || start_server(server_context));
+        thread::sleep(Duration::from_millis(200));
+        let question = Question::new("www.example.com.", RecordType::A);
+        let request = Message::query(5, vec![question]);
+        let request_bytes = Vec::<u8>::try_from(request).unwrap();
+        for listen in listens {
+            // When
+            let responses = [
+                exchange_udp(&request_bytes, &listen).unwrap(),
+                exchange_tcp(&request_bytes, &listen).unwrap(),
+            ];
+            // Then
+            for response_bytes in responses {
+                let response = Message::try_from(response_bytes.as_slice()).unwrap();
+                assert_eq!(response.header.id, 5);
+                assert_eq!(response.answers.len(), 1);
+            }
+        }
+        context.shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_when_listen_address_is_in_use_then_server_fails_to_start() {
+        // Given a free address, then one in use
+        let free_address = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let busy_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let busy_address = busy_socket.local_addr().unwrap();
+        let params = vec![
+            CliParam::Listen(free_address),
+            CliParam::Listen(busy_address),
+        ];
+        let context = Arc::new(Context::new(params, Dispatcher::default()).unwrap());
+        // When
+        let error = start_server(context).unwrap_err();
+        // Then
+        assert_eq!(
+            error.to_string(),
+            format!("failed to bind UDP socket on {busy_address}")
+        );
+        // And nothing is left listening on the free address
+        UdpSocket::bind(free_address).unwrap();
+        TcpListener::bind(free_address).unwrap();
+    }
+
+    #[test]
+    fn test_when_shutdown_is_requested_then_queries_in_flight_are_answered() {
+        // Given
+        let upstream = upstream(Duration::from_millis(300), 1);
+        let listen = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let params = vec![CliParam::Listen(listen), CliParam::Resolver(upstream)];
+        let context = Arc::new(Context::new(params, Dispatcher::default()).unwrap());
+        let server_context = context.clone();
+        let server = thread::spawn(move || start_server(server_context));
+        thread::sleep(Duration::from_millis(200));
+        let clients: Vec<UdpSocket> = (1..=3).map(|id| send_query(listen, id)).collect();
+        thread::sleep(Duration::from_millis(100));
+        // When
+        context.shutdown.request();
+        // Then
+        for (id, client) in (1..=3).zip(clients) {
+            let mut buffer = [0; 512];
+            let size = client.recv(&mut buffer).unwrap();
+            let response = Message::try_from(&buffer[..size]).unwrap();
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.answers.len(), 1);
+        }
+        server.join().unwrap().unwrap();
+        let late_client = send_query(listen, 4);
+        late_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        assert!(late_client.recv(&mut [0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_when_udp_request_is_larger_than_512_bytes_then_it_is_answered() {
+        // Given a request with 1000 bytes of EDNS padding (RFC 7830)
+        let upstream = upstream(Duration::ZERO, 1);
+        let listen = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let params = vec![CliParam::Listen(listen), CliParam::Resolver(upstream)];
+        let context = Arc::new(Context::new(params, Dispatcher::default()).unwrap());
+        let server_context = context.clone();
+        let server = thread::spawn(move || start_server(server_context));
+        thread::sleep(Duration::from_millis(200));
+        let padding = RData::Unknown([&[0, 12, 3, 232][..], &[0; 1000]].concat());
+        let question = Question::new("padded.example.com.", RecordType::A);
+        let mut request = Message::query(9, vec![question]);
+        request.additionals = vec![Answer::new(".", RecordType::Opt, 4096, 0, &padding)];
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        // When
+        client
+            .send_to(&Vec::<u8>::try_from(request).unwrap(), listen)
+            .unwrap();
+        // Then
+        let mut buffer = [0; 1232];
+        let size = client.recv(&mut buffer).unwrap();
+        let response = Message::try_from(&buffer[..size]).unwrap();
+        assert_eq!(response.header.id, 9);
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        context.shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_when_request_is_malformed_then_formerr_is_answered_without_panicking() {
+        // Given a header announcing a question that isn't there
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let client = Client {
+            address: "127.0.0.1".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        let bytes = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'c', b'o',
+        ];
+        // When
+        let responses_bytes = context.handle_request(&bytes, client).unwrap();
+        // Then
+        let response = Message::try_from(responses_bytes[0].as_slice()).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(Rcode::from(response.header.rcode), Rcode::FormErr);
+        assert!(response.questions.is_empty());
+        assert!(context
+            .handle_request(&bytes[..11], client)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_when_answer_is_larger_than_the_client_buffer_then_udp_response_is_truncated() {
+        // Given an answer of 30 addresses, about 1 KB
+        let params = vec![CliParam::Resolver(upstream(Duration::ZERO, 30))];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let request = |transport, edns: Option<u32>| {
+            let question = Question::new("big.example.com.", RecordType::A);
+            let mut request = Message::query(7, vec![question]);
+            request.additionals = edns
+                .map(|flags| {
+                    let rdata = RData::Unknown(vec![]);
+                    Answer::new(".", RecordType::Opt, 4096, flags, &rdata)
+                })
+                .into_iter()
+                .collect();
+            let client = Client {
+                address: "127.0.0.1".parse().unwrap(),
+                transport,
+            };
+            let request_bytes = Vec::<u8>::try_from(request).unwrap();
+            let response_bytes = context
+                .handle_request(&request_bytes, client)
+                .unwrap()
+                .remove(0);
+            (
+                response_bytes.len(),
+                Message::try_from(response_bytes.as_slice()).unwrap(),
+            )
+        };
+        // When
+        let (size, response) = request(Transport::Udp, None);
+        // Then
+        assert!(size <= 512);
+        assert_eq!(response.header.tc, 1);
+        assert!(response.answers.is_empty());
+        assert!(response.additionals.is_empty());
+        // When
+        let (size, response) = request(Transport::Udp, Some(EDNS_DO_FLAG));
+        // Then
+        assert!(size > 512 && size <= EDNS_BUFFER_SIZE as usize);
+        assert_eq!(response.header.tc, 0);
+        assert_eq!(response.answers.len(), 30);
+        let opt = response.edns().unwrap();
+        assert_eq!(opt.class, EDNS_BUFFER_SIZE);
+        assert_eq!(opt.ttl, EDNS_DO_FLAG);
+        // When
+        let (_, response) = request(Transport::Tcp, None);
+        // Then
+        assert_eq!(response.header.tc, 0);
+        assert_eq!(response.answers.len(), 30);
+    }
+
+    #[test]
+    fn test_when_udp_responses_are_rate_limited_then_they_slip_truncated_over_udp_only() {
+        // Given a rate of one response per second, every limited one slipping
+        let directory = TempPath::directory("rrl");
+        let config_path = directory.join("config.toml");
+        let config = "[static]\nrecords = [\"router.lan A 192.168.1.1\"]\n\n\
+                      [rrl]\nresponses_per_second = 1\nslip = 1\n";
+        fs::write(&config_path, config).unwrap();
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let request = Message::query(3, vec![Question::new("router.lan.", RecordType::A)]);
+        let request_bytes = Vec::<u8>::try_from(request).unwrap();
+        let send = |transport| {
+            let client = Client {
+                address: "192.0.2.1".parse().unwrap(),
+                transport,
+            };
+            let responses_bytes = context.handle_request(&request_bytes, client).unwrap();
+            Message::try_from(responses_bytes[0].as_slice()).unwrap()
+        };
+        // When
+        let first = send(Transport::Udp);
+        let second = send(Transport::Udp);
+        let over_tcp = send(Transport::Tcp);
+        // Then
+        assert_eq!((first.header.tc, first.answers.len()), (0, 1));
+        assert_eq!((second.header.tc, second.answers.len()), (1, 0));
+        assert_eq!(second.header.id, 3);
+        assert_eq!(second.questions.len(), 1);
+        assert_eq!((over_tcp.header.tc, over_tcp.answers.len()), (0, 1));
+        assert_eq!(context.rrl.stats().slipped, 1);
+    }
+
+    #[test]
+    fn test_when_config_is_reloaded_then_zones_change_and_cache_follows_upstreams() {
+        // Given
+        let directory = TempPath::directory("reload");
+        let config_path = directory.join("config.toml");
+        let zone_path = directory.join("example.com.zone");
+        let write_config = |upstream: &str| {
+            let config = format!(
+                "[resolver]\nupstreams = [\"{upstream}\"]\n\n\
+                 [[zones]]\nname = \"example.com\"\nfile = \"example.com.zone\"\n"
+            );
+            fs::write(&config_path, config).unwrap();
+        };
+        let write_zone = |address: &str| {
+            let zone = format!("@ SOA ns1 hostmaster 1 3600 900 604800 60\nwww A {address}\n");
+            fs::write(&zone_path, zone).unwrap();
+        };
+        write_config("127.0.0.1:5300");
+        write_zone("192.0.2.1");
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let question = Question::new("www.example.com.", RecordType::A);
+        let zone_rdata = |context: &Context| {
+            let (_, answers, _) = context.state().zones.lookup(&question).unwrap();
+            answers[0].rdata.clone()
+        };
+        let cached: Answer = "cached.com. 300 IN A 192.0.2.9".parse().unwrap();
+        context.cache.insert(&question, &[cached]);
+        // When the zone changes
+        write_zone("192.0.2.2");
+        context.reload().unwrap();
+        // Then
+        assert_eq!(zone_rdata(&context), vec![192, 0, 2, 2]);
+        assert_eq!(context.cache.len(), 1);
+        // When the upstreams change
+        write_config("127.0.0.1:5301");
+        context.reload().unwrap();
+        // Then
+        assert_eq!(context.cache.len(), 0);
+        // When the zone becomes invalid
+        write_zone("192.0.2.300");
+        let error = context.reload().unwrap_err();
+        // Then the active state is kept
+        assert!(
+            format!("{error:#}").contains("invalid IPv4 address"),
+            "{error:#}"
+        );
+        assert_eq!(zone_rdata(&context), vec![192, 0, 2, 2]);
+        assert_eq!(
+            context.state().config.upstreams,
+            vec![Upstream::Dns("127.0.0.1:5301".parse().unwrap())]
+        );
+    }
 
-        // Send response message
-        let response_bytes: Vec<u8> = response_message.into();
-        udp_socket.send_to(&response_bytes, source)?;
+    #[test]
+    fn test_when_reload_fails_then_log_level_cache_capacity_and_state_are_unchanged() {
+        // Given
+        let directory = TempPath::directory("reload-error");
+        let config_path = directory.join("config.toml");
+        let write_config = |level: &str, cache_size: usize, zone: &str| {
+            let config = format!(
+                "[log]\nlevel = \"{level}\"\n\n[cache]\nsize = {cache_size}\n\n\
+                 [[zones]]\nname = \"example.com\"\nfile = \"{zone}\"\n"
+            );
+            fs::write(&config_path, config).unwrap();
+        };
+        let zone = "@ SOA ns1 hostmaster 1 3600 900 604800 60\nwww A 192.0.2.1\n";
+        fs::write(directory.join("valid.zone"), zone).unwrap();
+        fs::write(
+            directory.join("invalid.zone"),
+            zone.replace("192.0.2.1", "192.0.2.300"),
+        )
+        .unwrap();
+        write_config("info", 1, "valid.zone");
+        let params = vec![CliParam::Config(config_path.display().to_string())];
+        let context = Context::new(params, Dispatcher::default()).unwrap();
+        let active_state = context.state();
+        // When the new config lowers the level and empties the cache, but its zone is invalid
+        write_config("error", 0, "invalid.zone");
+        let error = context.reload().unwrap_err();
+        // Then
+        assert!(
+            format!("{error:#}").contains("invalid IPv4 address"),
+            "{error:#}"
+        );
+        assert!(log::is_enabled(log::Level::Info));
+        let question = Question::new("cached.com.", RecordType::A);
+        let cached: Answer = "cached.com. 300 IN A 192.0.2.9".parse().unwrap();
+        context.cache.insert(&question, &[cached]);
+        assert_eq!(context.cache.len(), 1);
+        assert!(Arc::ptr_eq(&context.state(), &active_state));
     }
 }
@@ -0,0 +1,197 @@
+use super::acl::Cidr;
+use super::blocklist::Blocklist;
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::rcode::Rcode;
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+
+const DEFAULT_POLICY_NAME: &str = "default";
+
+// Filtering for the clients of some subnets, in place of the default blocklist
+#[derive(Debug)]
+pub struct Policy {
+    name: String,
+    clients: Vec<Cidr>,
+    blocklist: Blocklist,
+}
+
+#[derive(Debug)]
+pub struct Policies {
+    default: Policy,
+    policies: Vec<Policy>,
+}
+
+impl Policy {
+    // Returns the response to a blocked question, or None when the name is not blocked
+    pub fn check(&self, question: &Question) -> Option<(Rcode, Vec<Answer>)> {
+        let response = self.blocklist.check(question);
+        if response.is_some() {
+            log_debug!(
+                "Blocked query for {} by policy '{}'",
+                question.label,
+                self.name
+            );
+        }
+        response
+    }
+}
+
+impl Default for Policies {
+    fn default() -> Self {
+        Policies {
+            default: Policy {
+                name: DEFAULT_POLICY_NAME.to_string(),
+                clients: vec![],
+                blocklist: Blocklist::default(),
+            },
+            policies: vec![],
+        }
+    }
+}
+
+impl Policies {
+    pub fn load(config: &Config) -> Result<Self> {
+        let default = Blocklist::load(&config.blocklist)?;
+        if default.blocked_count() > 0 {
+            log_info!(
+                "Loaded blocklist with {} blocked and {} allowed domains",
+                default.blocked_count(),
+                default.allowed_count()
+            );
+        }
+
+        let mut policies = vec![];
+        for policy_config in &config.policies {
+            let blocklist = Blocklist::load(&policy_config.blocklist)
+                .with_context(|| format!("policy '{}'", policy_config.name))?;
+            log_info!(
+                "Loaded policy '{}' with {} blocked and {} allowed domains",
+                policy_config.name,
+                blocklist.blocked_count(),
+                blocklist.allowed_count()
+            );
+            policies.push(Policy {
+                name: policy_config.name.clone(),
+                clients: policy_config.clients.clone(),
+                blocklist,
+            });
+        }
+
+        let default = Policy {
+            name: DEFAULT_POLICY_NAME.to_string(),
+            clients: vec![],
+            blocklist: default,
+        };
+        Ok(Policies { default, policies })
+    }
+
+    // The policy with the most specific subnet containing the client wins
+    pub fn for_client(&self, address: &IpAddr) -> &Policy {
+        self.policies
+            .iter()
+            .filter_map(|policy| {
+                policy
+                    .clients
+                    .iter()
+                    .filter(|cidr| cidr.contains(address))
+                    .map(|cidr| cidr.prefix_length)
+                    .max()
+                    .map(|prefix_length| (prefix_length, policy))
+            })
+            .max_by_key(|(prefix_length, _)| *prefix_length)
+            .map_or(&self.default, |(_, policy)| policy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{BlocklistConfig, PolicyConfig};
+    use crate::server::blocklist::BlockAction;
+    use crate::server::message::record_type::RecordType;
+    use crate::test_utils::TempPath;
+    use std::fs;
+
+    fn policy(name: &str, clients: &[&str]) -> Policy {
+        Policy {
+            name: name.to_string(),
+            clients: clients.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+            blocklist: Blocklist::default(),
+        }
+    }
+
+    #[test]
+    fn test_when_client_is_in_several_subnets_then_most_specific_policy_is_used() {
+        // Given
+        let policies = Policies {
+            policies: vec![
+                policy("home", &["192.168.0.0/16"]),
+                policy("kids", &["192.168.1.0/24", "fd00::/8"]),
+            ],
+            ..Policies::default()
+        };
+        let name_for = |address: &str| policies.for_client(&address.parse().unwrap()).name.as_str();
+        // When
+        // Then
+        assert_eq!(name_for("192.168.1.20"), "kids");
+        assert_eq!(name_for("::ffff:192.168.1.20"), "kids");
+        assert_eq!(name_for("192.168.2.20"), "home");
+        assert_eq!(name_for("fd00::20"), "kids");
+        assert_eq!(name_for("10.0.0.1"), "default");
+    }
+
+    #[test]
+    fn test_when_policies_are_loaded_then_each_blocks_with_its_own_lists() {
+        // Given
+        let directory = TempPath::directory("policies");
+        fs::write(directory.join("ads.txt"), "ads.example.com\n").unwrap();
+        fs::write(directory.join("games.txt"), "games.example.com\n").unwrap();
+        let blocklist = |file: &str| BlocklistConfig {
+            files: vec![directory.join(file)],
+            allowlist: vec![],
+            action: BlockAction::NxDomain,
+        };
+        let config = Config {
+            blocklist: blocklist("ads.txt"),
+            policies: vec![PolicyConfig {
+                name: "kids".to_string(),
+                clients: vec!["192.168.1.0/24".parse().unwrap()],
+                blocklist: blocklist("games.txt"),
+            }],
+            ..Config::default()
+        };
+        let question = |label: &str| Question::new(label, RecordType::A);
+        // When
+        let policies = Policies::load(&config).unwrap();
+        // Then
+        let kids = policies.for_client(&"192.168.1.20".parse().unwrap());
+        let default = policies.for_client(&"10.0.0.1".parse().unwrap());
+        assert!(kids.check(&question("games.example.com.")).is_some());
+        assert!(kids.check(&question("ads.example.com.")).is_none());
+        assert!(default.check(&question("ads.example.com.")).is_some());
+        assert!(default.check(&question("games.example.com.")).is_none());
+    }
+
+    #[test]
+    fn test_when_policy_list_is_missing_then_loading_fails_with_policy_name() {
+        // Given
+        let config = Config {
+            policies: vec![PolicyConfig {
+                name: "kids".to_string(),
+                clients: vec!["192.168.1.0/24".parse().unwrap()],
+                blocklist: BlocklistConfig {
+                    files: vec!["/nonexistent/games.txt".into()],
+                    allowlist: vec![],
+                    action: BlockAction::NxDomain,
+                },
+            }],
+            ..Config::default()
+        };
+        // When
+        let error = Policies::load(&config).unwrap_err();
+        // Then
+        assert!(format!("{error:#}").starts_with("policy 'kids': failed to read domain list"));
+    }
+}
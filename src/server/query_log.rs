@@ -0,0 +1,290 @@
+// One structured line per answered query, in JSON or logfmt, to stderr or to a rotated file
+use super::client::Client;
+use super::message::rcode::Rcode;
+use super::message::record_type::RecordType;
+use crate::config::{QueryLogConfig, QueryLogFormat};
+use crate::log::Level;
+use anyhow::{Context, Result};
+use rand::Rng;
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What the resolution of the request being answered by this thread went through
+#[derive(Debug, Default)]
+pub struct Trace {
+    pub upstream: Option<SocketAddr>,
+    pub cache_hit: bool,
+}
+
+thread_local! {
+    static TRACE: RefCell<Trace> = RefCell::default();
+}
+
+pub fn trace_upstream(upstream: SocketAddr) {
+    TRACE.with(|trace| trace.borrow_mut().upstream = Some(upstream));
+}
+
+pub fn trace_cache_hit() {
+    TRACE.with(|trace| trace.borrow_mut().cache_hit = true);
+}
+
+// Returns the trace of this thread and starts a new one
+pub fn take_trace() -> Trace {
+    TRACE.with(|trace| trace.take())
+}
+
+pub struct QueryEntry {
+    pub time: SystemTime,
+    pub client: Client,
+    pub qname: String,
+    pub qtype: RecordType,
+    pub rcode: Rcode,
+    pub answers: usize,
+    pub trace: Trace,
+    pub latency: Duration,
+}
+
+impl QueryEntry {
+    // Failures are warnings, so they can be logged on their own
+    fn level(&self) -> Level {
+        if self.rcode == Rcode::ServFail {
+            Level::Warn
+        } else {
+            Level::Info
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+#[derive(Default)]
+pub struct QueryLog {
+    file: Mutex<Option<LogFile>>,
+}
+
+impl QueryLog {
+    pub fn log(&self, config: &QueryLogConfig, entry: &QueryEntry) {
+        let level = entry.level();
+        if level > config.level {
+            return;
+        }
+        // Only successful queries are sampled, failures are always logged
+        if level == Level::Info
+            && config.sample > 1
+            && rand::thread_rng().gen_range(0..config.sample) != 0
+        {
+            return;
+        }
+        let line = format_entry(entry, config.format);
+        match &config.file {
+            Some(path) => {
+                if let Err(error) = self.write(config, path, &line) {
+                    log_warn!("Failed to write the query log: {error:#}");
+                }
+            }
+            None => eprintln!("{line}"),
+        }
+    }
+
+    fn write(&self, config: &QueryLogConfig, path: &Path, line: &str) -> Result<()> {
+        let mut log_file = self.file.lock().unwrap();
+        // The file changes when the configuration is reloaded
+        if !matches!(log_file.as_ref(), Some(log_file) if log_file.path == path) {
+            *log_file = Some(open(path)?);
+        }
+        let open_file = log_file.as_mut().unwrap();
+        let size = line.len() as u64 + 1;
+        if config.max_size > 0 && open_file.size > 0 && open_file.size + size > config.max_size {
+            rotate(path, config.max_files)?;
+            *open_file = open(path)?;
+        }
+        writeln!(open_file.file, "{line}")?;
+        open_file.size += size;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<LogFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok(LogFile {
+        path: path.to_path_buf(),
+        file,
+        size,
+    })
+}
+
+// Moves file.log to file.log.1, file.log.1 to file.log.2 and so on, keeping max_files of them
+fn rotate(path: &Path, max_files: u32) -> Result<()> {
+    let rotated = |index: u32| PathBuf::from(format!("{}.{index}", path.display()));
+    if max_files == 0 {
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+    for index in (1..max_files).rev() {
+        if rotated(index).exists() {
+            fs::rename(rotated(index), rotated(index + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1)).with_context(|| format!("failed to rotate {}", path.display()))?;
+    Ok(())
+}
+
+pub fn format_entry(entry: &QueryEntry, format: QueryLogFormat) -> String {
+    let time = entry
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let level = format!("{:?}", entry.level()).to_lowercase();
+    let upstream = entry.trace.upstream.map(|upstream| upstream.to_string());
+    let latency_ms = entry.latency.as_secs_f64() * 1000.0;
+    let mut line = String::new();
+
+    match format {
+        QueryLogFormat::Json => {
+            let upstream = upstream.map_or("null".to_string(), |upstream| json_string(&upstream));
+            let _ = write!(
+                line,
+                "{{\"time\":{time:.3},\"level\":\"{level}\",\"client\":\"{}\",\"transport\":\"{}\",\
+                 \"qname\":{},\"qtype\":\"{}\",\"rcode\":\"{}\",\"answers\":{},\"upstream\":{upstream},\
+                 \"latency_ms\":{latency_ms:.3},\"cache_hit\":{}}}",
+                entry.client.address,
+                entry.client.transport,
+                json_string(&entry.qname),
+                entry.qtype,
+                entry.rcode,
+                entry.answers,
+                entry.trace.cache_hit
+            );
+        }
+        QueryLogFormat::Logfmt => {
+            let _ = write!(
+                line,
+                "time={time:.3} level={level} client={} transport={} qname={} qtype={} rcode={} \
+                 answers={} upstream={} latency_ms={latency_ms:.3} cache_hit={}",
+                entry.client.address,
+                entry.client.transport,
+                logfmt_value(&entry.qname),
+                entry.qtype,
+                entry.rcode,
+                entry.answers,
+                upstream.unwrap_or_default(),
+                entry.trace.cache_hit
+            );
+        }
+    }
+    line
+}
+
+fn json_string(value: &str) -> String {
+    let mut string = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(string, "\\u{:04x}", c as u32);
+            }
+            c => string.push(c),
+        }
+    }
+    string.push('"');
+    string
+}
+
+// Values are quoted when they would not read back as a single value
+fn logfmt_value(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c <= ' ' || c == '"' || c == '=') {
+        format!("{value:?}")
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::client::Transport;
+    use crate::test_utils::TempPath;
+
+    fn entry() -> QueryEntry {
+        QueryEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            client: Client {
+                address: "192.0.2.1".parse().unwrap(),
+                transport: Transport::Udp,
+            },
+            qname: "my \"host\".example.com.".to_string(),
+            qtype: RecordType::Aaaa,
+            rcode: Rcode::NoError,
+            answers: 2,
+            trace: Trace {
+                upstream: Some("192.0.2.53:53".parse().unwrap()),
+                cache_hit: false,
+            },
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn test_when_entry_is_formatted_then_values_are_escaped() {
+        // Given
+        let entry = entry();
+        // When
+        let json = format_entry(&entry, QueryLogFormat::Json);
+        let logfmt = format_entry(&entry, QueryLogFormat::Logfmt);
+        // Then
+        assert_eq!(
+            json,
+            "{\"time\":1700000000.250,\"level\":\"info\",\"client\":\"192.0.2.1\",\
+             \"transport\":\"udp\",\"qname\":\"my \\\"host\\\".example.com.\",\"qtype\":\"AAAA\",\
+             \"rcode\":\"NOERROR\",\"answers\":2,\"upstream\":\"192.0.2.53:53\",\
+             \"latency_ms\":1.500,\"cache_hit\":false}"
+        );
+        assert_eq!(
+            logfmt,
+            "time=1700000000.250 level=info client=192.0.2.1 transport=udp \
+             qname=\"my \\\"host\\\".example.com.\" qtype=AAAA rcode=NOERROR answers=2 \
+             upstream=192.0.2.53:53 latency_ms=1.500 cache_hit=false"
+        );
+    }
+
+    #[test]
+    fn test_when_log_file_is_full_then_it_is_rotated() {
+        // Given
+        let directory = TempPath::directory("query-log");
+        let path = directory.join("queries.log");
+        let config = QueryLogConfig {
+            file: Some(path.clone()),
+            max_size: 300,
+            max_files: 2,
+            ..QueryLogConfig::default()
+        };
+        let query_log = QueryLog::default();
+        // When
+        for _ in 0..4 {
+            query_log.log(&config, &entry());
+        }
+        // Then
+        let count_lines = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(count_lines(path.clone()), 1);
+        assert_eq!(count_lines(directory.join("queries.log.1")), 1);
+        assert_eq!(count_lines(directory.join("queries.log.2")), 1);
+        assert!(!directory.join("queries.log.3").exists());
+    }
+}
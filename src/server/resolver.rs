@@ -1,67 +1,584 @@
-use super::message::{answer::Answer, header::Header, question::Question};
-use crate::{cli_params::CliParam, server::message::Message};
-use anyhow::Result;
+use super::client::Transport;
+use super::dnstap::{Event, EventType};
+use super::doh::{self, Connections};
+use super::message::rdata::RData;
+use super::message::record_type::RecordType;
+use super::message::{answer::Answer, header::Header, question::Question, rcode::Rcode};
+use super::query_log;
+use super::Context;
+use crate::config::{Config, ForwardConfig, Upstream};
+use crate::server::message::{Message, EDNS_BUFFER_SIZE};
+use anyhow::{anyhow, bail, Context as _, Result};
 use rand::Rng;
-use std::net::UdpSocket;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_MESSAGE_SIZE: usize = 65_535;
 
 pub enum Resolver {
+    // Without upstreams, names not answered locally don't exist
     Default,
-    Custom(String),
+    // Names under a forwarded suffix go to its upstreams, the others to the global ones
+    Custom {
+        upstreams: Vec<Upstream>,
+        forwards: Vec<ForwardConfig>,
+        connections: Connections,
+    },
 }
 
-impl From<&[CliParam]> for Resolver {
-    fn from(params: &[CliParam]) -> Self {
-        let resolver_param = params
-            .iter()
-            .find(|param| matches!(param, CliParam::Resolver(_)));
-        match resolver_param {
-            Some(CliParam::Resolver(value)) => Resolver::Custom(value.clone()),
-            None => Resolver::Default,
+impl From<&Config> for Resolver {
+    fn from(config: &Config) -> Self {
+        if config.upstreams.is_empty() && config.forwards.is_empty() {
+            return Resolver::Default;
         }
+        // Longest suffixes first, so the most specific one wins
+        let mut forwards = config.forwards.clone();
+        forwards.sort_by_key(|forward| std::cmp::Reverse(forward.suffix.len()));
+        Resolver::Custom {
+            upstreams: config.upstreams.clone(),
+            forwards,
+            connections: Connections::default(),
+        }
+    }
+}
+
+impl Resolver {
+    pub fn upstreams_for(&self, name: &str) -> &[Upstream] {
+        let Resolver::Custom {
+            upstreams,
+            forwards,
+            ..
+        } = self
+        else {
+            return &[];
+        };
+        let name = name.to_lowercase();
+        forwards
+            .iter()
+            .find(|forward| {
+                name == forward.suffix || name.ends_with(&format!(".{}", forward.suffix))
+            })
+            .map_or(upstreams, |forward| &forward.upstreams)
     }
 }
 
-pub fn resolve_questions(questions: &[Question], resolver: &Resolver) -> Result<Vec<Answer>> {
+pub fn resolve_questions(
+    questions: &[Question],
+    resolver: &Resolver,
+    context: &Context,
+) -> Result<(Rcode, Vec<Answer>)> {
     match resolver {
         Resolver::Default => default_resolver(questions),
-        Resolver::Custom(address_port) => custom_resolver(questions, address_port),
+        Resolver::Custom { connections, .. } => {
+            custom_resolver(questions, resolver, connections, context)
+        }
     }
 }
 
-fn default_resolver(questions: &[Question]) -> Result<Vec<Answer>> {
-    Ok(questions.iter().map(Answer::for_question).collect())
+fn default_resolver(_questions: &[Question]) -> Result<(Rcode, Vec<Answer>)> {
+    Ok((Rcode::NxDomain, vec![]))
 }
 
-fn custom_resolver(questions: &[Question], address_port: &String) -> Result<Vec<Answer>> {
-    let udp_socket = UdpSocket::bind("0.0.0.0:0")?;
-    udp_socket.connect(address_port)?;
-
-    let mut thread_rand = rand::thread_rng();
+// Upstreams are tried in order, moving to the next one when a query fails. The response
+// code is the one of the last question that failed, if any.
+fn custom_resolver(
+    questions: &[Question],
+    resolver: &Resolver,
+    connections: &Connections,
+    context: &Context,
+) -> Result<(Rcode, Vec<Answer>)> {
+    let metrics = &context.metrics;
     let mut answers = vec![];
+    let mut rcode = Rcode::NoError;
 
     for question in questions {
-        // Create a request
-        let header = Header {
-            id: thread_rand.gen(),
-            qdcount: 1,
-            ..Header::default()
+        let upstreams = resolver.upstreams_for(&question.label);
+        if upstreams.is_empty() {
+            rcode = Rcode::NxDomain;
+            continue;
+        }
+        let mut last_error = anyhow!("no upstream resolver configured");
+        let mut question_answers = None;
+        for upstream in upstreams {
+            let started_at = Instant::now();
+            let result = query_upstream(question, upstream, connections, context);
+            // Failures and timeouts count in the latency too, they are what is slow
+            metrics.record_upstream(upstream.address(), started_at.elapsed());
+            match result {
+                Ok(upstream_answers) => {
+                    query_log::trace_upstream(upstream.address());
+                    question_answers = Some(upstream_answers);
+                    break;
+                }
+                Err(error) => {
+                    log_warn!("Upstream {upstream} failed: {error:#}");
+                    metrics.record_upstream_error(upstream.address());
+                    last_error = error;
+                }
+            }
+        }
+        match question_answers {
+            Some((question_rcode, question_answers)) => {
+                if question_rcode != Rcode::NoError {
+                    rcode = question_rcode;
+                }
+                answers.extend(question_answers);
+            }
+            None => return Err(last_error),
+        }
+    }
+
+    Ok((rcode, answers))
+}
+
+fn query_upstream(
+    question: &Question,
+    upstream: &Upstream,
+    connections: &Connections,
+    context: &Context,
+) -> Result<(Rcode, Vec<Answer>)> {
+    // Create a request, asking the upstream to recurse. DNS over HTTP requests have the ID 0
+    // so that HTTP caches can share their responses (RFC 8484 section 4.1).
+    let id = match upstream {
+        Upstream::Dns(_) => rand::thread_rng().gen(),
+        Upstream::Http { .. } => 0,
+    };
+    let header = Header {
+        id,
+        rd: 1,
+        qdcount: 1,
+        ..Header::default()
+    };
+    // A larger UDP buffer is advertised with EDNS (RFC 6891), so fewer responses are truncated.
+    // DNS over HTTP upstreams get it too, as they resolve over UDP themselves.
+    let rdata = RData::Unknown(vec![]);
+    let additionals = vec![Answer::new(
+        ".",
+        RecordType::Opt,
+        EDNS_BUFFER_SIZE,
+        0,
+        &rdata,
+    )];
+    let request_message = Message {
+        header,
+        questions: vec![question.uncompressed_question()],
+        answers: vec![],
+        authorities: vec![],
+        additionals,
+    };
+    let request_bytes = Vec::<u8>::try_from(request_message)?;
+
+    // Every exchange with the upstream is tapped, with the transport it used
+    let exchange = |transport, send: &dyn Fn() -> Result<Vec<u8>>| -> Result<Vec<u8>> {
+        let query_time = SystemTime::now();
+        let tap = |event_type, message: &[u8], response_time| {
+            if let Some(dnstap) = &context.dnstap {
+                dnstap.send(&Event {
+                    event_type,
+                    transport,
+                    client: None,
+                    upstream: Some(upstream.address()),
+                    query_time,
+                    response_time,
+                    message,
+                });
+            }
+        };
+        tap(EventType::ForwarderQuery, &request_bytes, None);
+        let response_bytes = send()?;
+        tap(
+            EventType::ForwarderResponse,
+            &response_bytes,
+            Some(SystemTime::now()),
+        );
+        Ok(response_bytes)
+    };
+    let response_bytes = match upstream {
+        Upstream::Dns(address) => {
+            exchange(Transport::Udp, &|| exchange_udp(&request_bytes, address))?
+        }
+        Upstream::Http { address, path } => exchange(Transport::Doh, &|| {
+            doh::exchange(&request_bytes, *address, path, connections)
+        })?,
+    };
+
+    let mut response_message =
+        Message::try_from(response_bytes.as_slice()).context("malformed response message")?;
+    // A truncated response only has part of the answers, the whole one is asked over TCP
+    if let (Upstream::Dns(address), 1) = (upstream, response_message.header.tc) {
+        log_debug!("Upstream {upstream} truncated its response, retrying over TCP");
+        let response_bytes = exchange(Transport::Tcp, &|| exchange_tcp(&request_bytes, address))?;
+        response_message =
+            Message::try_from(response_bytes.as_slice()).context("malformed response message")?;
+    }
+    Ok((
+        Rcode::from(response_message.header.rcode),
+        chained_answers(response_message.answers, question),
+    ))
+}
+
+// Only the answers owned by the question name, or by the names its CNAME chain leads to, are
+// kept, so an upstream can't have records for unrelated names cached
+fn chained_answers(answers: Vec<Answer>, question: &Question) -> Vec<Answer> {
+    let mut names = vec![question.label.to_lowercase()];
+    // The chain may be in any order, every name found is searched for its alias in turn
+    let mut index = 0;
+    while index < names.len() {
+        for answer in &answers {
+            if !answer.label.eq_ignore_ascii_case(&names[index]) {
+                continue;
+            }
+            if let RData::Cname(target) = answer.rdata() {
+                let target = target.to_lowercase();
+                if !names.contains(&target) {
+                    names.push(target);
+                }
+            }
+        }
+        index += 1;
+    }
+
+    let (chained, unrelated): (Vec<Answer>, Vec<Answer>) = answers
+        .into_iter()
+        .partition(|answer| names.contains(&answer.label.to_lowercase()));
+    for answer in &unrelated {
+        log_debug!(
+            "Ignoring an answer for {} unrelated to {}",
+            answer.label,
+            question.label
+        );
+    }
+    chained
+}
+
+// Sends the request to the server over UDP and waits for its response. Datagrams that don't
+// answer the request, which may be spoofed, are ignored until the timeout expires.
+pub fn exchange_udp(request_bytes: &[u8], server: &SocketAddr) -> Result<Vec<u8>> {
+    // The local socket must match the server address family
+    let local_address = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let udp_socket = UdpSocket::bind(local_address)?;
+    udp_socket.connect(server)?;
+
+    udp_socket.send(request_bytes)?;
+
+    // Responses can be larger than 512 bytes when the request advertises a larger buffer
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    let deadline = Instant::now() + UPSTREAM_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!("no response from {server} within {UPSTREAM_TIMEOUT:?}");
+        }
+        udp_socket.set_read_timeout(Some(remaining))?;
+        let size = udp_socket.recv(&mut buffer)?;
+        if is_response_to(request_bytes, &buffer[..size]) {
+            buffer.truncate(size);
+            return Ok(buffer);
+        }
+        log_debug!("Ignoring a response from {server} that doesn't match the query");
+    }
+}
+
+// Sends the request to the server over TCP, where messages are prefixed with their length
+pub fn exchange_tcp(request_bytes: &[u8], server: &SocketAddr) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(server, UPSTREAM_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+    let length = u16::try_from(request_bytes.len())
+        .with_context(|| format!("request of {} bytes is too large", request_bytes.len()))?;
+    stream.write_all(&[&length.to_be_bytes()[..], request_bytes].concat())?;
+
+    let mut length_bytes = [0; 2];
+    stream.read_exact(&mut length_bytes)?;
+    let mut response_bytes = vec![0; u16::from_be_bytes(length_bytes) as usize];
+    stream.read_exact(&mut response_bytes)?;
+    if !is_response_to(request_bytes, &response_bytes) {
+        bail!("response from {server} doesn't match the query");
+    }
+    Ok(response_bytes)
+}
+
+// A response has the ID and the questions of its request (RFC 5452 section 9.1), with names
+// compared without case as servers may not keep it
+pub fn is_response_to(request_bytes: &[u8], response_bytes: &[u8]) -> bool {
+    let (Ok(request), Ok(response)) = (
+        Message::try_from(request_bytes),
+        Message::try_from(response_bytes),
+    ) else {
+        return false;
+    };
+    response.header.qr == 1
+        && response.header.id == request.header.id
+        && response.questions.len() == request.questions.len()
+        && response.questions.iter().zip(&request.questions).all(
+            |(response_question, request_question)| {
+                response_question
+                    .label
+                    .eq_ignore_ascii_case(&request_question.label)
+                    && response_question.qtype == request_question.qtype
+                    && response_question.qclass == request_question.qclass
+            },
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::dispatcher::Dispatcher;
+    use crate::server::message::name::labels_bytes;
+    use crate::server::message::record_type::CLASS_IN;
+    use crate::server::metrics;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn test_when_name_is_under_forwarded_suffixes_then_longest_suffix_wins() {
+        // Given
+        let forward = |suffix: &str, upstream: &str| ForwardConfig {
+            suffix: suffix.to_string(),
+            upstreams: vec![Upstream::Dns(upstream.parse().unwrap())],
         };
-        let request_message = Message {
-            header,
-            questions: vec![question.uncompressed_question()],
-            answers: vec![],
+        let config = Config {
+            upstreams: vec![Upstream::Dns("1.1.1.1:53".parse().unwrap())],
+            forwards: vec![
+                forward("corp.internal.", "10.0.0.53:53"),
+                forward("lab.corp.internal.", "10.1.0.53:53"),
+            ],
+            ..Config::default()
         };
+        // When
+        let resolver = Resolver::from(&config);
+        // Then
+        let upstream_for = |name| resolver.upstreams_for(name)[0].to_string();
+        assert_eq!(upstream_for("corp.internal."), "10.0.0.53:53");
+        assert_eq!(upstream_for("wiki.Corp.Internal."), "10.0.0.53:53");
+        assert_eq!(upstream_for("host.lab.corp.internal."), "10.1.0.53:53");
+        assert_eq!(upstream_for("notcorp.internal."), "1.1.1.1:53");
+        assert_eq!(upstream_for("example.com."), "1.1.1.1:53");
+    }
+
+    #[test]
+    fn test_when_upstream_truncates_over_udp_then_question_is_asked_over_tcp() {
+        // Given an upstream answering over UDP with an empty truncated response
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let udp_socket = UdpSocket::bind(address).unwrap();
+        let udp_upstream = thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (size, client) = udp_socket.recv_from(&mut buffer).unwrap();
+            let mut message = Message::try_from(&buffer[..size]).unwrap();
+            let request_additionals = message.additionals.clone();
+            message.header.qr = 1;
+            message.header.tc = 1;
+            message.additionals = vec![];
+            udp_socket
+                .send_to(&Vec::<u8>::try_from(message).unwrap(), client)
+                .unwrap();
+            request_additionals
+        });
+        thread::spawn(move || {
+            let (mut stream, _) = tcp_listener.accept().unwrap();
+            let mut length_bytes = [0; 2];
+            stream.read_exact(&mut length_bytes).unwrap();
+            let mut request_bytes = vec![0; u16::from_be_bytes(length_bytes) as usize];
+            stream.read_exact(&mut request_bytes).unwrap();
+            let mut message = Message::try_from(request_bytes.as_slice()).unwrap();
+            message.header.qr = 1;
+            message.additionals = vec![];
+            let rdata = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+            message.answers = vec![Answer::new(
+                "big.example.",
+                RecordType::A,
+                CLASS_IN,
+                60,
+                &rdata,
+            )];
+            let response_bytes = Vec::<u8>::try_from(message).unwrap();
+            let length_bytes = (response_bytes.len() as u16).to_be_bytes();
+            stream
+                .write_all(&[&length_bytes[..], &response_bytes].concat())
+                .unwrap();
+        });
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let question = Question {
+            qname: vec![],
+            qtype: RecordType::A.into(),
+            qclass: CLASS_IN,
+            label: "big.example.".to_string(),
+        };
+        // When
+        let (rcode, answers) = query_upstream(
+            &question,
+            &Upstream::Dns(address),
+            &Connections::default(),
+            &context,
+        )
+        .unwrap();
+        // Then
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].to_string(), "big.example.\t60\tIN\tA\t192.0.2.1");
+        let request_additionals = udp_upstream.join().unwrap();
+        assert_eq!(request_additionals[0].record_type(), RecordType::Opt);
+        assert_eq!(request_additionals[0].class, EDNS_BUFFER_SIZE);
+    }
 
-        // Send request
-        let request_bytes: Vec<u8> = request_message.into();
-        udp_socket.send(&request_bytes)?;
+    #[test]
+    fn test_when_responses_do_not_match_the_query_then_they_are_ignored() {
+        // Given an upstream first sending responses with another ID and another question
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (size, client) = upstream.recv_from(&mut buffer).unwrap();
+            let request = Message::try_from(&buffer[..size]).unwrap();
+            let response = |id: u16, label: &str, address: Ipv4Addr| {
+                let mut response = request.response_message(
+                    vec![Answer::new(
+                        label,
+                        RecordType::A,
+                        CLASS_IN,
+                        60,
+                        &RData::A(address),
+                    )],
+                    Rcode::NoError,
+                );
+                response.header.id = id;
+                response.questions[0].label = label.to_string();
+                response.questions[0].qname = labels_bytes(label);
+                Vec::<u8>::try_from(response).unwrap()
+            };
+            let id = request.header.id;
+            for response_bytes in [
+                response(
+                    id.wrapping_add(1),
+                    "victim.example.",
+                    Ipv4Addr::new(6, 6, 6, 6),
+                ),
+                response(id, "other.example.", Ipv4Addr::new(6, 6, 6, 6)),
+                response(id, "Victim.Example.", Ipv4Addr::new(192, 0, 2, 1)),
+            ] {
+                upstream.send_to(&response_bytes, client).unwrap();
+            }
+        });
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let question = Question {
+            qname: vec![],
+            qtype: RecordType::A.into(),
+            qclass: CLASS_IN,
+            label: "victim.example.".to_string(),
+        };
+        // When
+        let (_, answers) = query_upstream(
+            &question,
+            &Upstream::Dns(address),
+            &Connections::default(),
+            &context,
+        )
+        .unwrap();
+        // Then
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rdata(), RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+    }
 
-        let mut buffer = [0; 512];
-        let (size, _) = udp_socket.recv_from(&mut buffer)?;
-        let bytes = &buffer[..size];
-        let response_message = Message::from(bytes);
-        answers.extend(response_message.answers);
+    #[test]
+    fn test_when_answers_are_not_in_the_cname_chain_then_they_are_dropped() {
+        // Given answers for the question, its alias chain in reverse order and an unrelated name
+        let answer = |label: &str, rdata: RData| {
+            let record_type = match rdata {
+                RData::Cname(_) => RecordType::Cname,
+                _ => RecordType::A,
+            };
+            Answer::new(label, record_type, CLASS_IN, 60, &rdata)
+        };
+        let answers = vec![
+            answer("cdn.example.net.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            answer("bank.example.", RData::A(Ipv4Addr::new(6, 6, 6, 6))),
+            answer(
+                "edge.example.com.",
+                RData::Cname("CDN.example.net.".to_string()),
+            ),
+            answer(
+                "WWW.example.com.",
+                RData::Cname("edge.example.com.".to_string()),
+            ),
+        ];
+        let question = Question {
+            qname: vec![],
+            qtype: RecordType::A.into(),
+            qclass: CLASS_IN,
+            label: "www.example.com.".to_string(),
+        };
+        // When
+        let answers = chained_answers(answers, &question);
+        // Then
+        let labels: Vec<&str> = answers.iter().map(|answer| answer.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            vec!["cdn.example.net.", "edge.example.com.", "WWW.example.com."]
+        );
     }
 
-    Ok(answers)
+    #[test]
+    fn test_when_an_upstream_fails_then_the_next_one_answers() {
+        // Given a closed port, then an upstream answering with an address
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap();
+        let closed_address = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (size, client) = upstream.recv_from(&mut buffer).unwrap();
+            let request = Message::try_from(&buffer[..size]).unwrap();
+            let rdata = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+            let answer = Answer::new("www.example.", RecordType::A, CLASS_IN, 60, &rdata);
+            let response = request.response_message(vec![answer], Rcode::NoError);
+            upstream
+                .send_to(&Vec::<u8>::try_from(response).unwrap(), client)
+                .unwrap();
+        });
+        let resolver = |upstreams| {
+            Resolver::from(&Config {
+                upstreams,
+                ..Config::default()
+            })
+        };
+        let context = Context::new(vec![], Dispatcher::default()).unwrap();
+        let question = Question {
+            qname: labels_bytes("www.example."),
+            qtype: RecordType::A.into(),
+            qclass: CLASS_IN,
+            label: "www.example.".to_string(),
+        };
+        let questions = [question];
+        // When
+        let failover_resolver =
+            resolver(vec![Upstream::Dns(closed_address), Upstream::Dns(address)]);
+        let (rcode, answers) = resolve_questions(&questions, &failover_resolver, &context).unwrap();
+        // Then
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rdata(), RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        // When every upstream fails
+        let failing_resolver = resolver(vec![Upstream::Dns(closed_address)]);
+        let result = resolve_questions(&questions, &failing_resolver, &context);
+        // Then the error leads to SERVFAIL
+        assert!(result.is_err());
+        // And the failed queries are in the latency of the closed upstream
+        let output = metrics::render(&context);
+        assert!(output.contains(&format!(
+            "dns_upstream_latency_seconds_count{{upstream=\"{closed_address}\"}} 2\n"
+        )));
+        assert!(output.contains(&format!(
+            "dns_upstream_latency_seconds_count{{upstream=\"{address}\"}} 1\n"
+        )));
+    }
 }
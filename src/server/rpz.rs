@@ -0,0 +1,596 @@
+// Response policy zones (draft-vixie-dnsop-dns-rpz): zones whose records are triggers on
+// the questions or their resolved answers, each one with the action rewriting the response
+use super::acl::Cidr;
+use super::message::answer::Answer;
+use super::message::name::labels_bytes;
+use super::message::question::Question;
+use super::message::rdata::RData;
+use super::message::record_type::{RecordType, CLASS_IN};
+use super::zone::Zone;
+use crate::config::ZoneConfig;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const RPZ_IP: &str = ".rpz-ip";
+const RPZ_NSIP: &str = ".rpz-nsip";
+const RPZ_NSDNAME: &str = ".rpz-nsdname";
+const RPZ_CLIENT_IP: &str = ".rpz-client-ip";
+// Names whose name servers are kept for the name server triggers, and how long a name
+// without name servers is kept
+const NAME_SERVERS_CACHE_SIZE: usize = 4096;
+const NO_NAME_SERVERS_TTL: u32 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpzAction {
+    NxDomain,
+    NoData,
+    PassThru,
+    Drop,
+    TcpOnly,
+    // Records answered instead of the resolved ones, for the name of the question
+    LocalData(Vec<Answer>),
+}
+
+impl RpzAction {
+    // Actions are written as a CNAME to a special target, any other records are local data
+    fn from_records(records: &[Answer]) -> Self {
+        if let [record] = records {
+            if let RData::Cname(target) = record.rdata() {
+                match target.as_str() {
+                    "" | "." => return RpzAction::NxDomain,
+                    "*." => return RpzAction::NoData,
+                    "rpz-passthru." => return RpzAction::PassThru,
+                    "rpz-drop." => return RpzAction::Drop,
+                    "rpz-tcp-only." => return RpzAction::TcpOnly,
+                    _ => {}
+                }
+            }
+        }
+        RpzAction::LocalData(records.to_vec())
+    }
+
+    // The local records matching the question type, or its CNAME, owned by the question name
+    pub fn local_answers(records: &[Answer], question: &Question) -> Vec<Answer> {
+        let qtype = RecordType::from(question.qtype);
+        let mut matching: Vec<&Answer> = records
+            .iter()
+            .filter(|record| qtype == RecordType::Any || record.record_type() == qtype)
+            .collect();
+        if matching.is_empty() {
+            matching = records
+                .iter()
+                .filter(|record| record.record_type() == RecordType::Cname)
+                .collect();
+        }
+        matching
+            .into_iter()
+            .map(|record| Answer {
+                name: labels_bytes(&question.label),
+                label: question.label.clone(),
+                ..record.clone()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponsePolicyZone {
+    pub name: String,
+    qnames: HashMap<String, RpzAction>,
+    response_ips: Vec<(Cidr, RpzAction)>,
+    ns_names: HashMap<String, RpzAction>,
+    ns_ips: Vec<(Cidr, RpzAction)>,
+}
+
+impl ResponsePolicyZone {
+    pub fn from_zone(zone: &Zone) -> Result<Self> {
+        let mut rpz = ResponsePolicyZone {
+            name: zone.origin.clone(),
+            qnames: HashMap::new(),
+            response_ips: vec![],
+            ns_names: HashMap::new(),
+            ns_ips: vec![],
+        };
+        let suffix = format!(".{}", zone.origin);
+
+        for (owner, records) in zone.records() {
+            // The apex only holds the SOA and NS records of the zone itself
+            let Some(trigger) = owner.strip_suffix(&suffix) else {
+                continue;
+            };
+            let action = RpzAction::from_records(records);
+
+            if let Some(address) = trigger.strip_suffix(RPZ_IP) {
+                rpz.response_ips.push((trigger_cidr(address)?, action));
+            } else if let Some(address) = trigger.strip_suffix(RPZ_NSIP) {
+                rpz.ns_ips.push((trigger_cidr(address)?, action));
+            } else if let Some(name) = trigger.strip_suffix(RPZ_NSDNAME) {
+                rpz.ns_names.insert(format!("{name}."), action);
+            } else if trigger.ends_with(RPZ_CLIENT_IP) {
+                log_warn!("Ignoring unsupported client IP trigger {owner}");
+            } else {
+                rpz.qnames.insert(format!("{trigger}."), action);
+            }
+        }
+
+        Ok(rpz)
+    }
+
+    pub fn triggers_count(&self) -> usize {
+        self.qnames.len() + self.response_ips.len() + self.ns_names.len() + self.ns_ips.len()
+    }
+
+    fn has_response_triggers(&self) -> bool {
+        !self.response_ips.is_empty() || !self.ns_names.is_empty() || !self.ns_ips.is_empty()
+    }
+}
+
+// Exact names win over wildcards, and closer wildcards over farther ones
+fn name_action<'a>(triggers: &'a HashMap<String, RpzAction>, name: &str) -> Option<&'a RpzAction> {
+    if let Some(action) = triggers.get(name) {
+        return Some(action);
+    }
+    let mut parent = name;
+    while let Some((_, rest)) = parent.split_once('.') {
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(action) = triggers.get(&format!("*.{rest}")) {
+            return Some(action);
+        }
+        parent = rest;
+    }
+    None
+}
+
+// The longest prefix containing one of the addresses wins
+fn address_action<'a>(
+    triggers: &'a [(Cidr, RpzAction)],
+    addresses: &[IpAddr],
+) -> Option<&'a RpzAction> {
+    triggers
+        .iter()
+        .filter(|(cidr, _)| addresses.iter().any(|address| cidr.contains(address)))
+        .max_by_key(|(cidr, _)| cidr.prefix_length)
+        .map(|(_, action)| action)
+}
+
+// Reads "32.1.2.0.192" as 192.0.2.1/32 and "128.1.zz.db8.2001" as 2001:db8::1/128
+fn trigger_cidr(trigger: &str) -> Result<Cidr> {
+    let mut labels: Vec<&str> = trigger.split('.').collect();
+    let prefix_length = labels.remove(0);
+    labels.reverse();
+
+    let is_ipv4 = labels.len() == 4 && labels.iter().all(|label| label.parse::<u8>().is_ok());
+    let mut address = if is_ipv4 {
+        labels.join(".")
+    } else {
+        // "zz" stands for the "::" of the address
+        labels
+            .iter()
+            .map(|label| if *label == "zz" { "" } else { label })
+            .collect::<Vec<_>>()
+            .join(":")
+    };
+    if address.starts_with(':') {
+        address.insert(0, ':');
+    }
+    if address.ends_with(':') {
+        address.push(':');
+    }
+    format!("{address}/{prefix_length}")
+        .parse()
+        .with_context(|| format!("invalid IP trigger '{trigger}'"))
+}
+
+// The first zone with a matching trigger wins, whatever the type of the trigger
+#[derive(Debug, Default)]
+pub struct ResponsePolicyZones {
+    zones: Vec<ResponsePolicyZone>,
+    // Name servers of the question names, until the lowest TTL of their NS records
+    name_servers: Mutex<HashMap<String, (Vec<String>, Instant)>>,
+}
+
+impl ResponsePolicyZones {
+    pub fn load(configs: &[ZoneConfig]) -> Result<Self> {
+        let mut zones = vec![];
+        for config in configs {
+            let zone = Zone::load(&config.name, &config.file)?;
+            let rpz = ResponsePolicyZone::from_zone(&zone)
+                .with_context(|| format!("invalid response policy zone {}", config.name))?;
+            log_info!(
+                "Loaded response policy zone {} with {} triggers",
+                rpz.name,
+                rpz.triggers_count()
+            );
+            zones.push(rpz);
+        }
+        Ok(ResponsePolicyZones {
+            zones,
+            name_servers: Mutex::default(),
+        })
+    }
+
+    pub fn zones_count(&self) -> usize {
+        self.zones.len()
+    }
+
+    // Returns the index and the action of the first zone with a trigger on the question
+    // name, checked before resolving so a rewritten name is never sent upstream
+    pub fn check_qname(&self, question: &Question) -> Option<(usize, &RpzAction)> {
+        let qname = question.label.to_lowercase();
+        self.zones.iter().enumerate().find_map(|(index, zone)| {
+            let action = name_action(&zone.qnames, &qname)?;
+            log_debug!("Response policy zone {} matched {qname}", zone.name);
+            Some((index, action))
+        })
+    }
+
+    // Whether one of the zones before the given count has triggers on the response, which
+    // then needs to be resolved as they win over the query name triggers of the next zones
+    pub fn has_response_triggers(&self, zones_count: usize) -> bool {
+        self.zones[..zones_count]
+            .iter()
+            .any(ResponsePolicyZone::has_response_triggers)
+    }
+
+    // Returns the action of the first zone, among the given count, with a trigger on the
+    // resolved answers. Name server triggers need the name servers of the question, looked
+    // up with resolve.
+    pub fn check_response(
+        &self,
+        question: &Question,
+        answers: &[Answer],
+        resolve: &dyn Fn(&Question) -> Result<Vec<Answer>>,
+        zones_count: usize,
+    ) -> Option<&RpzAction> {
+        let qname = question.label.to_lowercase();
+        let response_ips = addresses(answers);
+        let mut name_servers: Option<Vec<String>> = None;
+        let mut name_server_ips: Option<Vec<IpAddr>> = None;
+
+        for zone in &self.zones[..zones_count] {
+            let action = address_action(&zone.response_ips, &response_ips)
+                .or_else(|| {
+                    if zone.ns_names.is_empty() {
+                        return None;
+                    }
+                    name_servers
+                        .get_or_insert_with(|| self.name_servers(&qname, resolve))
+                        .iter()
+                        .find_map(|name_server| name_action(&zone.ns_names, name_server))
+                })
+                .or_else(|| {
+                    if zone.ns_ips.is_empty() {
+                        return None;
+                    }
+                    let name_servers =
+                        name_servers.get_or_insert_with(|| self.name_servers(&qname, resolve));
+                    let ips = name_server_ips
+                        .get_or_insert_with(|| lookup_addresses(name_servers, resolve));
+                    address_action(&zone.ns_ips, ips)
+                });
+            if let Some(action) = action {
+                log_debug!(
+                    "Response policy zone {} matched the response to {qname}",
+                    zone.name
+                );
+                return Some(action);
+            }
+        }
+        None
+    }
+
+    // Looking up the name servers takes a query per label of the name, so they are cached
+    fn name_servers(
+        &self,
+        name: &str,
+        resolve: &dyn Fn(&Question) -> Result<Vec<Answer>>,
+    ) -> Vec<String> {
+        let now = Instant::now();
+        if let Some((name_servers, expires_at)) = self.name_servers.lock().unwrap().get(name) {
+            if *expires_at > now {
+                return name_servers.clone();
+            }
+        }
+        // Failed lookups are not cached, the next question tries again
+        let Some((name_servers, ttl)) = lookup_name_servers(name, resolve) else {
+            return vec![];
+        };
+
+        let mut cache = self.name_servers.lock().unwrap();
+        if cache.len() >= NAME_SERVERS_CACHE_SIZE {
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        if cache.len() >= NAME_SERVERS_CACHE_SIZE {
+            cache.clear();
+        }
+        let expires_at = now + Duration::from_secs(ttl as u64);
+        cache.insert(name.to_string(), (name_servers.clone(), expires_at));
+        name_servers
+    }
+}
+
+fn addresses(answers: &[Answer]) -> Vec<IpAddr> {
+    answers
+        .iter()
+        .filter_map(|answer| match answer.rdata() {
+            RData::A(address) => Some(IpAddr::V4(address)),
+            RData::Aaaa(address) => Some(IpAddr::V6(address)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn question_for(name: &str, record_type: RecordType) -> Question {
+    Question {
+        qname: labels_bytes(name),
+        qtype: record_type.into(),
+        qclass: CLASS_IN,
+        label: name.to_string(),
+    }
+}
+
+// The name servers of the closest zone enclosing the name, querying NS records up the tree,
+// with the lowest TTL of their records. None when a query fails.
+fn lookup_name_servers(
+    name: &str,
+    resolve: &dyn Fn(&Question) -> Result<Vec<Answer>>,
+) -> Option<(Vec<String>, u32)> {
+    let mut name = name;
+    while !name.is_empty() && name != "." {
+        let answers = match resolve(&question_for(name, RecordType::Ns)) {
+            Ok(answers) => answers,
+            Err(error) => {
+                log_warn!("Failed to look up the name servers of {name}: {error:#}");
+                return None;
+            }
+        };
+        let (name_servers, ttls): (Vec<String>, Vec<u32>) = answers
+            .iter()
+            .filter(|answer| answer.label.eq_ignore_ascii_case(name))
+            .filter_map(|answer| match answer.rdata() {
+                RData::Ns(name_server) => Some((name_server.to_lowercase(), answer.ttl)),
+                _ => None,
+            })
+            .unzip();
+        if let Some(ttl) = ttls.into_iter().min() {
+            return Some((name_servers, ttl));
+        }
+        name = name.split_once('.').map_or("", |(_, parent)| parent);
+    }
+    Some((vec![], NO_NAME_SERVERS_TTL))
+}
+
+fn lookup_addresses(
+    names: &[String],
+    resolve: &dyn Fn(&Question) -> Result<Vec<Answer>>,
+) -> Vec<IpAddr> {
+    let mut ips = vec![];
+    for name in names {
+        for record_type in [RecordType::A, RecordType::Aaaa] {
+            match resolve(&question_for(name, record_type)) {
+                Ok(answers) => ips.extend(addresses(&answers)),
+                Err(error) => log_warn!("Failed to look up the address of {name}: {error:#}"),
+            }
+        }
+    }
+    ips
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    const RPZ: &str = "\
+$TTL 300
+@ IN SOA ns.rpz.local. admin.rpz.local. 1 3600 600 86400 60
+  IN NS ns.rpz.local.
+ads.example.com       CNAME .
+*.ads.example.com     CNAME *.
+www.example.com       CNAME rpz-passthru.
+*.example.com         CNAME rpz-drop.
+big.example.org       CNAME rpz-tcp-only.
+portal.example.org    A     10.0.0.80
+portal.example.org    TXT   \"walled garden\"
+32.1.2.0.192.rpz-ip   CNAME .
+24.0.2.0.192.rpz-ip   CNAME rpz-passthru.
+128.1.zz.db8.2001.rpz-ip CNAME *.
+ns.evil.net.rpz-nsdname CNAME .
+32.66.100.51.198.rpz-nsip CNAME rpz-drop.
+";
+
+    fn answer(label: &str, rdata: RData, record_type: RecordType) -> Answer {
+        Answer::new(label, record_type, CLASS_IN, 60, &rdata)
+    }
+
+    fn rpz() -> ResponsePolicyZones {
+        let zone = Zone::parse("rpz.local.", RPZ).unwrap();
+        ResponsePolicyZones {
+            zones: vec![ResponsePolicyZone::from_zone(&zone).unwrap()],
+            name_servers: Mutex::default(),
+        }
+    }
+
+    // An upstream where bad.net is served by ns.evil.net, and ok.net by a server at the
+    // address of the NS IP trigger
+    fn resolve(question: &Question) -> Result<Vec<Answer>> {
+        let ns = |name: &str| answer(&question.label, RData::Ns(name.to_string()), RecordType::Ns);
+        let answers = match (RecordType::from(question.qtype), question.label.as_str()) {
+            (RecordType::Ns, "bad.net.") => vec![ns("ns.evil.net.")],
+            (RecordType::Ns, "ok.net.") => vec![ns("ns1.ok.net.")],
+            (RecordType::A, "ns1.ok.net.") => vec![answer(
+                "ns1.ok.net.",
+                RData::A("198.51.100.66".parse().unwrap()),
+                RecordType::A,
+            )],
+            _ => vec![],
+        };
+        Ok(answers)
+    }
+
+    // The action the dispatcher applies, the response triggers of the zones before the one
+    // matching the question name winning over it
+    fn check_zones(
+        rpz: &ResponsePolicyZones,
+        question: &Question,
+        answers: &[Answer],
+        resolve: &dyn Fn(&Question) -> Result<Vec<Answer>>,
+    ) -> Option<RpzAction> {
+        let (zones_count, action) = match rpz.check_qname(question) {
+            Some((index, action)) => (index, Some(action)),
+            None => (rpz.zones_count(), None),
+        };
+        rpz.check_response(question, answers, resolve, zones_count)
+            .or(action)
+            .cloned()
+    }
+
+    fn check(name: &str, record_type: RecordType, answers: &[Answer]) -> Option<RpzAction> {
+        check_zones(&rpz(), &question_for(name, record_type), answers, &resolve)
+    }
+
+    #[test]
+    fn test_when_ip_trigger_is_parsed_then_labels_are_reversed() {
+        // Given
+        // When
+        // Then
+        let cidr = |labels| trigger_cidr(labels).unwrap();
+        assert_eq!(cidr("32.1.2.0.192"), "192.0.2.1/32".parse().unwrap());
+        assert_eq!(
+            cidr("128.1.zz.db8.2001"),
+            "2001:db8::1/128".parse().unwrap()
+        );
+        assert_eq!(cidr("48.zz.db8.2001"), "2001:db8::/48".parse().unwrap());
+        assert_eq!(cidr("128.1.zz"), "::1/128".parse().unwrap());
+        assert!(trigger_cidr("33.1.2.0.192").is_err());
+    }
+
+    #[test]
+    fn test_when_qname_triggers_then_most_specific_action_is_returned() {
+        // Given
+        // When
+        // Then
+        assert_eq!(
+            check("ads.example.com.", RecordType::A, &[]),
+            Some(RpzAction::NxDomain)
+        );
+        assert_eq!(
+            check("x.ads.example.com.", RecordType::A, &[]),
+            Some(RpzAction::NoData)
+        );
+        assert_eq!(
+            check("www.example.com.", RecordType::A, &[]),
+            Some(RpzAction::PassThru)
+        );
+        assert_eq!(
+            check("mail.example.com.", RecordType::A, &[]),
+            Some(RpzAction::Drop)
+        );
+        assert_eq!(
+            check("big.example.org.", RecordType::A, &[]),
+            Some(RpzAction::TcpOnly)
+        );
+        assert_eq!(check("example.com.", RecordType::A, &[]), None);
+        let Some(RpzAction::LocalData(records)) = check("Portal.example.org.", RecordType::A, &[])
+        else {
+            panic!("portal has no local data");
+        };
+        let question = question_for("Portal.example.org.", RecordType::A);
+        let answers = RpzAction::local_answers(&records, &question);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].label, "Portal.example.org.");
+        assert_eq!(answers[0].rdata(), RData::A("10.0.0.80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_when_answers_or_name_servers_trigger_then_action_is_returned() {
+        // Given
+        let a = |address: &str| {
+            answer(
+                "host.example.net.",
+                RData::A(address.parse().unwrap()),
+                RecordType::A,
+            )
+        };
+        let aaaa = answer(
+            "host.example.net.",
+            RData::Aaaa("2001:db8::1".parse().unwrap()),
+            RecordType::Aaaa,
+        );
+        // When
+        // Then
+        let check_answers = |answers: &[Answer]| check("host.example.net.", RecordType::A, answers);
+        assert_eq!(check_answers(&[a("192.0.2.1")]), Some(RpzAction::NxDomain));
+        assert_eq!(check_answers(&[a("192.0.2.2")]), Some(RpzAction::PassThru));
+        assert_eq!(check_answers(&[aaaa]), Some(RpzAction::NoData));
+        assert_eq!(check_answers(&[a("203.0.113.1")]), None);
+        assert_eq!(
+            check("www.bad.net.", RecordType::A, &[]),
+            Some(RpzAction::NxDomain)
+        );
+        assert_eq!(
+            check("a.b.ok.net.", RecordType::A, &[]),
+            Some(RpzAction::Drop)
+        );
+    }
+
+    #[test]
+    fn test_when_zones_have_different_triggers_then_the_first_matching_zone_wins() {
+        // Given a first zone with an IP trigger, and a second one letting the name through
+        let zone = |origin, trigger| {
+            let text =
+                format!("$TTL 300\n@ SOA ns.rpz. admin.rpz. 1 3600 600 86400 60\n{trigger}\n");
+            ResponsePolicyZone::from_zone(&Zone::parse(origin, &text).unwrap()).unwrap()
+        };
+        let rpz = ResponsePolicyZones {
+            zones: vec![
+                zone("ip.rpz.", "32.1.2.0.192.rpz-ip CNAME ."),
+                zone(
+                    "names.rpz.",
+                    "$TTL 300\nhost.example.net CNAME rpz-passthru.\n",
+                ),
+            ],
+            name_servers: Mutex::default(),
+        };
+        let question = question_for("host.example.net.", RecordType::A);
+        let a = |address: &str| {
+            answer(
+                "host.example.net.",
+                RData::A(address.parse().unwrap()),
+                RecordType::A,
+            )
+        };
+        // When
+        let check_answers = |answers: &[Answer]| check_zones(&rpz, &question, answers, &resolve);
+        // Then
+        assert!(rpz.has_response_triggers(1));
+        assert_eq!(check_answers(&[a("192.0.2.1")]), Some(RpzAction::NxDomain));
+        assert_eq!(check_answers(&[a("192.0.2.2")]), Some(RpzAction::PassThru));
+    }
+
+    #[test]
+    fn test_when_name_server_triggers_are_checked_again_then_name_servers_are_cached() {
+        // Given
+        let rpz = rpz();
+        let queries = Cell::new(0);
+        let counting_resolve = |question: &Question| {
+            queries.set(queries.get() + 1);
+            resolve(question)
+        };
+        let question = question_for("www.bad.net.", RecordType::A);
+        // When
+        let first_action = check_zones(&rpz, &question, &[], &counting_resolve);
+        let first_queries = queries.get();
+        let second_action = check_zones(&rpz, &question, &[], &counting_resolve);
+        // Then
+        assert_eq!(first_action, Some(RpzAction::NxDomain));
+        assert_eq!(second_action, Some(RpzAction::NxDomain));
+        assert_eq!(first_queries, 2);
+        assert_eq!(queries.get(), first_queries);
+    }
+}
@@ -0,0 +1,266 @@
+// Response rate limiting against reflection attacks: responses to each client prefix are
+// counted per kind of response in token buckets, and the ones over the rate are dropped,
+// except every slip-th one which is sent truncated, so real clients can retry over TCP
+use super::limits::{Buckets, Idle, TokenBucket};
+use super::message::rcode::Rcode;
+use super::message::Message;
+use crate::config::RrlConfig;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ResponseClass {
+    Answer,
+    NoData,
+    NxDomain,
+    Error,
+}
+
+// Answers are limited per question, while NXDOMAIN and errors are limited for any question, so
+// random names can't get around the limit
+type BucketKey = (IpAddr, ResponseClass, String, u16);
+
+struct Bucket {
+    tokens: TokenBucket,
+    // Limited responses since the last slipped one
+    limited: u64,
+}
+
+impl Idle for Bucket {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.tokens.is_idle(now)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Send,
+    Slip,
+    Drop,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RrlStats {
+    pub slipped: u64,
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets<BucketKey, Bucket>>,
+    slipped: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn check(&self, config: &RrlConfig, client: &IpAddr, response: &Message) -> Verdict {
+        self.check_at(config, client, response, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        config: &RrlConfig,
+        client: &IpAddr,
+        response: &Message,
+        now: Instant,
+    ) -> Verdict {
+        let key = bucket_key(config, client, response);
+        let rate = match key.1 {
+            ResponseClass::NxDomain => config.nxdomains_per_second,
+            ResponseClass::Error => config.errors_per_second,
+            ResponseClass::Answer | ResponseClass::NoData => None,
+        }
+        .unwrap_or(config.responses_per_second) as f64;
+        if rate == 0.0 {
+            return Verdict::Send;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_with(key, now, || Bucket {
+            tokens: TokenBucket::new(rate, now),
+            limited: 0,
+        });
+        if bucket.tokens.take(rate, now) {
+            return Verdict::Send;
+        }
+
+        bucket.limited += 1;
+        if config.slip > 0 && bucket.limited >= config.slip as u64 {
+            bucket.limited = 0;
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Drop
+        }
+    }
+
+    pub fn stats(&self) -> RrlStats {
+        RrlStats {
+            slipped: self.slipped.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn bucket_key(config: &RrlConfig, client: &IpAddr, response: &Message) -> BucketKey {
+    let network = network(config, client);
+    let class = match Rcode::from(response.header.rcode) {
+        Rcode::NoError if response.answers.is_empty() => ResponseClass::NoData,
+        Rcode::NoError => ResponseClass::Answer,
+        Rcode::NxDomain => return (network, ResponseClass::NxDomain, String::new(), 0),
+        _ => return (network, ResponseClass::Error, String::new(), 0),
+    };
+    match response.questions.first() {
+        Some(question) => (
+            network,
+            class,
+            question.label.to_lowercase(),
+            question.qtype,
+        ),
+        None => (network, class, String::new(), 0),
+    }
+}
+
+// The client address with only its prefix kept, IPv4 mapped addresses counting as IPv4
+fn network(config: &RrlConfig, client: &IpAddr) -> IpAddr {
+    let client = match client {
+        IpAddr::V6(address) => address.to_ipv4_mapped().map_or(*client, IpAddr::V4),
+        IpAddr::V4(_) => *client,
+    };
+    match client {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - config.ipv4_prefix as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - config.ipv6_prefix as u32)
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::limits::MAX_BUCKETS;
+    use crate::server::message::header::Header;
+    use std::time::Duration;
+
+    fn response(rcode: Rcode) -> Message {
+        Message {
+            header: Header {
+                qr: 1,
+                rcode: rcode.into(),
+                ..Header::default()
+            },
+            questions: vec![],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+
+    #[test]
+    fn test_when_client_prefix_exceeds_rate_then_responses_slip_or_drop() {
+        // Given
+        let config = RrlConfig {
+            responses_per_second: 2,
+            slip: 2,
+            ..RrlConfig::default()
+        };
+        let rate_limiter = RateLimiter::default();
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let neighbour: IpAddr = "192.0.2.200".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        let nxdomain = response(Rcode::NxDomain);
+        // When
+        let verdicts: Vec<Verdict> = [client, neighbour, client, client, other]
+            .iter()
+            .map(|address| rate_limiter.check_at(&config, address, &nxdomain, now))
+            .collect();
+        let later = now + Duration::from_secs(1);
+        // Then
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Send,
+                Verdict::Send,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Send
+            ]
+        );
+        let noerror = response(Rcode::NoError);
+        assert_eq!(
+            rate_limiter.check_at(&config, &client, &noerror, now),
+            Verdict::Send
+        );
+        assert_eq!(
+            rate_limiter.check_at(&config, &client, &nxdomain, later),
+            Verdict::Send
+        );
+        let stats = rate_limiter.stats();
+        assert_eq!((stats.slipped, stats.dropped), (1, 1));
+    }
+
+    #[test]
+    fn test_when_nxdomain_and_error_rates_are_set_then_they_apply_to_those_responses() {
+        // Given
+        let config = RrlConfig {
+            responses_per_second: 1,
+            nxdomains_per_second: Some(3),
+            errors_per_second: Some(0),
+            slip: 0,
+            ..RrlConfig::default()
+        };
+        let rate_limiter = RateLimiter::default();
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let count_sent = |rcode| {
+            (0..5)
+                .filter(|_| {
+                    rate_limiter.check_at(&config, &client, &response(rcode), now) == Verdict::Send
+                })
+                .count()
+        };
+        // When
+        // Then
+        assert_eq!(count_sent(Rcode::NoError), 1);
+        assert_eq!(count_sent(Rcode::NxDomain), 3);
+        assert_eq!(count_sent(Rcode::ServFail), 5);
+    }
+
+    #[test]
+    fn test_when_client_prefixes_flood_then_the_oldest_bucket_is_forgotten() {
+        // Given a limited client, then as many other prefixes as there can be buckets
+        let config = RrlConfig {
+            responses_per_second: 1,
+            slip: 0,
+            ..RrlConfig::default()
+        };
+        let rate_limiter = RateLimiter::default();
+        let now = Instant::now();
+        let nxdomain = response(Rcode::NxDomain);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        rate_limiter.check_at(&config, &client, &nxdomain, now);
+        let limited = rate_limiter.check_at(&config, &client, &nxdomain, now);
+        // When
+        for index in 0..MAX_BUCKETS as u32 {
+            let other = IpAddr::V4(Ipv4Addr::from((10 << 24) + (index << 8)));
+            rate_limiter.check_at(&config, &other, &nxdomain, now);
+        }
+        // Then its bucket was evicted, and is full again
+        assert_eq!(limited, Verdict::Drop);
+        assert_eq!(
+            rate_limiter.check_at(&config, &client, &nxdomain, now),
+            Verdict::Send
+        );
+    }
+}
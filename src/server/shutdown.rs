@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Tells the listeners to stop and counts the requests still being answered
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+}
+
+pub struct InFlightGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // The request counts as in flight until the guard is dropped
+    pub fn track(self: &Arc<Self>) -> InFlightGuard {
+        *self.in_flight.lock().unwrap() += 1;
+        InFlightGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    // Returns the number of requests still in flight when the timeout expires
+    pub fn wait_in_flight(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            in_flight = self.idle.wait_timeout(in_flight, deadline - now).unwrap().0;
+        }
+        *in_flight
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.shutdown.in_flight.lock().unwrap();
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.shutdown.idle.notify_all();
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Minimal signal handling through the C library, which std already links on unix
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod unix {
+    use super::{RELOAD_REQUESTED, SHUTDOWN_REQUESTED};
+    use std::os::raw::c_int;
+    use std::sync::atomic::Ordering;
+
+    pub const SIGHUP: c_int = 1;
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    // Only stores to atomics, which is safe to do in a signal handler
+    extern "C" fn on_signal(signum: c_int) {
+        match signum {
+            SIGHUP => RELOAD_REQUESTED.store(true, Ordering::SeqCst),
+            _ => SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst),
+        }
+    }
+
+    pub fn install() {
+        unsafe {
+            signal(SIGHUP, on_signal);
+            signal(SIGINT, on_signal);
+            signal(SIGTERM, on_signal);
+        }
+    }
+}
+
+pub fn install() {
+    #[cfg(unix)]
+    unix::install();
+}
+
+// Returns true once for every reload requested since the last call
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,150 @@
+use super::client::{Client, Transport};
+use super::{is_timeout, Context, POLL_INTERVAL};
+use anyhow::{Context as _, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Connections served at once by a listener, each by its own thread. The next ones are closed
+// right away, so a flood of them can't exhaust threads.
+pub const MAX_CONNECTIONS: usize = 256;
+
+// Counts the open connections of a listener, each until its guard is dropped
+pub struct ConnectionLimit {
+    max_count: usize,
+    count: Arc<AtomicUsize>,
+}
+
+pub struct ConnectionGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_count: usize) -> Self {
+        ConnectionLimit {
+            max_count,
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // None when the listener already has its maximum of connections
+    pub fn open(&self) -> Option<ConnectionGuard> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.max_count).then_some(count + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard {
+            count: self.count.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn serve(tcp_listener: TcpListener, context: Arc<Context>) -> Result<()> {
+    // Poll for connections to notice a shutdown
+    tcp_listener.set_nonblocking(true)?;
+    let connection_limit = ConnectionLimit::new(MAX_CONNECTIONS);
+
+    while !context.shutdown.is_requested() {
+        let (stream, source) = match tcp_listener.accept() {
+            Ok(accepted) => accepted,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(error) => {
+                log_error!("Failed to accept TCP connection: {error}");
+                continue;
+            }
+        };
+        let Some(connection) = connection_limit.open() else {
+            log_debug!("Too many TCP connections, closing the one from {source}");
+            context.metrics.record_dropped("overload");
+            continue;
+        };
+        let context = context.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(error) = serve_connection(stream, context) {
+                log_warn!("TCP connection error: {error:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+// Messages over TCP are prefixed with their length as a two bytes integer
+fn serve_connection(mut stream: TcpStream, context: Arc<Context>) -> Result<()> {
+    let client = Client {
+        address: stream.peer_addr()?.ip(),
+        transport: Transport::Tcp,
+    };
+    stream.set_nonblocking(false)?;
+    let mut idle_since = Instant::now();
+
+    loop {
+        // Wait for the next message, closing the connection when idle or shutting down
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        match stream.peek(&mut [0; 1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(error) if is_timeout(&error) => {
+                if context.shutdown.is_requested() || idle_since.elapsed() > IDLE_TIMEOUT {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        }
+        let _in_flight = context.shutdown.track();
+
+        // Read request message
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut length_bytes = [0; 2];
+        stream.read_exact(&mut length_bytes)?;
+        let mut bytes = vec![0; u16::from_be_bytes(length_bytes) as usize];
+        stream.read_exact(&mut bytes)?;
+
+        // Send response messages, none when dropped
+        for response_bytes in context.handle_request(&bytes, client)? {
+            let length = u16::try_from(response_bytes.len()).with_context(|| {
+                format!("response of {} bytes is too large", response_bytes.len())
+            })?;
+            stream.write_all(&[&length.to_be_bytes()[..], &response_bytes].concat())?;
+        }
+        idle_since = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_when_connections_reach_the_maximum_then_the_next_one_is_refused_until_one_closes() {
+        // Given
+        let connection_limit = ConnectionLimit::new(2);
+        let first = connection_limit.open();
+        let second = connection_limit.open();
+        // When
+        let third = connection_limit.open();
+        // Then
+        assert!(first.is_some() && second.is_some());
+        assert!(third.is_none());
+        // When
+        drop(first);
+        // Then
+        assert!(connection_limit.open().is_some());
+    }
+}
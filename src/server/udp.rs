@@ -0,0 +1,86 @@
+use super::client::{Client, Transport};
+use super::shutdown::InFlightGuard;
+use super::{is_timeout, Context, POLL_INTERVAL};
+use anyhow::Result;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Requests are answered by a fixed number of workers, so a slow resolution doesn't hold the
+// next requests while a flood of them can't exhaust threads
+const WORKER_COUNT: usize = 64;
+// Requests waiting for a worker, the next ones are dropped
+const QUEUE_SIZE: usize = 1024;
+// Requests with EDNS can be larger than 512 bytes, up to the largest UDP payload
+const BUFFER_SIZE: usize = 65535;
+
+struct Request {
+    bytes: Vec<u8>,
+    source: SocketAddr,
+    _in_flight: InFlightGuard,
+}
+
+pub fn serve(udp_socket: UdpSocket, context: Arc<Context>) -> Result<()> {
+    // Wake up regularly to notice a shutdown
+    udp_socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    let udp_socket = Arc::new(udp_socket);
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKER_COUNT {
+        let udp_socket = udp_socket.clone();
+        let receiver = receiver.clone();
+        let context = context.clone();
+        thread::spawn(move || answer_requests(&udp_socket, &receiver, &context));
+    }
+
+    while !context.shutdown.is_requested() {
+        // Read request message
+        let (size, source) = match udp_socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => continue,
+            Err(error) => {
+                log_warn!("Failed to receive UDP message: {error}");
+                continue;
+            }
+        };
+        let request = Request {
+            bytes: buffer[..size].to_vec(),
+            source,
+            _in_flight: context.shutdown.track(),
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(request) {
+            log_debug!("All UDP workers are busy, dropping the request from {source}");
+            context.metrics.record_dropped("overload");
+        }
+    }
+    // The workers answer the queued requests within the shutdown timeout, then stop
+    Ok(())
+}
+
+fn answer_requests(udp_socket: &UdpSocket, receiver: &Mutex<Receiver<Request>>, context: &Context) {
+    loop {
+        // The lock is only held while waiting, not while answering
+        let Ok(request) = receiver.lock().unwrap().recv() else {
+            return;
+        };
+        let source = request.source;
+        let client = Client {
+            address: source.ip(),
+            transport: Transport::Udp,
+        };
+        let result = context
+            .handle_request(&request.bytes, client)
+            .and_then(|responses_bytes| {
+                for response_bytes in responses_bytes {
+                    udp_socket.send_to(&response_bytes, source)?;
+                }
+                Ok(())
+            });
+        if let Err(error) = result {
+            log_warn!("Failed to answer {source} over UDP: {error:#}");
+        }
+    }
+}
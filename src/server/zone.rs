@@ -0,0 +1,592 @@
+use super::message::answer::Answer;
+use super::message::question::Question;
+use super::message::rcode::Rcode;
+use super::message::rdata::{parse_name, RData};
+use super::message::record_type::{RecordType, CLASS_IN};
+use crate::config::ZoneConfig;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_TTL: u32 = 3600;
+const MAX_CNAME_CHAIN: usize = 8;
+
+// A zone loaded from a master file (RFC 1035 section 5)
+#[derive(Debug)]
+pub struct Zone {
+    pub origin: String,
+    records: HashMap<String, Vec<Answer>>,
+    // Every name of the zone, including those that only have records below them
+    names: HashSet<String>,
+}
+
+struct ZoneEntry {
+    line: usize,
+    starts_with_blank: bool,
+    tokens: Vec<String>,
+}
+
+impl Zone {
+    pub fn load(origin: &str, path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read zone file {}", path.display()))?;
+        Zone::parse(origin, &text).with_context(|| format!("invalid zone file {}", path.display()))
+    }
+
+    pub fn parse(origin: &str, text: &str) -> Result<Self> {
+        let zone_origin = origin.to_lowercase();
+        let mut zone = Zone {
+            origin: zone_origin.clone(),
+            records: HashMap::new(),
+            names: HashSet::new(),
+        };
+        let mut origin = zone_origin.clone();
+        let mut default_ttl = DEFAULT_TTL;
+        let mut previous_owner: Option<String> = None;
+
+        for entry in Self::entries(text)? {
+            let line = entry.line;
+            let mut tokens = entry.tokens.into_iter().peekable();
+
+            if !entry.starts_with_blank {
+                match tokens.peek().map(String::as_str) {
+                    Some("$ORIGIN") => {
+                        tokens.next();
+                        let name = tokens
+                            .next()
+                            .with_context(|| format!("line {line}: $ORIGIN without a name"))?;
+                        origin = parse_name(&name, &origin)
+                            .with_context(|| format!("line {line}: invalid $ORIGIN"))?
+                            .to_lowercase();
+                        continue;
+                    }
+                    Some("$TTL") => {
+                        tokens.next();
+                        let ttl = tokens.next().unwrap_or_default();
+                        default_ttl = ttl
+                            .parse()
+                            .with_context(|| format!("line {line}: invalid $TTL '{ttl}'"))?;
+                        continue;
+                    }
+                    Some(directive) if directive.starts_with('$') => {
+                        bail!("line {line}: unsupported directive {directive}")
+                    }
+                    _ => {}
+                }
+            }
+
+            // The owner is omitted when the line starts with a blank
+            let owner = if entry.starts_with_blank {
+                previous_owner
+                    .clone()
+                    .with_context(|| format!("line {line}: record without an owner name"))?
+            } else {
+                parse_name(&tokens.next().unwrap(), &origin)
+                    .with_context(|| format!("line {line}: invalid owner name"))?
+                    .to_lowercase()
+            };
+            if owner != zone_origin && !owner.ends_with(&format!(".{zone_origin}")) {
+                bail!("line {line}: '{owner}' is outside of the zone '{zone_origin}'");
+            }
+
+            // TTL and class are optional, in any order
+            let mut ttl = None;
+            let mut record_type = None;
+            for token in tokens.by_ref() {
+                if token.eq_ignore_ascii_case("IN") {
+                    continue;
+                }
+                if let Ok(value) = token.parse::<u32>() {
+                    if ttl.replace(value).is_some() {
+                        bail!("line {line}: TTL is given twice");
+                    }
+                    continue;
+                }
+                record_type = Some(
+                    token
+                        .parse::<RecordType>()
+                        .with_context(|| format!("line {line}"))?,
+                );
+                break;
+            }
+            let record_type =
+                record_type.with_context(|| format!("line {line}: missing record type"))?;
+            let fields: Vec<String> = tokens.collect();
+            let rdata = RData::parse(record_type, &fields, &origin)
+                .with_context(|| format!("line {line}: {record_type:?} record"))?;
+
+            let answer = Answer::new(
+                &owner,
+                record_type,
+                CLASS_IN,
+                ttl.unwrap_or(default_ttl),
+                &rdata,
+            );
+            zone.insert(answer)
+                .with_context(|| format!("line {line}"))?;
+            previous_owner = Some(owner);
+        }
+
+        let has_soa = zone.records.get(&zone.origin).is_some_and(|records| {
+            records
+                .iter()
+                .any(|record| record.record_type() == RecordType::Soa)
+        });
+        if !has_soa {
+            bail!("zone '{}' has no SOA record at its apex", zone.origin);
+        }
+        Ok(zone)
+    }
+
+    fn insert(&mut self, answer: Answer) -> Result<()> {
+        let records = self.records.entry(answer.label.clone()).or_default();
+        let has_cname = records
+            .iter()
+            .any(|record| record.record_type() == RecordType::Cname);
+        let is_cname = answer.record_type() == RecordType::Cname;
+        if (has_cname || is_cname) && !records.is_empty() {
+            bail!("'{}' has a CNAME record and other data", answer.label);
+        }
+        if answer.record_type() == RecordType::Soa && answer.label != self.origin {
+            bail!("SOA record for '{}' is not at the zone apex", answer.label);
+        }
+        let mut name = Some(answer.label.as_str());
+        while let Some(current) = name {
+            if !self.names.insert(current.to_string()) || current == self.origin {
+                break;
+            }
+            name = parent_name(current);
+        }
+        records.push(answer);
+        Ok(())
+    }
+
+    pub fn records_count(&self) -> usize {
+        self.records.values().map(Vec::len).sum()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = (&str, &[Answer])> {
+        self.records
+            .iter()
+            .map(|(name, records)| (name.as_str(), records.as_slice()))
+    }
+
+    // Every record of the zone, between two copies of its SOA record (RFC 5936)
+    pub fn transfer(&self) -> Vec<Answer> {
+        let soa = self.records[&self.origin]
+            .iter()
+            .find(|record| record.record_type() == RecordType::Soa)
+            .cloned()
+            .unwrap();
+        let mut records = vec![soa.clone()];
+        records.extend(
+            self.records
+                .values()
+                .flatten()
+                .filter(|record| record.record_type() != RecordType::Soa)
+                .cloned(),
+        );
+        records.push(soa);
+        records
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        name == self.origin || name.ends_with(&format!(".{}", self.origin))
+    }
+
+    // Answers with the records of the name, following aliases inside the zone. Negative
+    // answers have the SOA record of the zone as authority, with the TTL of negative caching
+    // (RFC 2308 section 3).
+    pub fn lookup(&self, question: &Question) -> (Rcode, Vec<Answer>, Vec<Answer>) {
+        let qtype = RecordType::from(question.qtype);
+        let mut name = question.label.to_lowercase();
+        let mut answers = vec![];
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = self.records.get(&name) else {
+                // Names with records below them exist, even without records of their own
+                let rcode = if self.names.contains(&name) {
+                    Rcode::NoError
+                } else {
+                    Rcode::NxDomain
+                };
+                return (rcode, answers, self.negative_authorities());
+            };
+
+            let matching: Vec<Answer> = records
+                .iter()
+                .filter(|record| qtype == RecordType::Any || record.record_type() == qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                return (Rcode::NoError, answers, vec![]);
+            }
+
+            // Follow the alias while it stays inside the zone
+            let Some(record) = records
+                .first()
+                .filter(|record| record.record_type() == RecordType::Cname)
+            else {
+                return (Rcode::NoError, answers, self.negative_authorities());
+            };
+            answers.push(record.clone());
+            let RData::Cname(target) = record.rdata() else {
+                break;
+            };
+            let target = target.to_lowercase();
+            if !self.contains(&target) {
+                break;
+            }
+            name = target;
+        }
+
+        (Rcode::NoError, answers, vec![])
+    }
+
+    fn negative_authorities(&self) -> Vec<Answer> {
+        self.records[&self.origin]
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::Soa { minimum, .. } => Some(Answer {
+                    ttl: record.ttl.min(minimum),
+                    ..record.clone()
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Splits the text in entries, joining the lines between parentheses and dropping comments
+    fn entries(text: &str) -> Result<Vec<ZoneEntry>> {
+        let mut entries = vec![];
+        let mut entry: Option<ZoneEntry> = None;
+        let mut token = String::new();
+        let mut is_quoted = false;
+        let mut is_escaped = false;
+        let mut parentheses = 0;
+        let mut parentheses_line = 0;
+        let mut line = 1;
+        let mut chars = text.chars().peekable();
+        let mut is_line_start = true;
+
+        while let Some(char) = chars.next() {
+            let current_entry = entry.get_or_insert_with(|| ZoneEntry {
+                line,
+                starts_with_blank: is_line_start && (char == ' ' || char == '\t'),
+                tokens: vec![],
+            });
+            is_line_start = false;
+
+            // Escapes are kept in the token, to be read by the field they belong to
+            if is_escaped {
+                token.push(char);
+                is_escaped = false;
+                continue;
+            }
+            if char == '\\' {
+                token.push(char);
+                is_escaped = true;
+                continue;
+            }
+            if is_quoted {
+                match char {
+                    '\n' => bail!("line {line}: unterminated quoted string"),
+                    '"' => {
+                        current_entry.tokens.push(std::mem::take(&mut token));
+                        is_quoted = false;
+                    }
+                    _ => token.push(char),
+                }
+                continue;
+            }
+
+            match char {
+                '"' => is_quoted = true,
+                '(' => {
+                    if parentheses == 0 {
+                        parentheses_line = line;
+                    }
+                    parentheses += 1;
+                }
+                ')' => {
+                    if parentheses == 0 {
+                        bail!("line {line}: unbalanced ')'");
+                    }
+                    parentheses -= 1;
+                }
+                ';' => {
+                    while chars.peek().is_some_and(|char| *char != '\n') {
+                        chars.next();
+                    }
+                }
+                ' ' | '\t' | '\r' | '\n' => {
+                    if !token.is_empty() {
+                        current_entry.tokens.push(std::mem::take(&mut token));
+                    }
+                }
+                _ => token.push(char),
+            }
+            if char != '\n' {
+                continue;
+            }
+
+            line += 1;
+            is_line_start = true;
+            if parentheses == 0 {
+                if let Some(entry) = entry.take().filter(|entry| !entry.tokens.is_empty()) {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        if is_quoted {
+            bail!("line {line}: unterminated quoted string");
+        }
+        if parentheses > 0 {
+            bail!("line {parentheses_line}: unbalanced '('");
+        }
+        if !token.is_empty() {
+            entry.as_mut().unwrap().tokens.push(token);
+        }
+        entries.extend(entry.filter(|entry| !entry.tokens.is_empty()));
+        Ok(entries)
+    }
+}
+
+// The name without its first label, which can hold escaped dots
+fn parent_name(name: &str) -> Option<&str> {
+    let mut bytes = name.bytes().enumerate();
+    while let Some((index, byte)) = bytes.next() {
+        match byte {
+            b'\\' => {
+                bytes.next();
+            }
+            b'.' => return Some(&name[(index + 1)..]),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Splits a record written on one line in tokens, the way zone files are read
+pub fn record_tokens(text: &str) -> Result<Vec<String>> {
+    Ok(Zone::entries(text)?
+        .into_iter()
+        .flat_map(|entry| entry.tokens)
+        .collect())
+}
+
+#[derive(Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn load(configs: &[ZoneConfig]) -> Result<Self> {
+        let mut zones = vec![];
+        for config in configs {
+            let zone = Zone::load(&config.name, &config.file)?;
+            log_info!(
+                "Loaded zone {} with {} records",
+                zone.origin,
+                zone.records_count()
+            );
+            zones.push(zone);
+        }
+        Ok(Zones { zones })
+    }
+
+    pub fn transfer(&self, origin: &str) -> Option<Vec<Answer>> {
+        let origin = origin.to_lowercase();
+        self.zones
+            .iter()
+            .find(|zone| zone.origin == origin)
+            .map(Zone::transfer)
+    }
+
+    // Answers from the most specific zone containing the name, if any
+    pub fn lookup(&self, question: &Question) -> Option<(Rcode, Vec<Answer>, Vec<Answer>)> {
+        let name = question.label.to_lowercase();
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(&name))
+            .max_by_key(|zone| zone.origin.len())
+            .map(|zone| zone.lookup(question))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ZONE: &str = r#"
+$TTL 300
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                3600 900 604800 60 )
+        IN  NS  ns1
+ns1         A   192.0.2.1
+www     60  IN  A   192.0.2.10
+            AAAA    2001:db8::10
+alias       CNAME   www
+txt         TXT "hello world" "with \"quotes\""
+a.b         A   192.0.2.20
+"#;
+
+    fn question(label: &str, record_type: RecordType) -> Question {
+        Question {
+            qname: vec![],
+            qtype: record_type.into(),
+            qclass: CLASS_IN,
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_when_zone_file_is_valid_then_records_are_loaded() {
+        // Given
+        // When
+        let zone = Zone::parse("example.com.", ZONE).unwrap();
+        // Then
+        assert_eq!(zone.records_count(), 8);
+        let (rcode, answers, _) = zone.lookup(&question("WWW.example.com.", RecordType::A));
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].ttl, 60);
+        assert_eq!(answers[0].rdata, vec![192, 0, 2, 10]);
+        let (_, answers, _) = zone.lookup(&question("www.example.com.", RecordType::Aaaa));
+        assert_eq!(answers[0].ttl, 300);
+        assert_eq!(answers[0].rdata.len(), 16);
+        let (_, answers, _) = zone.lookup(&question("txt.example.com.", RecordType::Txt));
+        assert_eq!(answers[0].ttl, 300);
+        assert_eq!(answers[0].rdata, b"\x0bhello world\x0dwith \"quotes\"");
+    }
+
+    #[test]
+    fn test_when_zone_is_transferred_then_records_are_between_soa_records() {
+        // Given
+        let zones = Zones {
+            zones: vec![Zone::parse("example.com.", ZONE).unwrap()],
+        };
+        // When
+        let records = zones.transfer("Example.com.").unwrap();
+        // Then
+        assert_eq!(records.len(), 9);
+        assert_eq!(records[0].record_type(), RecordType::Soa);
+        assert_eq!(records[8], records[0]);
+        assert!(records[1..8]
+            .iter()
+            .all(|record| record.record_type() != RecordType::Soa));
+        assert!(zones.transfer("www.example.com.").is_none());
+    }
+
+    #[test]
+    fn test_when_name_is_alias_then_cname_is_followed() {
+        // Given
+        let zone = Zone::parse("example.com.", ZONE).unwrap();
+        // When
+        let (rcode, answers, _) = zone.lookup(&question("alias.example.com.", RecordType::A));
+        // Then
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].record_type(), RecordType::Cname);
+        assert_eq!(answers[1].rdata, vec![192, 0, 2, 10]);
+    }
+
+    #[test]
+    fn test_when_name_is_missing_then_nxdomain_unless_it_has_children() {
+        // Given
+        let zone = Zone::parse("example.com.", ZONE).unwrap();
+        // When
+        let (missing_rcode, _, missing_authorities) =
+            zone.lookup(&question("nope.example.com.", RecordType::A));
+        let (parent_rcode, parent_answers, parent_authorities) =
+            zone.lookup(&question("b.example.com.", RecordType::A));
+        // Then
+        assert_eq!(missing_rcode, Rcode::NxDomain);
+        assert_eq!(parent_rcode, Rcode::NoError);
+        assert!(parent_answers.is_empty());
+        for authorities in [missing_authorities, parent_authorities] {
+            assert_eq!(authorities.len(), 1);
+            assert_eq!(authorities[0].record_type(), RecordType::Soa);
+            assert_eq!(authorities[0].ttl, 60);
+        }
+    }
+
+    #[test]
+    fn test_when_name_has_no_record_of_the_type_then_soa_is_the_authority() {
+        // Given
+        let zone = Zone::parse("example.com.", ZONE).unwrap();
+        // When
+        let (rcode, answers, authorities) =
+            zone.lookup(&question("ns1.example.com.", RecordType::Aaaa));
+        let (_, _, positive_authorities) =
+            zone.lookup(&question("ns1.example.com.", RecordType::A));
+        // Then
+        assert_eq!(rcode, Rcode::NoError);
+        assert!(answers.is_empty());
+        assert_eq!(authorities[0].record_type(), RecordType::Soa);
+        assert!(positive_authorities.is_empty());
+    }
+
+    #[test]
+    fn test_when_zone_file_is_invalid_then_error_has_line_number() {
+        // Given
+        let soa = "@ SOA ns1 hostmaster 1 2 3 4 5\n";
+        let inputs = [
+            (
+                "www A 300.0.0.1\n",
+                "line 2: A record: invalid IPv4 address '300.0.0.1'",
+            ),
+            (
+                "www.other.com. A 192.0.2.1\n",
+                "line 2: 'www.other.com.' is outside",
+            ),
+            (
+                "www CNAME a\nwww A 192.0.2.1\n",
+                "line 3: 'www.example.com.' has a CNAME",
+            ),
+            (
+                "www IN HINFO a b\n",
+                "line 2: unsupported record type 'HINFO'",
+            ),
+            ("www A (192.0.2.1\n", "line 2: unbalanced '('"),
+        ];
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        let long_string = "a".repeat(256);
+        let too_long = [
+            (
+                format!("{long_label} A 192.0.2.1\n"),
+                format!("line 2: invalid owner name: label '{long_label}' is longer than 63 bytes"),
+            ),
+            (
+                format!("www CNAME {long_name}.\n"),
+                format!("line 2: Cname record: name '{long_name}.' is longer than 255 bytes"),
+            ),
+            (
+                format!("www TXT \"{long_string}\"\n"),
+                "line 2: Txt record: TXT string of 256 bytes is longer than 255 bytes".to_string(),
+            ),
+        ];
+        let too_long = too_long
+            .iter()
+            .map(|(input, expected_error)| (input.as_str(), expected_error.as_str()));
+        for (input, expected_error) in inputs.into_iter().chain(too_long) {
+            // When
+            let text = format!("{soa}{input}");
+            let error = format!("{:#}", Zone::parse("example.com.", &text).unwrap_err());
+            // Then
+            assert!(error.starts_with(expected_error), "{error}");
+        }
+        // When
+        let error = Zone::parse("example.com.", "www A 192.0.2.1\n").unwrap_err();
+        // Then
+        assert_eq!(
+            error.to_string(),
+            "zone 'example.com.' has no SOA record at its apex"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A path in the temporary directory that no other test uses, removed with all it contains when
+// dropped, so that failing tests clean up too
+pub struct TempPath {
+    path: PathBuf,
+}
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("dns-{name}-{}-{id}", process::id()));
+        TempPath { path }
+    }
+
+    pub fn directory(name: &str) -> Self {
+        let directory = Self::new(name);
+        fs::create_dir_all(&directory.path).unwrap();
+        directory
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.path.is_dir() {
+            fs::remove_dir_all(&self.path)
+        } else {
+            fs::remove_file(&self.path)
+        };
+    }
+}